@@ -0,0 +1,373 @@
+use actix_web::{
+  delete, get,
+  http::StatusCode,
+  post,
+  web::{self, Data},
+  HttpRequest, HttpResponse,
+};
+use bson::{doc, oid::ObjectId, Document};
+use log::{error, info};
+use mongodb::{
+  options::{FindOneOptions, FindOptions, UpdateOptions},
+  Database,
+};
+use serde_json::json;
+use std::env;
+use tokio::sync::oneshot;
+
+use crate::{
+  circuit_breaker::circuit_breakers,
+  consumer_api::constant_time_eq,
+  database::{delete_many, find_all, find_one, update_many, update_one},
+  delivery_sink::Destination,
+  dispatcher::{dispatcher_commands, DispatcherCommand},
+  helper_functions::AppState,
+  lease::{get_instance_id, take_over_lease},
+};
+
+pub fn get_admin_secret() -> Option<String> {
+  env::var("ADMIN_SECRET")
+    .ok()
+    .filter(|secret| !secret.is_empty())
+}
+
+fn admin_error(status: StatusCode, message: &str) -> HttpResponse {
+  HttpResponse::build(status).json(json!({ "message": message }))
+}
+
+// operators send `x-msl-admin-key`, the api is closed while ADMIN_SECRET is unset,
+// returns the response refusing the request
+pub fn reject_admin_request(req: &HttpRequest) -> Option<HttpResponse> {
+  let secret = match get_admin_secret() {
+    Some(secret) => secret,
+    None => {
+      error!("ADMIN_SECRET is not set, refusing admin request");
+      return Some(admin_error(
+        StatusCode::UNAUTHORIZED,
+        "admin api is not configured",
+      ));
+    }
+  };
+  match req.headers().get("x-msl-admin-key") {
+    Some(key) if constant_time_eq(key.as_bytes(), secret.as_bytes()) => None,
+    _ => Some(admin_error(
+      StatusCode::UNAUTHORIZED,
+      "invalid admin credentials",
+    )),
+  }
+}
+
+// a subscription is paused on its own or through its contract, deactivation is left alone
+pub async fn is_delivery_paused(db: &Database, subscription: &Document) -> anyhow::Result<bool> {
+  if subscription.get_bool("delivery_paused") == Ok(true) {
+    return Ok(true);
+  }
+  let contract_id = match subscription.get_str("contract_id") {
+    Ok(contract_id) => contract_id,
+    Err(_) => return Ok(false),
+  };
+  let paused = find_one(
+    db.collection("pausedcontracts"),
+    doc! { "_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await?;
+  Ok(paused.is_some())
+}
+
+// false when the subscription does not exist
+pub async fn set_subscription_paused(
+  db: &Database,
+  sub_id: &ObjectId,
+  paused: bool,
+) -> anyhow::Result<bool> {
+  let update = if paused {
+    doc! { "$set": { "delivery_paused": true, "delivery_paused_at": bson::DateTime::now() } }
+  } else {
+    doc! { "$unset": { "delivery_paused": "", "delivery_paused_at": "" } }
+  };
+  let result = update_one(
+    db.collection("subscriptions"),
+    doc! { "_id": sub_id },
+    update,
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok(result.matched_count > 0)
+}
+
+// kept apart from the subscriptions, so ones registered while paused are paused too
+pub async fn set_contract_paused(
+  db: &Database,
+  contract_id: &str,
+  paused: bool,
+) -> anyhow::Result<()> {
+  if paused {
+    let mut options = UpdateOptions::default();
+    options.upsert = Some(true);
+    update_one(
+      db.collection("pausedcontracts"),
+      doc! { "_id": contract_id },
+      doc! { "$setOnInsert": { "paused_at": bson::DateTime::now() } },
+      options,
+    )
+    .await?;
+  } else {
+    delete_many(
+      db.collection("pausedcontracts"),
+      doc! { "_id": contract_id },
+    )
+    .await?;
+  }
+  Ok(())
+}
+
+pub async fn contract_subscription_ids(
+  db: &Database,
+  contract_id: &str,
+) -> anyhow::Result<Vec<String>> {
+  let mut find_option = FindOptions::default();
+  find_option.projection = Some(doc! { "_id": 1 });
+  Ok(
+    find_all(
+      db.collection("subscriptions"),
+      doc! { "contract_id": contract_id },
+      find_option,
+    )
+    .await?
+    .iter()
+    .filter_map(|subscription| subscription.get_object_id("_id").ok())
+    .map(|sub_id| sub_id.to_string())
+    .collect(),
+  )
+}
+
+pub async fn purge_pending_blocks(db: &Database, sub_id: &str) -> anyhow::Result<u64> {
+  let result = delete_many(db.collection("transactionblocks"), doc! { "subid": sub_id }).await?;
+  Ok(result.deleted_count)
+}
+
+// drops the backoff of the queued blocks and the open circuit of the host, and moves the lease
+// to this replica, so the round its dispatcher starts delivers right away. Blocks of a round in
+// flight keep their lock, backed off ones carry no `locked_by`. Returns how many were unlocked
+pub async fn reset_retry_state(db: &Database, subscription: &Document) -> anyhow::Result<u64> {
+  let sub_id = subscription.get_object_id("_id")?.to_string();
+  let result = update_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id, "locked_by": { "$exists": false } },
+    doc! { "$set": { "locked_until": bson::DateTime::now() } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  take_over_lease(db, &sub_id, get_instance_id()).await?;
+  let host = Destination::from_subscription(subscription)
+    .ok()
+    .and_then(|destination| destination.breaker_key(subscription));
+  if let Some(host) = host {
+    circuit_breakers().lock().unwrap().record_success(&host);
+  }
+  Ok(result.modified_count)
+}
+
+// false when no dispatcher loop runs in this process
+fn send_command(command: DispatcherCommand) -> bool {
+  dispatcher_commands().is_some_and(|commands| commands.send(command).is_ok())
+}
+
+fn invalid_sub_id() -> HttpResponse {
+  admin_error(StatusCode::BAD_REQUEST, "invalid subscription id")
+}
+
+#[get("/queue")]
+pub async fn admin_queue(req: HttpRequest) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  let (reply, snapshot) = oneshot::channel();
+  if !send_command(DispatcherCommand::Snapshot(reply)) {
+    return admin_error(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the dispatcher is not running",
+    );
+  }
+  match snapshot.await {
+    Ok(snapshot) => HttpResponse::Ok().json(snapshot),
+    Err(_) => admin_error(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the dispatcher is not running",
+    ),
+  }
+}
+
+async fn pause_subscription(
+  req: HttpRequest,
+  sub_id: &str,
+  db: &Database,
+  paused: bool,
+) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  let object_id = match ObjectId::parse_str(sub_id) {
+    Ok(object_id) => object_id,
+    Err(_) => return invalid_sub_id(),
+  };
+  match set_subscription_paused(db, &object_id, paused).await {
+    Ok(true) => {
+      info!("Delivery to {} paused: {}", sub_id, paused);
+      if !paused {
+        send_command(DispatcherCommand::Wake(vec![sub_id.to_string()]));
+      }
+      HttpResponse::Ok().json(json!({ "subscription_id": sub_id, "paused": paused }))
+    }
+    Ok(false) => admin_error(StatusCode::NOT_FOUND, "subscription not found"),
+    Err(err) => {
+      error!("Failed to pause subscription: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
+
+#[post("/subscriptions/{sub_id}/pause")]
+pub async fn admin_pause_subscription(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  pause_subscription(req, &path, &data.db, true).await
+}
+
+#[post("/subscriptions/{sub_id}/resume")]
+pub async fn admin_resume_subscription(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  pause_subscription(req, &path, &data.db, false).await
+}
+
+async fn pause_contract(
+  req: HttpRequest,
+  contract_id: &str,
+  db: &Database,
+  paused: bool,
+) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  let result = async {
+    set_contract_paused(db, contract_id, paused).await?;
+    contract_subscription_ids(db, contract_id).await
+  }
+  .await;
+  match result {
+    Ok(sub_ids) => {
+      info!("Delivery for contract {:?} paused: {}", contract_id, paused);
+      let subscriptions = sub_ids.len();
+      if !paused {
+        send_command(DispatcherCommand::Wake(sub_ids));
+      }
+      HttpResponse::Ok().json(json!({
+        "contract_id": contract_id,
+        "paused": paused,
+        "subscriptions": subscriptions,
+      }))
+    }
+    Err(err) => {
+      error!("Failed to pause contract: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
+
+#[post("/contracts/{contract_id}/pause")]
+pub async fn admin_pause_contract(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  pause_contract(req, &path, &data.db, true).await
+}
+
+#[post("/contracts/{contract_id}/resume")]
+pub async fn admin_resume_contract(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  pause_contract(req, &path, &data.db, false).await
+}
+
+#[post("/subscriptions/{sub_id}/retry")]
+pub async fn admin_retry_subscription(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  let object_id = match ObjectId::parse_str(path.as_str()) {
+    Ok(object_id) => object_id,
+    Err(_) => return invalid_sub_id(),
+  };
+  if dispatcher_commands().is_none() {
+    return admin_error(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the dispatcher is not running",
+    );
+  }
+  let subscription = match find_one(
+    data.db.collection("subscriptions"),
+    doc! { "_id": object_id },
+    FindOneOptions::default(),
+  )
+  .await
+  {
+    Ok(Some(subscription)) => subscription,
+    Ok(None) => return admin_error(StatusCode::NOT_FOUND, "subscription not found"),
+    Err(err) => {
+      error!("Failed to load subscription: {:?}", err);
+      return HttpResponse::InternalServerError().finish();
+    }
+  };
+  let unlocked = match reset_retry_state(&data.db, &subscription).await {
+    Ok(unlocked) => unlocked,
+    Err(err) => {
+      error!("Failed to reset the retry state: {:?}", err);
+      return HttpResponse::InternalServerError().finish();
+    }
+  };
+  if !send_command(DispatcherCommand::Retry(vec![path.to_string()])) {
+    return admin_error(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the dispatcher is not running",
+    );
+  }
+  info!("Forced a delivery round for {}", path);
+  HttpResponse::Accepted().json(json!({ "subscription_id": path.as_str(), "unlocked": unlocked }))
+}
+
+#[delete("/subscriptions/{sub_id}/blocks")]
+pub async fn admin_purge_blocks(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  if ObjectId::parse_str(path.as_str()).is_err() {
+    return invalid_sub_id();
+  }
+  match purge_pending_blocks(&data.db, &path).await {
+    Ok(purged) => {
+      info!("Purged {} pending blocks of {}", purged, path);
+      HttpResponse::Ok().json(json!({ "subscription_id": path.as_str(), "purged": purged }))
+    }
+    Err(err) => {
+      error!("Failed to purge pending blocks: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
@@ -0,0 +1,76 @@
+use crate::{
+  database::{find_one, find_one_and_update},
+  helper_functions::get_i64_from_doc,
+};
+use bson::{doc, Document};
+use mongodb::{
+  options::{FindOneAndUpdateOptions, FindOneOptions},
+  Database,
+};
+use std::env;
+
+const DEFAULT_CONFIRMATION_POLL_MS: u64 = 2000;
+
+// write services report how far their chain has advanced, a late report never moves it back
+pub async fn record_chain_head(
+  db: &Database,
+  contract_id: &str,
+  head_block: i64,
+) -> anyhow::Result<()> {
+  let options = FindOneAndUpdateOptions::builder()
+    .upsert(Some(true))
+    .build();
+  find_one_and_update(
+    db.collection("chainheads"),
+    doc! { "contract_id": contract_id },
+    doc! {
+      "$max": { "head_block": head_block },
+      "$set": { "updated_at": bson::DateTime::now() },
+    },
+    Some(options),
+  )
+  .await?;
+  Ok(())
+}
+
+pub async fn get_chain_head(db: &Database, contract_id: &str) -> anyhow::Result<Option<i64>> {
+  let head = find_one(
+    db.collection("chainheads"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await?;
+  Ok(head.and_then(|head| head.get_i64("head_block").ok()))
+}
+
+pub fn get_confirmations(subscription: &Document) -> i64 {
+  get_i64_from_doc(subscription, "confirmations".to_string()).max(0)
+}
+
+// the highest block a subscription may receive, None when it does not wait for confirmations
+pub fn confirmed_height(subscription: &Document, head: Option<i64>) -> Option<i64> {
+  let confirmations = get_confirmations(subscription);
+  if confirmations == 0 {
+    return None;
+  }
+  // nothing is confirmed before the first head arrives
+  Some(head.map_or(-1, |head| head - confirmations))
+}
+
+// retractions are never held back, the block they retract went out already
+pub fn deliverable_blocks_filter(sub_id: &str, confirmed_height: Option<i64>) -> Document {
+  match confirmed_height {
+    Some(height) => doc! {
+      "subid": sub_id,
+      "$or": [{ "block_number": { "$lte": height } }, { "removed": true }],
+    },
+    None => doc! { "subid": sub_id },
+  }
+}
+
+pub fn get_confirmation_poll_millis() -> u64 {
+  env::var("DISPATCHER_CONFIRMATION_POLL_MS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_CONFIRMATION_POLL_MS)
+}
@@ -0,0 +1,152 @@
+use reqwest::Url;
+use std::{
+  collections::HashMap,
+  env,
+  sync::{Mutex, OnceLock},
+  time::{Duration, Instant},
+};
+
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+const DEFAULT_BREAKER_OPEN_SECS: u64 = 30;
+
+static CIRCUIT_BREAKERS: OnceLock<Mutex<CircuitBreakers>> = OnceLock::new();
+
+pub fn get_breaker_failure_threshold() -> u32 {
+  env::var("DISPATCHER_BREAKER_FAILURES")
+    .ok()
+    .and_then(|value| value.parse::<u32>().ok())
+    .filter(|value| *value > 0)
+    .unwrap_or(DEFAULT_BREAKER_FAILURES)
+}
+
+pub fn get_breaker_open_duration() -> Duration {
+  Duration::from_secs(
+    env::var("DISPATCHER_BREAKER_OPEN_SECS")
+      .ok()
+      .and_then(|value| value.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_BREAKER_OPEN_SECS),
+  )
+}
+
+// subscriptions pointing at the same host and port share a circuit
+pub fn breaker_key(url: &str) -> Option<String> {
+  let url = Url::parse(url).ok()?;
+  Some(format!(
+    "{}:{}",
+    url.host_str()?,
+    url.port_or_known_default()?
+  ))
+}
+
+#[derive(Debug, Default)]
+struct HostCircuit {
+  consecutive_failures: u32,
+  open_until: Option<Instant>,
+  // the half-open probe out right now, until it reports a result or is released
+  probe: Option<u64>,
+}
+
+pub struct CircuitBreakers {
+  hosts: HashMap<String, HostCircuit>,
+  failure_threshold: u32,
+  open_duration: Duration,
+  next_probe: u64,
+}
+
+impl CircuitBreakers {
+  pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+    CircuitBreakers {
+      hosts: HashMap::new(),
+      failure_threshold,
+      open_duration,
+      next_probe: 0,
+    }
+  }
+
+  // None when a request to the host may go out, otherwise how long its circuit stays open
+  pub fn check(&mut self, host: &str, now: Instant) -> Option<Duration> {
+    self.admit(host, now).err()
+  }
+
+  // like `check`, Ok(Some(probe)) when the request is the half-open probe
+  pub fn admit(&mut self, host: &str, now: Instant) -> Result<Option<u64>, Duration> {
+    let circuit = match self.hosts.get_mut(host) {
+      Some(circuit) => circuit,
+      None => return Ok(None),
+    };
+    match circuit.open_until {
+      Some(open_until) if now < open_until => Err(open_until - now),
+      // a single probe goes out at a time while everyone else keeps failing fast
+      Some(_) if circuit.probe.is_some() => Err(self.open_duration),
+      Some(_) => {
+        self.next_probe += 1;
+        circuit.probe = Some(self.next_probe);
+        circuit.open_until = Some(now + self.open_duration);
+        Ok(Some(self.next_probe))
+      }
+      None => Ok(None),
+    }
+  }
+
+  // the probe ended without a result, the next request probes again right away
+  pub fn release_probe(&mut self, host: &str, probe: u64, now: Instant) {
+    if let Some(circuit) = self.hosts.get_mut(host) {
+      if circuit.probe == Some(probe) {
+        circuit.probe = None;
+        circuit.open_until = Some(now);
+      }
+    }
+  }
+
+  pub fn record_success(&mut self, host: &str) {
+    self.hosts.remove(host);
+  }
+
+  pub fn record_failure(&mut self, host: &str, now: Instant) {
+    let circuit = self.hosts.entry(host.to_string()).or_default();
+    circuit.consecutive_failures += 1;
+    circuit.probe = None;
+    if circuit.consecutive_failures >= self.failure_threshold {
+      circuit.open_until = Some(now + self.open_duration);
+    }
+  }
+
+  pub fn is_open(&self, host: &str, now: Instant) -> bool {
+    self
+      .hosts
+      .get(host)
+      .and_then(|circuit| circuit.open_until)
+      .is_some_and(|open_until| now < open_until)
+  }
+}
+
+// shared by every worker of this replica
+pub fn circuit_breakers() -> &'static Mutex<CircuitBreakers> {
+  CIRCUIT_BREAKERS.get_or_init(|| {
+    Mutex::new(CircuitBreakers::new(
+      get_breaker_failure_threshold(),
+      get_breaker_open_duration(),
+    ))
+  })
+}
+
+// held while a probe is out, dropping it on any exit path releases a probe that never reported
+pub struct ProbeGuard {
+  host: String,
+  probe: u64,
+}
+
+impl ProbeGuard {
+  pub fn new(host: String, probe: u64) -> Self {
+    ProbeGuard { host, probe }
+  }
+}
+
+impl Drop for ProbeGuard {
+  fn drop(&mut self) {
+    // a poisoned lock must not turn an unwinding worker into an abort
+    if let Ok(mut breakers) = circuit_breakers().lock() {
+      breakers.release_probe(&self.host, self.probe, Instant::now());
+    }
+  }
+}
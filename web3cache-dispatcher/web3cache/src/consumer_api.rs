@@ -1,45 +1,221 @@
-use std::{collections::HashMap, env};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+  env,
+};
 
 use actix_web::{
-  get, post,
+  get,
+  http::{header::CONTENT_LENGTH, StatusCode},
+  post,
   web::{self, Data},
   HttpRequest, HttpResponse,
 };
 
-use crate::helper_functions::get_i64_from_doc;
+use crate::helper_functions::{get_i64_from_doc, subscription_wants_event};
 use bson::Document;
+use chrono::Utc;
 use futures::StreamExt;
 
 use crate::{
-  database::{find_one, find_one_and_update, insert_many},
+  admin_api::reject_admin_request,
+  chain_head::{get_chain_head, record_chain_head},
+  database::{
+    commit_transaction, delete_many_with_session, find_all_with_session, find_one,
+    find_one_and_update_with_session, find_one_with_session, insert_many_with_session,
+    is_transient_transaction_error, update_one, update_one_with_session,
+  },
+  dispatcher::{get_signature_tolerance_secs, verify_body_signature},
   helper_functions::AppState,
+  metrics::{dispatcher_metrics, render_metrics},
+  realtime_outbox::{get_realtime_url, outbox_doc},
+  shutdown::is_shutting_down,
 };
 use log::{error, info, warn};
 use mongodb::{
   bson::doc,
-  options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertManyOptions},
-  Database,
+  error::Error as MongoErr,
+  options::{
+    FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertManyOptions, UpdateOptions,
+  },
+  ClientSession, Database,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use serde_json::{json, Value};
+use validator::{Validate, ValidationError};
 
 use crate::database::find_all;
 
 use snailquote::unescape;
 
-#[derive(Deserialize, Debug, Clone)]
+const DEFAULT_INGEST_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+const MAX_INGEST_TRANSACTION_ATTEMPTS: usize = 5;
+const EVENTS_INFO_FIELDS: [&str; 3] = ["_id", "contract_id", "reset_nonce"];
+
+#[derive(Deserialize, Debug, Clone, Validate)]
 pub struct TransactionBlock {
+  #[validate(range(min = 0))]
   pub block_number: i64,
+  #[validate(length(min = 1))]
   pub event_name: String,
+  #[validate(length(min = 1), custom = "validate_transaction_objects")]
   pub transactions: Vec<Value>,
+  #[serde(default)]
+  #[validate(length(min = 1))]
+  pub block_hash: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Validate)]
 pub struct Transactions {
+  #[validate(length(min = 1))]
   pub contract_id: String,
   pub reset_nonce: i64,
-  pub data: Vec<Value>,
+  #[validate]
+  pub data: Vec<TransactionBlock>,
+  #[serde(default)]
+  #[validate(range(min = 0))]
+  pub head_block: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct HeadNotice {
+  #[validate(length(min = 1))]
+  pub contract_id: String,
+  #[validate(range(min = 0))]
+  pub head_block: i64,
+}
+
+// transactions are stored as documents, so each one has to be a JSON object
+pub fn validate_transaction_objects(transactions: &[Value]) -> Result<(), ValidationError> {
+  if transactions.iter().all(Value::is_object) {
+    Ok(())
+  } else {
+    Err(ValidationError::new("transactions must be JSON objects"))
+  }
+}
+
+pub fn get_ingest_secret() -> Option<String> {
+  env::var("INGEST_SECRET")
+    .ok()
+    .filter(|secret| !secret.is_empty())
+}
+
+// a migration switch for rolling out write services with credentials, requests that carry none are
+// let through while it is set, credentials that are sent are still checked
+pub fn allows_unauthenticated_ingest() -> bool {
+  env::var("ALLOW_UNAUTHENTICATED_INGEST").is_ok_and(|value| value == "true")
+}
+
+pub fn get_ingest_max_body_bytes() -> usize {
+  env::var("INGEST_MAX_BODY_BYTES")
+    .ok()
+    .and_then(|value| value.parse::<usize>().ok())
+    .unwrap_or(DEFAULT_INGEST_MAX_BODY_BYTES)
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// write services send the shared secret in `x-msl-ingest-key`, or sign the raw body the way
+// webhooks are signed and send `x-msl-ingest-signature: t=<unix timestamp>,v1=<hex>`
+pub fn is_ingest_authorized(req: &HttpRequest, body: &[u8], secret: &str, now: i64) -> bool {
+  if let Some(key) = req.headers().get("x-msl-ingest-key") {
+    return constant_time_eq(key.as_bytes(), secret.as_bytes());
+  }
+  match req
+    .headers()
+    .get("x-msl-ingest-signature")
+    .and_then(|value| value.to_str().ok())
+  {
+    Some(signature) => {
+      verify_body_signature(signature, body, secret, get_signature_tolerance_secs(), now)
+    }
+    None => false,
+  }
+}
+
+fn ingest_error(status: StatusCode, message: String) -> HttpResponse {
+  HttpResponse::build(status).json(json!({ "message": message }))
+}
+
+// caps the body before buffering it, then authenticates it
+pub async fn read_ingest_body(
+  req: &HttpRequest,
+  mut body: web::Payload,
+) -> Result<web::BytesMut, HttpResponse> {
+  let max_body_bytes = get_ingest_max_body_bytes();
+  let too_large = || {
+    ingest_error(
+      StatusCode::PAYLOAD_TOO_LARGE,
+      format!("payload exceeds {} bytes", max_body_bytes),
+    )
+  };
+  let content_length = req
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<usize>().ok());
+  if content_length.is_some_and(|length| length > max_body_bytes) {
+    return Err(too_large());
+  }
+  let mut bytes = web::BytesMut::new();
+  while let Some(chunk) = body.next().await {
+    let chunk = chunk.map_err(|err| {
+      ingest_error(
+        StatusCode::BAD_REQUEST,
+        format!("could not read payload: {}", err),
+      )
+    })?;
+    if bytes.len() + chunk.len() > max_body_bytes {
+      return Err(too_large());
+    }
+    bytes.extend_from_slice(&chunk);
+  }
+
+  let has_credentials = ["x-msl-ingest-key", "x-msl-ingest-signature"]
+    .iter()
+    .any(|name| req.headers().contains_key(*name));
+  if !has_credentials && allows_unauthenticated_ingest() {
+    return Ok(bytes);
+  }
+  let secret = match get_ingest_secret() {
+    Some(secret) => secret,
+    None => {
+      error!("INGEST_SECRET is not set, refusing ingestion");
+      return Err(ingest_error(
+        StatusCode::UNAUTHORIZED,
+        "ingestion is not configured".to_string(),
+      ));
+    }
+  };
+  if !is_ingest_authorized(req, &bytes, &secret, Utc::now().timestamp()) {
+    return Err(ingest_error(
+      StatusCode::UNAUTHORIZED,
+      "invalid ingest credentials".to_string(),
+    ));
+  }
+  Ok(bytes)
+}
+
+pub async fn read_ingest_payload<T: DeserializeOwned + Validate>(
+  req: &HttpRequest,
+  body: web::Payload,
+) -> Result<T, HttpResponse> {
+  // write services retry on another replica while this one drains
+  if is_shutting_down() {
+    return Err(ingest_error(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the dispatcher is shutting down".to_string(),
+    ));
+  }
+  let bytes = read_ingest_body(req, body).await?;
+  let payload: T = serde_json::from_slice(&bytes)
+    .map_err(|err| ingest_error(StatusCode::BAD_REQUEST, format!("invalid payload: {}", err)))?;
+  if let Err(err) = payload.validate() {
+    return Err(HttpResponse::BadRequest().json(err));
+  }
+  Ok(payload)
 }
 
 pub fn filter_contract_info(
@@ -59,8 +235,8 @@ pub fn filter_contract_info(
   }
   let mut update_doc = doc! { "reset_nonce": payload.reset_nonce };
   let mut result_docs = vec![];
-  for record in &payload.data {
-    let transaction_block: TransactionBlock = serde_json::from_value(record.to_owned())?;
+  for transaction_block in &payload.data {
+    let transaction_block = transaction_block.clone();
     let event_name = transaction_block.event_name.clone();
     let received_block_number = transaction_block.block_number;
 
@@ -97,13 +273,20 @@ pub fn generate_dbdata_from_records(
     let locked_until = bson::DateTime::now();
 
     for item in subscriptions {
-      insert_docs.push(doc! {
+      if !subscription_wants_event(item, &transaction_block.event_name) {
+        continue;
+      }
+      let mut insert_doc = doc! {
         "subid": item.get_object_id("_id").unwrap().to_string(),
         "transactions": transactions_block_doc.clone(),
         "block_number": transaction_block.block_number,
         "event_name": transaction_block.event_name.clone(),
         "locked_until": locked_until,
-      })
+      };
+      if let Some(block_hash) = &transaction_block.block_hash {
+        insert_doc.insert("block_hash", block_hash);
+      }
+      insert_docs.push(insert_doc)
     }
     for tx in &transaction_block.transactions {
       send_transactions.push(tx.to_owned());
@@ -112,111 +295,798 @@ pub fn generate_dbdata_from_records(
   Ok((insert_docs, send_transactions))
 }
 
-#[get("/healthcheck")]
-pub async fn consumer_health_check() -> HttpResponse {
-  HttpResponse::Ok().body("web3cache dispatcher OK")
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AcceptedBlock {
+  pub block_number: i64,
+  pub event_name: String,
+  // copies queued for the subscriptions listening to the event
+  pub queued: usize,
+  // copies an earlier push had queued already
+  pub duplicates: usize,
 }
 
-#[post("/push-transactions")]
-pub async fn push_transactions(
-  _req: HttpRequest,
-  mut body: web::Payload,
-  data: Data<AppState>,
-) -> HttpResponse {
-  info!("push-transaction request received!");
-  let mut bytes = web::BytesMut::new();
-  while let Some(item) = body.next().await {
-    bytes.extend_from_slice(&item.unwrap());
-  }
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SkippedBlock {
+  pub block_number: i64,
+  pub event_name: String,
+  pub checkpoint: i64,
+}
 
-  let payload: Transactions = serde_json::from_slice(&bytes).unwrap();
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct IngestReport {
+  pub accepted: Vec<AcceptedBlock>,
+  pub skipped: Vec<SkippedBlock>,
+}
 
-  let contract_id = unescape(&payload.contract_id).unwrap();
+fn queued_block_key(doc: &Document) -> (String, i64, String) {
+  (
+    doc.get_str("subid").unwrap_or_default().to_string(),
+    get_i64_from_doc(doc, "block_number".to_string()),
+    doc.get_str("event_name").unwrap_or_default().to_string(),
+  )
+}
 
-  info!("Receiving transactions from {:?}\n", contract_id);
+// splits `insert_docs` into the ones to queue and the ones already queued, in `queued` or earlier in the batch
+pub fn drop_queued_duplicates(
+  insert_docs: Vec<Document>,
+  queued: &[Document],
+) -> (Vec<Document>, Vec<Document>) {
+  let mut keys: HashSet<(String, i64, String)> = queued.iter().map(queued_block_key).collect();
+  insert_docs
+    .into_iter()
+    .partition(|insert_doc| keys.insert(queued_block_key(insert_doc)))
+}
 
-  info!("reset_nonce: {}", payload.reset_nonce);
+pub fn build_ingest_report(
+  payload: &Transactions,
+  records: &[TransactionBlock],
+  events_info: Option<&Document>,
+  fresh: &[Document],
+  duplicates: &[Document],
+) -> IngestReport {
+  let same_block = |block: &TransactionBlock, doc: &Document| {
+    get_i64_from_doc(doc, "block_number".to_string()) == block.block_number
+      && doc.get_str("event_name").ok() == Some(block.event_name.as_str())
+  };
+  let mut report = IngestReport::default();
+  for block in records {
+    report.accepted.push(AcceptedBlock {
+      block_number: block.block_number,
+      event_name: block.event_name.clone(),
+      queued: fresh.iter().filter(|doc| same_block(block, doc)).count(),
+      duplicates: duplicates
+        .iter()
+        .filter(|doc| same_block(block, doc))
+        .count(),
+    });
+  }
+  for block in &payload.data {
+    let accepted = records.iter().any(|record| {
+      record.block_number == block.block_number && record.event_name == block.event_name
+    });
+    if !accepted {
+      report.skipped.push(SkippedBlock {
+        block_number: block.block_number,
+        event_name: block.event_name.clone(),
+        checkpoint: events_info
+          .map(|doc| get_i64_from_doc(doc, block.event_name.clone()))
+          .unwrap_or(-1),
+      });
+    }
+  }
+  report
+}
 
-  let filter = doc! { "contract_id": contract_id.clone(), "isActive": true };
-  let find_option = FindOptions::default();
-  let db: Database = data.db.clone();
+async fn queue_transaction_blocks(
+  db: &Database,
+  session: &mut ClientSession,
+  contract_id: &str,
+  payload: &Transactions,
+  subscriptions: &Vec<Document>,
+  notify_realtime: bool,
+) -> anyhow::Result<IngestReport> {
+  let events_info = find_one_with_session(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+    session,
+  )
+  .await?;
+  let (records, update_doc) = filter_contract_info(payload, events_info.clone())?;
+  let (insert_docs, send_transactions) = generate_dbdata_from_records(&records, subscriptions)?;
+
+  let mut queued = Vec::new();
+  if !insert_docs.is_empty() {
+    let sub_ids: BTreeSet<&str> = insert_docs
+      .iter()
+      .filter_map(|insert_doc| insert_doc.get_str("subid").ok())
+      .collect();
+    let block_numbers: BTreeSet<i64> = records.iter().map(|block| block.block_number).collect();
+    let mut find_option = FindOptions::default();
+    find_option.projection = Some(doc! { "subid": 1, "block_number": 1, "event_name": 1 });
+    queued = find_all_with_session(
+      db.collection("transactionblocks"),
+      doc! {
+        "subid": { "$in": sub_ids.into_iter().collect::<Vec<_>>() },
+        "block_number": { "$in": block_numbers.into_iter().collect::<Vec<_>>() },
+        "removed": { "$ne": true },
+      },
+      find_option,
+      session,
+    )
+    .await?;
+  }
+  let (fresh, duplicates) = drop_queued_duplicates(insert_docs, &queued);
+  info!(
+    "queueing {} blocks, {} already queued",
+    fresh.len(),
+    duplicates.len()
+  );
+
+  if !fresh.is_empty() {
+    insert_many_with_session(
+      db.collection("transactionblocks"),
+      &fresh,
+      InsertManyOptions::default(),
+      session,
+    )
+    .await?;
+  }
+  if !records.is_empty() {
+    let options = FindOneAndUpdateOptions::builder()
+      .upsert(Some(true))
+      .build();
+    find_one_and_update_with_session(
+      db.collection("events_info"),
+      doc! { "contract_id": contract_id },
+      doc! { "$set": update_doc },
+      Some(options),
+      session,
+    )
+    .await?;
+  }
+  if notify_realtime {
+    if let Some(notification) = outbox_doc(contract_id, &send_transactions)? {
+      insert_many_with_session(
+        db.collection("realtimeoutbox"),
+        &vec![notification],
+        InsertManyOptions::default(),
+        session,
+      )
+      .await?;
+    }
+  }
+
+  let report = build_ingest_report(payload, &records, events_info.as_ref(), &fresh, &duplicates);
+  Ok(report)
+}
 
-  let subscriptions = find_all(db.collection("subscriptions"), filter, find_option)
+// queues the accepted blocks, their realtime notification and the events_info checkpoint together,
+// or does none of it
+pub async fn ingest_transactions(
+  db: &Database,
+  contract_id: &str,
+  payload: &Transactions,
+  subscriptions: &Vec<Document>,
+  notify_realtime: bool,
+) -> anyhow::Result<IngestReport> {
+  let mut session = db
+    .collection::<Document>("transactionblocks")
+    .client()
+    .start_session(None)
+    .await?;
+  let mut attempt = 1;
+  loop {
+    session.start_transaction(None).await?;
+    let err = match queue_transaction_blocks(
+      db,
+      &mut session,
+      contract_id,
+      payload,
+      subscriptions,
+      notify_realtime,
+    )
     .await
-    .unwrap();
-  //info!("Subscriptions: {}", subscriptions.len());
-  let transaction_col = db.collection("transactionblocks");
+    {
+      Ok(ingested) => match commit_transaction(&mut session, MAX_INGEST_TRANSACTION_ATTEMPTS).await
+      {
+        Ok(()) => return Ok(ingested),
+        Err(err) => anyhow::Error::from(err),
+      },
+      Err(err) => {
+        // the server may have aborted the transaction already
+        _ = session.abort_transaction().await;
+        err
+      }
+    };
+    let transient = err
+      .downcast_ref::<MongoErr>()
+      .is_some_and(is_transient_transaction_error);
+    if !transient || attempt >= MAX_INGEST_TRANSACTION_ATTEMPTS {
+      return Err(err);
+    }
+    warn!("Retrying ingestion of {} after {:?}", contract_id, err);
+    attempt += 1;
+  }
+}
+
+#[derive(Deserialize, Debug, Clone, Validate)]
+pub struct RemovedBlock {
+  #[validate(range(min = 0))]
+  pub block_number: i64,
+  #[validate(length(min = 1))]
+  pub block_hash: String,
+}
+
+// every block above `fork_block` left the canonical chain, its replacements are pushed afterwards
+#[derive(Deserialize, Debug, Validate)]
+pub struct ReorgNotice {
+  #[validate(length(min = 1))]
+  pub contract_id: String,
+  #[validate(range(min = 0))]
+  pub fork_block: i64,
+  #[serde(default)]
+  #[validate]
+  pub removed_blocks: Vec<RemovedBlock>,
+}
+
+// events_info keeps the last accepted block of every event next to its own fields
+pub fn event_checkpoints(events_info: &Document) -> BTreeMap<String, i64> {
+  events_info
+    .keys()
+    .filter(|key| !EVENTS_INFO_FIELDS.contains(&key.as_str()))
+    .map(|key| (key.clone(), get_i64_from_doc(events_info, key.clone())))
+    .collect()
+}
+
+// events recorded past the fork go back to it so the replacement blocks are accepted
+pub fn rollback_events_info(events_info: &Document, fork_block: i64) -> Document {
+  let mut update_doc = doc! {};
+  for (event_name, block_number) in event_checkpoints(events_info) {
+    if block_number > fork_block {
+      update_doc.insert(event_name, fork_block);
+    }
+  }
+  update_doc
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EventCheckpoint {
+  pub block_number: i64,
+  // blocks between the chain head and the last accepted block
+  pub lag: Option<i64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Checkpoint {
+  pub contract_id: String,
+  // None until the first push
+  pub reset_nonce: Option<i64>,
+  pub head_block: Option<i64>,
+  pub events: BTreeMap<String, EventCheckpoint>,
+}
+
+pub fn build_checkpoint(
+  contract_id: &str,
+  events_info: Option<&Document>,
+  head_block: Option<i64>,
+) -> Checkpoint {
+  let events = events_info
+    .map(event_checkpoints)
+    .unwrap_or_default()
+    .into_iter()
+    .map(|(event_name, block_number)| {
+      let lag = head_block.map(|head| (head - block_number).max(0));
+      (event_name, EventCheckpoint { block_number, lag })
+    })
+    .collect();
+  Checkpoint {
+    contract_id: contract_id.to_string(),
+    reset_nonce: events_info.map(|doc| get_i64_from_doc(doc, "reset_nonce".to_string())),
+    head_block,
+    events,
+  }
+}
+
+pub async fn load_checkpoint(db: &Database, contract_id: &str) -> anyhow::Result<Checkpoint> {
+  let events_info = find_one(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await?;
+  let head_block = get_chain_head(db, contract_id).await?;
+  Ok(build_checkpoint(
+    contract_id,
+    events_info.as_ref(),
+    head_block,
+  ))
+}
+
+// bumps the reset nonce and forgets the event checkpoints, None when another writer changed them first
+pub async fn start_epoch(db: &Database, contract_id: &str) -> anyhow::Result<Option<i64>> {
+  let events_info = find_one(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await?;
+  let events_info = match events_info {
+    Some(events_info) => events_info,
+    None => {
+      let mut options = UpdateOptions::default();
+      options.upsert = Some(true);
+      let result = update_one(
+        db.collection("events_info"),
+        doc! { "contract_id": contract_id },
+        doc! { "$setOnInsert": { "reset_nonce": 1_i64 } },
+        options,
+      )
+      .await?;
+      return Ok(result.upserted_id.map(|_| 1));
+    }
+  };
 
-  let doc_result = find_one(
+  let reset_nonce = get_i64_from_doc(&events_info, "reset_nonce".to_string()) + 1;
+  let mut update_doc = doc! { "$set": { "reset_nonce": reset_nonce } };
+  let checkpoints = event_checkpoints(&events_info);
+  if !checkpoints.is_empty() {
+    let unset: Document = checkpoints
+      .keys()
+      .map(|key| (key.clone(), "".into()))
+      .collect();
+    update_doc.insert("$unset", unset);
+  }
+  let result = update_one(
     db.collection("events_info"),
     doc! {
-      "contract_id": contract_id.clone()
+      "_id": events_info.get("_id").cloned(),
+      "reset_nonce": events_info.get("reset_nonce").cloned(),
     },
+    update_doc,
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok((result.matched_count == 1).then_some(reset_nonce))
+}
+
+// `seen_blocks` in the order the subscriber got them, a delivered retraction cancels the block before it
+pub fn generate_retraction_docs(
+  sub_id: &str,
+  seen_blocks: &[Document],
+  notice: &ReorgNotice,
+) -> Vec<Document> {
+  let mut delivered: BTreeMap<(i64, String), Option<String>> = BTreeMap::new();
+  for block in seen_blocks {
+    let block_number = get_i64_from_doc(block, "block_number".to_string());
+    if block_number <= notice.fork_block {
+      continue;
+    }
+    let key = (
+      block_number,
+      block.get_str("event_name").unwrap_or_default().to_string(),
+    );
+    if block.get_bool("removed").unwrap_or(false) {
+      delivered.remove(&key);
+    } else {
+      let block_hash = block
+        .get_str("block_hash")
+        .ok()
+        .map(|hash| hash.to_string());
+      delivered.insert(key, block_hash);
+    }
+  }
+
+  let locked_until = bson::DateTime::now();
+  delivered
+    .into_iter()
+    .map(|((block_number, event_name), block_hash)| {
+      let block_hash = block_hash.or_else(|| {
+        notice
+          .removed_blocks
+          .iter()
+          .find(|removed| removed.block_number == block_number)
+          .map(|removed| removed.block_hash.clone())
+      });
+      let mut retraction = doc! {
+        "subid": sub_id,
+        "transactions": [],
+        "block_number": block_number,
+        "event_name": event_name,
+        "removed": true,
+        "locked_until": locked_until,
+      };
+      if let Some(block_hash) = block_hash {
+        retraction.insert("block_hash", block_hash);
+      }
+      retraction
+    })
+    .collect()
+}
+
+async fn apply_reorg(
+  db: &Database,
+  session: &mut ClientSession,
+  contract_id: &str,
+  notice: &ReorgNotice,
+) -> anyhow::Result<(u64, usize)> {
+  let subscriptions = find_all_with_session(
+    db.collection("subscriptions"),
+    doc! { "contract_id": contract_id },
+    FindOptions::default(),
+    session,
+  )
+  .await?;
+
+  let mut dropped = 0;
+  let mut retractions = Vec::new();
+  for subscription in &subscriptions {
+    let sub_id = subscription.get_object_id("_id")?.to_string();
+    let mut find_option = FindOptions::default();
+    find_option.sort = Some(doc! { "created_at": 1 });
+    let deliveries = find_all_with_session(
+      db.collection("deliveries"),
+      doc! { "subid": &sub_id, "success": true, "block_to": { "$gt": notice.fork_block } },
+      find_option,
+      session,
+    )
+    .await?;
+    let mut seen_blocks: Vec<Document> = deliveries
+      .iter()
+      .filter_map(|delivery| delivery.get_array("blocks").ok())
+      .flatten()
+      .filter_map(|block| block.as_document().cloned())
+      .collect();
+
+    // an attempted block may have reached the subscriber even if the delivery was not confirmed
+    let orphaned = doc! {
+      "subid": &sub_id,
+      "block_number": { "$gt": notice.fork_block },
+      "removed": { "$ne": true },
+    };
+    let mut attempted = orphaned.clone();
+    attempted.insert("sequence", doc! { "$exists": true });
+    seen_blocks.extend(
+      find_all_with_session(
+        db.collection("transactionblocks"),
+        attempted,
+        FindOptions::default(),
+        session,
+      )
+      .await?,
+    );
+    dropped += delete_many_with_session(db.collection("transactionblocks"), orphaned, session)
+      .await?
+      .deleted_count;
+
+    // retractions of an earlier notice for the same blocks are still queued, a duplicate key
+    // error would abort the transaction
+    let queued_retractions = find_all_with_session(
+      db.collection("transactionblocks"),
+      doc! { "subid": &sub_id, "block_number": { "$gt": notice.fork_block }, "removed": true },
+      FindOptions::default(),
+      session,
+    )
+    .await?;
+    retractions.extend(
+      generate_retraction_docs(&sub_id, &seen_blocks, notice)
+        .into_iter()
+        .filter(|retraction| {
+          !queued_retractions.iter().any(|queued| {
+            queued.get("block_number") == retraction.get("block_number")
+              && queued.get("event_name") == retraction.get("event_name")
+          })
+        }),
+    );
+  }
+
+  if !retractions.is_empty() {
+    insert_many_with_session(
+      db.collection("transactionblocks"),
+      &retractions,
+      InsertManyOptions::default(),
+      session,
+    )
+    .await?;
+  }
+
+  let events_info = find_one_with_session(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
     FindOneOptions::default(),
+    session,
   )
-  .await
-  .unwrap_or_else(|_| None);
-
-  let result = filter_contract_info(&payload, doc_result);
-  if result.is_err() {
-    return HttpResponse::InternalServerError().finish();
-  }
-  let (records, update_doc) = result.unwrap();
-
-  let result = generate_dbdata_from_records(&records, &subscriptions);
-  if result.is_err() {
-    return HttpResponse::InternalServerError().finish();
-  }
-  let (insert_docs, send_transactions) = result.unwrap();
-
-  // reqwest here
-  tokio::spawn(async move {
-    let realtime_url = env::var("REALTIME_URL").unwrap();
-    //for tx in transaction_block.transactions.iter() {
-    let client = reqwest::Client::new();
-    let _res = client
-      .post(format!("{}/notify-transactions", realtime_url))
-      .json(&json!({ "transactions": send_transactions }))
-      .send()
-      .await;
-    ////}
-    //info!("sending to realtime: {:?}", res);
-  });
+  .await?;
+  if let Some(events_info) = events_info {
+    let update_doc = rollback_events_info(&events_info, notice.fork_block);
+    if !update_doc.is_empty() {
+      update_one_with_session(
+        db.collection("events_info"),
+        doc! { "contract_id": contract_id },
+        doc! { "$set": update_doc },
+        UpdateOptions::default(),
+        session,
+      )
+      .await?;
+    }
+  }
+  // the orphaned head does not confirm anything, the next head report moves it up again
+  update_one_with_session(
+    db.collection("chainheads"),
+    doc! { "contract_id": contract_id },
+    doc! { "$min": { "head_block": notice.fork_block } },
+    UpdateOptions::default(),
+    session,
+  )
+  .await?;
 
-  if !insert_docs.is_empty() {
-    let mut insert_many_options = InsertManyOptions::default();
-    insert_many_options.ordered = Some(false);
-    info!("insert_docs.len() = {}", insert_docs.len());
-    if let Err(err) = insert_many(transaction_col.clone(), &insert_docs, insert_many_options).await
-    {
-      error!("err.kind: {:?}!", err.kind);
+  Ok((dropped, retractions.len()))
+}
 
-      if !err.kind.to_string().contains(" code: 11000,") {
-        return HttpResponse::BadRequest().json(json!({
-            "message": "error inserting"
-        }));
+// drops the orphaned blocks still queued and queues retractions for the ones subscribers may have
+// seen, together with the rolled back checkpoint and chain head, or does none of it
+pub async fn handle_reorg(db: &Database, notice: &ReorgNotice) -> anyhow::Result<(u64, usize)> {
+  let contract_id = unescape(&notice.contract_id)?;
+  let mut session = db
+    .collection::<Document>("transactionblocks")
+    .client()
+    .start_session(None)
+    .await?;
+  let mut attempt = 1;
+  loop {
+    session.start_transaction(None).await?;
+    let err = match apply_reorg(db, &mut session, &contract_id, notice).await {
+      Ok(result) => match commit_transaction(&mut session, MAX_INGEST_TRANSACTION_ATTEMPTS).await {
+        Ok(()) => return Ok(result),
+        Err(err) => anyhow::Error::from(err),
+      },
+      Err(err) => {
+        // the server may have aborted the transaction already
+        _ = session.abort_transaction().await;
+        err
       }
+    };
+    let transient = err
+      .downcast_ref::<MongoErr>()
+      .is_some_and(is_transient_transaction_error);
+    if !transient || attempt >= MAX_INGEST_TRANSACTION_ATTEMPTS {
+      return Err(err);
     }
+    warn!("Retrying the reorg of {} after {:?}", contract_id, err);
+    attempt += 1;
+  }
+}
 
-    let options = FindOneAndUpdateOptions::builder()
-      .upsert(Some(true))
-      .build();
+// shared by the HTTP and WebSocket ingestion paths, `contract_id` is the unescaped one
+pub async fn accept_transactions(
+  db: &Database,
+  contract_id: &str,
+  payload: &Transactions,
+) -> anyhow::Result<IngestReport> {
+  info!("Receiving transactions from {:?}\n", contract_id);
 
-    let result = find_one_and_update(
-      db.collection("events_info"),
-      doc! { "contract_id": contract_id },
-      doc! { "$set": update_doc },
-      Some(options),
+  info!("reset_nonce: {}", payload.reset_nonce);
+
+  if let Some(head_block) = payload.head_block {
+    if let Err(err) = record_chain_head(db, contract_id, head_block).await {
+      error!("Failed to record chain head: {:?}", err);
+    }
+  }
+
+  let filter = doc! { "contract_id": contract_id, "isActive": true };
+  let find_option = FindOptions::default();
+  let subscriptions = find_all(db.collection("subscriptions"), filter, find_option).await?;
+  //info!("Subscriptions: {}", subscriptions.len());
+
+  let report = ingest_transactions(
+    db,
+    contract_id,
+    payload,
+    &subscriptions,
+    get_realtime_url().is_some(),
+  )
+  .await?;
+  info!(
+    "accepted {} blocks, skipped {}",
+    report.accepted.len(),
+    report.skipped.len()
+  );
+  dispatcher_metrics().lock().unwrap().record_ingestion(
+    contract_id,
+    report.accepted.len(),
+    report.skipped.len(),
+  );
+  Ok(report)
+}
+
+#[get("/healthcheck")]
+pub async fn consumer_health_check() -> HttpResponse {
+  if is_shutting_down() {
+    return HttpResponse::ServiceUnavailable().body("web3cache dispatcher shutting down");
+  }
+  HttpResponse::Ok().body("web3cache dispatcher OK")
+}
+
+// scrapes authenticate like the admin api, the pending figures come from the refresher
+#[get("/metrics")]
+pub async fn metrics(req: HttpRequest) -> HttpResponse {
+  if let Some(response) = reject_admin_request(&req) {
+    return response;
+  }
+  let body = {
+    let metrics = dispatcher_metrics().lock().unwrap();
+    render_metrics(
+      &metrics,
+      &metrics.pending_blocks,
+      Utc::now().timestamp_millis(),
     )
-    .await;
+  };
+  HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(body)
+}
+
+#[post("/push-transactions")]
+pub async fn push_transactions(
+  req: HttpRequest,
+  body: web::Payload,
+  data: Data<AppState>,
+) -> HttpResponse {
+  info!("push-transaction request received!");
+  let payload: Transactions = match read_ingest_payload(&req, body).await {
+    Ok(payload) => payload,
+    Err(response) => return response,
+  };
+
+  let contract_id = match unescape(&payload.contract_id) {
+    Ok(contract_id) => contract_id,
+    Err(err) => {
+      return ingest_error(
+        StatusCode::BAD_REQUEST,
+        format!("invalid contract_id: {}", err),
+      )
+    }
+  };
+
+  match accept_transactions(&data.db, &contract_id, &payload).await {
+    Ok(report) => HttpResponse::Ok().json(report),
+    Err(err) => {
+      error!("Failed to ingest transactions: {:?}", err);
+      ingest_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "ingestion failed, no blocks were accepted".to_string(),
+      )
+    }
+  }
+}
+
+#[get("/checkpoint/{contract_id}")]
+pub async fn get_checkpoint(
+  req: HttpRequest,
+  body: web::Payload,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Err(response) = read_ingest_body(&req, body).await {
+    return response;
+  }
+  let contract_id = match unescape(&path) {
+    Ok(contract_id) => contract_id,
+    Err(err) => {
+      return ingest_error(
+        StatusCode::BAD_REQUEST,
+        format!("invalid contract_id: {}", err),
+      )
+    }
+  };
+  match load_checkpoint(&data.db, &contract_id).await {
+    Ok(checkpoint) => HttpResponse::Ok().json(checkpoint),
+    Err(err) => {
+      error!("Failed to load checkpoint: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
+
+#[post("/checkpoint/{contract_id}/epoch")]
+pub async fn start_checkpoint_epoch(
+  req: HttpRequest,
+  body: web::Payload,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Err(response) = read_ingest_body(&req, body).await {
+    return response;
+  }
+  let contract_id = match unescape(&path) {
+    Ok(contract_id) => contract_id,
+    Err(err) => {
+      return ingest_error(
+        StatusCode::BAD_REQUEST,
+        format!("invalid contract_id: {}", err),
+      )
+    }
+  };
+  match start_epoch(&data.db, &contract_id).await {
+    Ok(Some(reset_nonce)) => {
+      info!("Started epoch {} of {:?}", reset_nonce, contract_id);
+      match load_checkpoint(&data.db, &contract_id).await {
+        Ok(checkpoint) => HttpResponse::Ok().json(checkpoint),
+        Err(err) => {
+          error!("Failed to load checkpoint: {:?}", err);
+          HttpResponse::InternalServerError().finish()
+        }
+      }
+    }
+    Ok(None) => ingest_error(
+      StatusCode::CONFLICT,
+      "the checkpoint changed while starting the epoch, retry".to_string(),
+    ),
+    Err(err) => {
+      error!("Failed to start epoch: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
 
-    error!("{:?}", result);
+#[post("/push-head")]
+pub async fn push_head(req: HttpRequest, body: web::Payload, data: Data<AppState>) -> HttpResponse {
+  let notice: HeadNotice = match read_ingest_payload(&req, body).await {
+    Ok(notice) => notice,
+    Err(response) => return response,
+  };
+  let contract_id = match unescape(&notice.contract_id) {
+    Ok(contract_id) => contract_id,
+    Err(err) => {
+      return ingest_error(
+        StatusCode::BAD_REQUEST,
+        format!("invalid contract_id: {}", err),
+      )
+    }
+  };
+  match record_chain_head(&data.db, &contract_id, notice.head_block).await {
+    Ok(()) => HttpResponse::Ok().finish(),
+    Err(err) => {
+      error!("Failed to record chain head: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
   }
+}
+
+#[post("/push-reorg")]
+pub async fn push_reorg(
+  req: HttpRequest,
+  body: web::Payload,
+  data: Data<AppState>,
+) -> HttpResponse {
+  let notice: ReorgNotice = match read_ingest_payload(&req, body).await {
+    Ok(notice) => notice,
+    Err(response) => return response,
+  };
+  info!(
+    "Reorg of {:?} at block {}, {} blocks removed",
+    notice.contract_id,
+    notice.fork_block,
+    notice.removed_blocks.len()
+  );
 
-  HttpResponse::Ok().finish()
+  match handle_reorg(&data.db, &notice).await {
+    Ok((dropped, retracted)) => {
+      info!(
+        "Dropped {} queued blocks and queued {} retractions",
+        dropped, retracted
+      );
+      HttpResponse::Ok().json(json!({ "dropped": dropped, "retracted": retracted }))
+    }
+    Err(err) => {
+      error!("Failed to handle reorg: {:?}", err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
 }
 
 #[cfg(test)]
+#[allow(unused_imports, clippy::get_first)]
 mod tests {
   use super::*;
   use bson::{doc, oid::ObjectId, Document};
@@ -232,6 +1102,7 @@ mod tests {
           json!({"transaction_id": "tx1", "amount": 100}),
           json!({"transaction_id": "tx2", "amount": 200}),
         ],
+        block_hash: None,
       },
       TransactionBlock {
         block_number: 2,
@@ -240,6 +1111,7 @@ mod tests {
           json!({"transaction_id": "tx3", "amount": 300}),
           json!({"transaction_id": "tx4", "amount": 400}),
         ],
+        block_hash: None,
       },
     ]
   }
@@ -262,6 +1134,7 @@ mod tests {
     let payload = Transactions {
       contract_id: "test_contract".to_string(),
       reset_nonce: 1,
+      head_block: None,
       data: vec![],
     };
 
@@ -286,18 +1159,20 @@ mod tests {
     let payload = Transactions {
       contract_id: "test_contract".to_string(),
       reset_nonce: 1,
-      data: vec![
-        json!({
+      head_block: None,
+      data: serde_json::from_value(json!([
+        {
             "event_name": "Event1",
             "block_number": 5,
             "transactions": [],
-        }),
-        json!({
+        },
+        {
             "event_name": "Event2",
             "block_number": 3,
             "transactions": [],
-        }),
-      ],
+        },
+      ]))
+      .unwrap(),
     };
 
     let result = filter_contract_info(&payload, None)?;
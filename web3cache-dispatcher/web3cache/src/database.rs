@@ -1,15 +1,18 @@
-use bson::doc;
+use bson::{doc, Bson};
 use futures::stream::TryStreamExt;
 use log::info;
-use mongodb::error::Error as MongoErr;
+use mongodb::error::{
+  Error as MongoErr, ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR,
+  UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
 use mongodb::options::{
   FindOneAndUpdateOptions, FindOptions, IndexOptions, InsertManyOptions, UpdateOptions,
 };
-use mongodb::results::{DeleteResult, InsertManyResult, UpdateResult};
+use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
 use mongodb::{bson::Document, options::FindOneOptions};
 use mongodb::{options::ClientOptions, Client};
-use mongodb::{Collection, Database, IndexModel};
-use std::env;
+use mongodb::{ClientSession, Collection, Database, IndexModel};
+use std::{env, time::Duration};
 extern crate dotenv;
 use dotenv::dotenv;
 
@@ -60,7 +63,7 @@ pub async fn find_all(
   filter: Document,
   option: FindOptions,
 ) -> Result<Vec<Document>, MongoErr> {
-  let cursor = col.find(filter, option).await.unwrap();
+  let cursor = col.find(filter, option).await?;
   let results: Vec<Document> = cursor.try_collect().await?;
   Ok(results)
 }
@@ -106,19 +109,179 @@ pub async fn insert_many(
   col.clone().insert_many(docs, option).await
 }
 
+pub async fn find_one_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  option: FindOneOptions,
+  session: &mut ClientSession,
+) -> Result<Option<Document>, MongoErr> {
+  col.find_one_with_session(filter, option, session).await
+}
+
+pub async fn find_all_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  option: FindOptions,
+  session: &mut ClientSession,
+) -> Result<Vec<Document>, MongoErr> {
+  let mut cursor = col.find_with_session(filter, option, session).await?;
+  let results: Vec<Document> = cursor.stream(session).try_collect().await?;
+  Ok(results)
+}
+
+pub async fn find_one_and_update_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  update: Document,
+  option: Option<FindOneAndUpdateOptions>,
+  session: &mut ClientSession,
+) -> Result<Option<Document>, MongoErr> {
+  col
+    .find_one_and_update_with_session(filter, update, option, session)
+    .await
+}
+
+pub async fn insert_many_with_session(
+  col: Collection<Document>,
+  docs: &Vec<Document>,
+  option: InsertManyOptions,
+  session: &mut ClientSession,
+) -> Result<InsertManyResult, MongoErr> {
+  col.insert_many_with_session(docs, option, session).await
+}
+
+pub async fn update_one_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  doc: Document,
+  option: UpdateOptions,
+  session: &mut ClientSession,
+) -> Result<UpdateResult, MongoErr> {
+  col
+    .update_one_with_session(filter, doc, option, session)
+    .await
+}
+
+pub async fn insert_one_with_session(
+  col: Collection<Document>,
+  doc: &Document,
+  session: &mut ClientSession,
+) -> Result<InsertOneResult, MongoErr> {
+  col.insert_one_with_session(doc, None, session).await
+}
+
+pub async fn delete_many_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  session: &mut ClientSession,
+) -> Result<DeleteResult, MongoErr> {
+  col.delete_many_with_session(filter, None, session).await
+}
+
+pub async fn distinct(
+  col: Collection<Document>,
+  field_name: &str,
+  filter: Document,
+) -> Result<Vec<Bson>, MongoErr> {
+  col.distinct(field_name, filter, None).await
+}
+
+pub fn is_duplicate_key_error(err: &MongoErr) -> bool {
+  match err.kind.as_ref() {
+    ErrorKind::BulkWrite(failure) => {
+      failure.write_concern_error.is_none()
+        && failure
+          .write_errors
+          .as_ref()
+          .map(|errors| errors.iter().all(|error| error.code == 11000))
+          .unwrap_or(false)
+    }
+    ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == 11000,
+    _ => false,
+  }
+}
+
+// the whole transaction can be run again, e.g. after a write conflict with a concurrent push
+pub fn is_transient_transaction_error(err: &MongoErr) -> bool {
+  err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+// the commit may have been applied, committing again is safe
+pub fn is_unknown_commit_result(err: &MongoErr) -> bool {
+  err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+}
+
+// commits again while the outcome of the previous commit is unknown
+pub async fn commit_transaction(
+  session: &mut ClientSession,
+  max_attempts: usize,
+) -> Result<(), MongoErr> {
+  let mut result = session.commit_transaction().await;
+  for _ in 1..max_attempts {
+    if !result.as_ref().is_err_and(is_unknown_commit_result) {
+      break;
+    }
+    result = session.commit_transaction().await;
+  }
+  result
+}
+
+pub fn get_deliveries_ttl_secs() -> u64 {
+  env::var("DELIVERIES_TTL_SECS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(7 * 24 * 3600)
+}
+
 pub async fn setup_indexes(db: &Database) -> Result<(), MongoErr> {
   let transaction_col: Collection<Document> = db.collection("transactionblocks");
 
   //create indexes
+  // retractions are queued next to the blocks replacing them, so `removed` is part of the key
+  _ = transaction_col
+    .drop_index("subid_1_block_number_1_event_name_1", None)
+    .await;
   let mut index_model_options = IndexOptions::default();
   index_model_options.unique = Some(true);
   let mut index_model_keys = IndexModel::default();
-  index_model_keys.keys = doc! { "subid": 1, "block_number": 1, "event_name": 1, };
+  index_model_keys.keys = doc! { "subid": 1, "block_number": 1, "event_name": 1, "removed": 1 };
   index_model_keys.options = Some(index_model_options.clone());
   transaction_col
     .clone()
     .create_index(index_model_keys, None)
     .await?;
 
+  let dead_letters_col: Collection<Document> = db.collection("deadletters");
+  let mut dead_letters_keys = IndexModel::default();
+  dead_letters_keys.keys = doc! { "subid": 1, "dead_lettered_at": -1 };
+  dead_letters_col
+    .create_index(dead_letters_keys, None)
+    .await?;
+
+  let deliveries_col: Collection<Document> = db.collection("deliveries");
+  let mut deliveries_keys = IndexModel::default();
+  deliveries_keys.keys = doc! { "subid": 1, "created_at": -1 };
+  deliveries_col.create_index(deliveries_keys, None).await?;
+
+  // delivery history is kept for a bounded time only
+  let mut deliveries_ttl_options = IndexOptions::default();
+  deliveries_ttl_options.expire_after = Some(Duration::from_secs(get_deliveries_ttl_secs()));
+  let mut deliveries_ttl_keys = IndexModel::default();
+  deliveries_ttl_keys.keys = doc! { "created_at": 1 };
+  deliveries_ttl_keys.options = Some(deliveries_ttl_options);
+  deliveries_col
+    .create_index(deliveries_ttl_keys, None)
+    .await?;
+
+  // expired leases can already be taken over, this only cleans up leftovers
+  let mut leases_ttl_options = IndexOptions::default();
+  leases_ttl_options.expire_after = Some(Duration::from_secs(3600));
+  let mut leases_ttl_keys = IndexModel::default();
+  leases_ttl_keys.keys = doc! { "expires_at": 1 };
+  leases_ttl_keys.options = Some(leases_ttl_options);
+  db.collection::<Document>("dispatcherleases")
+    .create_index(leases_ttl_keys, None)
+    .await?;
+
   Ok(())
 }
@@ -0,0 +1,365 @@
+use crate::{
+  circuit_breaker::breaker_key,
+  dispatcher::{http_client, parse_retry_after, truncate_response, DeliveryOutcome, FailureKind},
+};
+use async_trait::async_trait;
+use bson::Document;
+use chrono::Utc;
+use reqwest::{
+  header::{HeaderMap, RETRY_AFTER},
+  StatusCode, Url,
+};
+use serde::Deserialize;
+use std::{
+  env,
+  time::{Duration, Instant},
+};
+
+const MAX_RESPONSE_EXCERPT_CHARS: usize = 512;
+
+// where a subscription is delivered, stored under `destination`, the webhook `url` when missing
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Destination {
+  Http,
+  Kafka { topic: String },
+  Nats { subject: String },
+  RedisStream { stream: String },
+}
+
+impl Destination {
+  pub fn from_subscription(subscription: &Document) -> anyhow::Result<Self> {
+    match subscription.get_document("destination") {
+      Ok(destination) => Ok(bson::from_document(destination.clone())?),
+      Err(_) => Ok(Destination::Http),
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    match self {
+      Destination::Http => true,
+      Destination::Kafka { .. } => cfg!(feature = "kafka"),
+      Destination::Nats { .. } => cfg!(feature = "nats"),
+      Destination::RedisStream { .. } => cfg!(feature = "redis-streams"),
+    }
+  }
+
+  // webhooks share a circuit per host, broker destinations one per broker address
+  pub fn breaker_key(&self, subscription: &Document) -> Option<String> {
+    match self {
+      Destination::Http => breaker_key(subscription.get_str("url").ok()?),
+      Destination::Kafka { .. } => Some(format!("kafka:{}", broker_address("KAFKA_BROKERS"))),
+      Destination::Nats { .. } => Some(format!("nats:{}", broker_address("NATS_URL"))),
+      Destination::RedisStream { .. } => Some(format!("redis:{}", broker_address("REDIS_URL"))),
+    }
+  }
+}
+
+// host and port of a broker url without its credentials, kafka's `host:port,host:port` lists as they are
+pub fn broker_address(variable: &str) -> String {
+  let address = env::var(variable).unwrap_or_default();
+  match Url::parse(&address) {
+    Ok(url) => match (url.host_str(), url.port()) {
+      (Some(host), Some(port)) => format!("{}:{}", host, port),
+      (Some(host), None) => host.to_string(),
+      (None, _) => address,
+    },
+    Err(_) => address,
+  }
+}
+
+// a signed batch ready to leave, the headers travel as message headers on brokers
+pub struct Delivery<'a> {
+  pub sub_id: &'a str,
+  pub headers: HeaderMap,
+  pub body: Vec<u8>,
+  pub timeout: Duration,
+}
+
+#[async_trait]
+pub trait DeliverySink: Send + Sync {
+  async fn deliver(&self, delivery: Delivery<'_>) -> DeliveryOutcome;
+}
+
+pub fn failed_outcome(failure: FailureKind, error: String, started_at: Instant) -> DeliveryOutcome {
+  DeliveryOutcome {
+    is_good: false,
+    error: Some(error),
+    failure: Some(failure),
+    latency_ms: started_at.elapsed().as_millis() as i64,
+    ..Default::default()
+  }
+}
+
+pub fn delivered_outcome(started_at: Instant) -> DeliveryOutcome {
+  DeliveryOutcome {
+    is_good: true,
+    latency_ms: started_at.elapsed().as_millis() as i64,
+    ..Default::default()
+  }
+}
+
+pub async fn sink_for(
+  destination: &Destination,
+  subscription: &Document,
+) -> anyhow::Result<Box<dyn DeliverySink>> {
+  match destination {
+    Destination::Http => Ok(Box::new(HttpSink {
+      url: subscription.get_str("url")?.to_string(),
+    })),
+    #[cfg(feature = "kafka")]
+    Destination::Kafka { topic } => Ok(Box::new(kafka::KafkaSink {
+      producer: kafka::producer().await?,
+      topic: topic.clone(),
+    })),
+    #[cfg(feature = "nats")]
+    Destination::Nats { subject } => Ok(Box::new(nats::NatsSink {
+      jetstream: nats::jetstream().await?,
+      subject: subject.clone(),
+    })),
+    #[cfg(feature = "redis-streams")]
+    Destination::RedisStream { stream } => Ok(Box::new(redis_streams::RedisStreamSink {
+      connection: redis_streams::connection().await?,
+      stream: stream.clone(),
+    })),
+    #[allow(unreachable_patterns)]
+    _ => Err(anyhow::anyhow!(
+      "{:?} delivery is not enabled in this build",
+      destination
+    )),
+  }
+}
+
+pub struct HttpSink {
+  pub url: String,
+}
+
+#[async_trait]
+impl DeliverySink for HttpSink {
+  async fn deliver(&self, delivery: Delivery<'_>) -> DeliveryOutcome {
+    let started_at = Instant::now();
+    let res = http_client()
+      .post(&self.url)
+      .timeout(delivery.timeout)
+      .headers(delivery.headers)
+      .body(delivery.body)
+      .send()
+      .await;
+
+    match res {
+      Ok(response) => {
+        let status = response.status();
+        let is_good = status.is_success();
+        let retry_after = match status {
+          StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now())),
+          _ => None,
+        };
+        let body = response.text().await.unwrap_or_default();
+        DeliveryOutcome {
+          is_good,
+          status: Some(status.as_u16()),
+          error: if is_good {
+            None
+          } else {
+            Some(format!("webhook responded with HTTP {}", status))
+          },
+          failure: (!is_good).then(|| FailureKind::from_status(status)),
+          retry_after,
+          latency_ms: started_at.elapsed().as_millis() as i64,
+          response_excerpt: Some(truncate_response(&body, MAX_RESPONSE_EXCERPT_CHARS)),
+        }
+      }
+      Err(err) => failed_outcome(
+        FailureKind::from_transport_error(&err),
+        err.to_string(),
+        started_at,
+      ),
+    }
+  }
+}
+
+#[cfg(feature = "kafka")]
+pub mod kafka {
+  use super::{delivered_outcome, failed_outcome, Delivery, DeliverySink};
+  use crate::dispatcher::{DeliveryOutcome, FailureKind};
+  use async_trait::async_trait;
+  use rdkafka::{
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig,
+  };
+  use std::{env, time::Instant};
+  use tokio::sync::OnceCell;
+
+  static PRODUCER: OnceCell<FutureProducer> = OnceCell::const_new();
+
+  pub async fn producer() -> anyhow::Result<&'static FutureProducer> {
+    PRODUCER
+      .get_or_try_init(|| async {
+        let brokers = env::var("KAFKA_BROKERS")?;
+        Ok(
+          ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true")
+            .create()?,
+        )
+      })
+      .await
+  }
+
+  pub struct KafkaSink {
+    pub producer: &'static FutureProducer,
+    pub topic: String,
+  }
+
+  #[async_trait]
+  impl DeliverySink for KafkaSink {
+    async fn deliver(&self, delivery: Delivery<'_>) -> DeliveryOutcome {
+      let started_at = Instant::now();
+      let mut headers = OwnedHeaders::new();
+      for (name, value) in delivery.headers.iter() {
+        headers = headers.insert(Header {
+          key: name.as_str(),
+          value: Some(value.as_bytes()),
+        });
+      }
+      // keyed by subscription so a subscription's batches stay ordered on one partition
+      let record = FutureRecord::to(&self.topic)
+        .key(delivery.sub_id)
+        .payload(&delivery.body)
+        .headers(headers);
+      match self.producer.send(record, delivery.timeout).await {
+        Ok(_) => delivered_outcome(started_at),
+        Err((err, _)) => {
+          let failure = match err {
+            KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut) => {
+              FailureKind::Timeout
+            }
+            _ => FailureKind::Transport,
+          };
+          failed_outcome(failure, err.to_string(), started_at)
+        }
+      }
+    }
+  }
+}
+
+#[cfg(feature = "nats")]
+pub mod nats {
+  use super::{delivered_outcome, failed_outcome, Delivery, DeliverySink};
+  use crate::dispatcher::{DeliveryOutcome, FailureKind};
+  use async_nats::jetstream::{self, Context};
+  use async_trait::async_trait;
+  use std::{env, time::Instant};
+  use tokio::{sync::OnceCell, time::timeout};
+
+  static JETSTREAM: OnceCell<Context> = OnceCell::const_new();
+
+  pub async fn jetstream() -> anyhow::Result<&'static Context> {
+    JETSTREAM
+      .get_or_try_init(|| async {
+        let client = async_nats::connect(env::var("NATS_URL")?).await?;
+        Ok(jetstream::new(client))
+      })
+      .await
+  }
+
+  pub struct NatsSink {
+    pub jetstream: &'static Context,
+    pub subject: String,
+  }
+
+  #[async_trait]
+  impl DeliverySink for NatsSink {
+    async fn deliver(&self, delivery: Delivery<'_>) -> DeliveryOutcome {
+      let started_at = Instant::now();
+      let mut headers = async_nats::HeaderMap::new();
+      for (name, value) in delivery.headers.iter() {
+        if let Ok(value) = value.to_str() {
+          headers.insert(name.as_str(), value);
+        }
+      }
+      // published through jetstream so the batch only counts as delivered once the stream stored it
+      let publish = async {
+        self
+          .jetstream
+          .publish_with_headers(self.subject.clone(), headers, delivery.body.into())
+          .await?
+          .await?;
+        anyhow::Ok(())
+      };
+      match timeout(delivery.timeout, publish).await {
+        Ok(Ok(())) => delivered_outcome(started_at),
+        Ok(Err(err)) => failed_outcome(FailureKind::Transport, err.to_string(), started_at),
+        Err(_) => failed_outcome(
+          FailureKind::Timeout,
+          "nats publish timed out".to_string(),
+          started_at,
+        ),
+      }
+    }
+  }
+}
+
+#[cfg(feature = "redis-streams")]
+pub mod redis_streams {
+  use super::{delivered_outcome, failed_outcome, Delivery, DeliverySink};
+  use crate::dispatcher::{DeliveryOutcome, FailureKind};
+  use async_trait::async_trait;
+  use redis::{aio::ConnectionManager, AsyncCommands};
+  use std::{env, time::Instant};
+  use tokio::{sync::OnceCell, time::timeout};
+
+  static CONNECTION: OnceCell<ConnectionManager> = OnceCell::const_new();
+
+  pub async fn connection() -> anyhow::Result<ConnectionManager> {
+    let connection = CONNECTION
+      .get_or_try_init(|| async {
+        let client = redis::Client::open(env::var("REDIS_URL")?)?;
+        anyhow::Ok(ConnectionManager::new(client).await?)
+      })
+      .await?;
+    Ok(connection.clone())
+  }
+
+  pub struct RedisStreamSink {
+    pub connection: ConnectionManager,
+    pub stream: String,
+  }
+
+  #[async_trait]
+  impl DeliverySink for RedisStreamSink {
+    async fn deliver(&self, delivery: Delivery<'_>) -> DeliveryOutcome {
+      let started_at = Instant::now();
+      let mut fields: Vec<(String, Vec<u8>)> = delivery
+        .headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+      fields.push(("body".to_string(), delivery.body));
+      let mut connection = self.connection.clone();
+      let add = connection.xadd::<_, _, _, _, String>(&self.stream, "*", &fields);
+      match timeout(delivery.timeout, add).await {
+        Ok(Ok(_)) => delivered_outcome(started_at),
+        Ok(Err(err)) => {
+          let failure = if err.is_timeout() {
+            FailureKind::Timeout
+          } else {
+            FailureKind::Transport
+          };
+          failed_outcome(failure, err.to_string(), started_at)
+        }
+        Err(_) => failed_outcome(
+          FailureKind::Timeout,
+          "redis XADD timed out".to_string(),
+          started_at,
+        ),
+      }
+    }
+  }
+}
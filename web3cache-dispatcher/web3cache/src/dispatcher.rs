@@ -1,26 +1,58 @@
 use crate::{
-  database::{delete_many, find_all, find_one, update_many, update_one},
-  helper_functions::get_i64_from_doc,
+  admin_api::is_delivery_paused,
+  chain_head::{
+    confirmed_height, deliverable_blocks_filter, get_chain_head, get_confirmation_poll_millis,
+    get_confirmations,
+  },
+  circuit_breaker::{circuit_breakers, ProbeGuard},
+  database::{
+    commit_transaction, delete_many, delete_many_with_session, distinct, find_all, find_one,
+    find_one_and_update, insert_one_with_session, is_transient_transaction_error, update_many,
+  },
+  delivery_sink::{failed_outcome, sink_for, Delivery, Destination},
+  helper_functions::{get_i64_from_doc, get_topics_from_doc},
+  lease::{acquire_lease, acquire_lease_for, get_instance_id, get_lease_ttl_millis, release_lease},
+  metrics::dispatcher_metrics,
+  payload_encoding::PayloadFormat,
+  shutdown::{get_shutdown_deadline, release_dispatcher_state, round_locks, shutdown_token},
+  transform::{delivery_body, Transform},
 };
 use actix_http::header::HeaderValue;
 use anyhow::Ok;
 use async_trait::async_trait;
-use bson::{oid::ObjectId, Document};
-use log::{error, info};
+use bson::{oid::ObjectId, Bson, Document};
+use futures::stream::{StreamExt, TryStreamExt};
+use log::{error, info, warn};
 use mongodb::{
   bson::doc,
-  options::{FindOneOptions, FindOptions, UpdateOptions},
+  change_stream::event::{ChangeStreamEvent, ResumeToken},
+  error::Error as MongoErr,
+  options::{
+    ChangeStreamOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument,
+    UpdateOptions,
+  },
   results::UpdateResult,
-  Database,
+  ClientSession, Database,
 };
-use reqwest::header::HeaderMap;
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Serialize;
 use serde_json::{json, Value};
 use std::{
   cmp,
-  collections::{HashMap, LinkedList},
-  time::SystemTime,
+  collections::{HashMap, HashSet, LinkedList},
+  env,
+  sync::OnceLock,
+  time::{Instant, SystemTime},
 };
-use tokio::time::{sleep, Duration};
+use tokio::{
+  sync::{
+    mpsc::{self, UnboundedSender},
+    oneshot,
+  },
+  task::{self, JoinSet},
+  time::{interval, sleep, timeout, Duration, MissedTickBehavior},
+};
+use tokio_util::time::{delay_queue, DelayQueue};
 
 use hmac::{Hmac, Mac};
 use jwt::{Header, SignWithKey, Token};
@@ -31,11 +63,92 @@ use chrono::prelude::{DateTime, Utc};
 
 #[derive(Clone, Debug)]
 pub struct DelayTimes {
-  increase_timeout: u64,
-  wait_until: bson::DateTime,
+  pub increase_timeout: u64,
+  pub wait_until: bson::DateTime,
+}
+
+const DEFAULT_MAX_ATTEMPTS: i64 = 20;
+const DEFAULT_MAX_AGE_SECS: i64 = 86400;
+const DEFAULT_SUSPEND_AFTER_SECS: i64 = 86400;
+const DEFAULT_FALLBACK_POLL_SECS: u64 = 30;
+const WATCH_RETRY_DELAY_MS: u64 = 5000;
+const DEFAULT_WORKERS: usize = 8;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 20000;
+const DELIVERY_LOCK_MARGIN_MS: i64 = 10000;
+const MAX_RETRY_DELAY_MS: u64 = 10000;
+const DEFAULT_MAX_BLOCKS: i64 = 50;
+const DEFAULT_BACKOFF_BASE_MS: u64 = 100;
+const SUCCESS_DELAY_MS: u64 = 150;
+const PAYLOAD_ENVELOPE_BYTES: usize = 128;
+const DEFAULT_SIGNATURE_TOLERANCE_SECS: i64 = 300;
+const MAX_RETRY_AFTER_SECS: u64 = 86400;
+const MAX_DEAD_LETTER_TRANSACTION_ATTEMPTS: usize = 5;
+
+pub const JWT_SIGNATURE_TYPE: &str = "jwt.light.v1";
+pub const BODY_SIGNATURE_TYPE: &str = "hmac.sha256.v1";
+
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+static DISPATCHER_COMMANDS: OnceLock<UnboundedSender<DispatcherCommand>> = OnceLock::new();
+
+// requests the admin api sends to the dispatcher loop of its own replica
+pub enum DispatcherCommand {
+  Snapshot(oneshot::Sender<QueueSnapshot>),
+  Wake(Vec<String>),
+  // drops the pending delay, so the next round starts right away
+  Retry(Vec<String>),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ScheduledSubscription {
+  pub sub_id: String,
+  pub increase_timeout: u64,
+  pub wait_until: String,
 }
 
-const MAX_RETRIES: i64 = 15;
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct QueueSnapshot {
+  pub scheduled: Vec<ScheduledSubscription>,
+  pub in_flight: Vec<String>,
+}
+
+// None until the dispatcher loop of this process is running
+pub fn dispatcher_commands() -> Option<&'static UnboundedSender<DispatcherCommand>> {
+  DISPATCHER_COMMANDS.get()
+}
+
+// soonest first
+pub fn queue_snapshot<'a>(
+  queue_map: &HashMap<String, DelayTimes>,
+  in_flight: impl Iterator<Item = &'a String>,
+) -> QueueSnapshot {
+  let mut scheduled: Vec<(bson::DateTime, ScheduledSubscription)> = queue_map
+    .iter()
+    .map(|(sub_id, delay_times)| {
+      (
+        delay_times.wait_until,
+        ScheduledSubscription {
+          sub_id: sub_id.clone(),
+          increase_timeout: delay_times.increase_timeout,
+          wait_until: delay_times
+            .wait_until
+            .try_to_rfc3339_string()
+            .unwrap_or_default(),
+        },
+      )
+    })
+    .collect();
+  scheduled.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.sub_id.cmp(&b.1.sub_id)));
+  let mut in_flight: Vec<String> = in_flight.cloned().collect();
+  in_flight.sort();
+  QueueSnapshot {
+    scheduled: scheduled
+      .into_iter()
+      .map(|(_, scheduled)| scheduled)
+      .collect(),
+    in_flight,
+  }
+}
 
 pub struct DispatcherData<'a> {
   pub queue_list: LinkedList<String>,
@@ -62,15 +175,93 @@ pub fn generate_dates(a: u64, b: u64) -> (u128, u128, u128) {
   (current_date, new_date, sent_date)
 }
 
+// signing keys of a delivery, newest first, the previous secret is kept during its grace period
+pub fn get_signing_secrets(subscription: &Document) -> Vec<String> {
+  let mut secrets: Vec<String> = vec![];
+  if let std::result::Result::Ok(secret) = subscription.get_str("secret") {
+    if !secret.is_empty() {
+      secrets.push(secret.to_string());
+    }
+  }
+  if let (std::result::Result::Ok(previous_secret), std::result::Result::Ok(expires_at)) = (
+    subscription.get_str("previous_secret"),
+    subscription.get_datetime("previous_secret_expires_at"),
+  ) {
+    if !secrets.is_empty() && *expires_at > bson::DateTime::now() {
+      secrets.push(previous_secret.to_string());
+    }
+  }
+  // subscriptions created before signing secrets existed keep the api key until their first rotation
+  if secrets.is_empty() {
+    if let std::result::Result::Ok(apikey) = subscription.get_str("apikey") {
+      secrets.push(apikey.to_string());
+    }
+  }
+  secrets
+}
+
+// identifies a delivered batch towards the subscriber, a retried batch keeps its sequence
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchMetadata {
+  pub sequence: i64,
+  pub replay: bool,
+}
+
+impl BatchMetadata {
+  pub fn from_block(block: &Document) -> Option<Self> {
+    let sequence = block.get_i64("sequence").ok()?;
+    Some(BatchMetadata {
+      sequence,
+      replay: is_replay_block(block),
+    })
+  }
+}
+
+pub fn is_replay_block(block: &Document) -> bool {
+  block.get_bool("replay").unwrap_or(false)
+}
+
+// a batch never mixes blocks of different sequences, nor replayed blocks with live ones
+pub fn leading_batch_len(blocks: &[Document]) -> usize {
+  let batch_key = |block: &Document| (block.get("sequence").cloned(), is_replay_block(block));
+  match blocks.first() {
+    Some(first) => {
+      let first_key = batch_key(first);
+      blocks
+        .iter()
+        .take_while(|block| batch_key(block) == first_key)
+        .count()
+    }
+    None => 0,
+  }
+}
+
+// sequences are counted on the subscription document so they survive restarts and replica changes
+pub async fn next_delivery_sequence(db: &Database, sub_id: &str) -> anyhow::Result<i64> {
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(Some(ReturnDocument::After))
+    .build();
+  let subscription = find_one_and_update(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::parse_str(sub_id)? },
+    doc! { "$inc": { "delivery_sequence": 1_i64 } },
+    Some(options),
+  )
+  .await?
+  .ok_or_else(|| anyhow::anyhow!("Subscription {} does not exist anymore", sub_id))?;
+  Ok(get_i64_from_doc(
+    &subscription,
+    "delivery_sequence".to_string(),
+  ))
+}
+
 pub fn create_webhook_headers(
   sub_id: String,
   subscription: &Document,
+  batch: &BatchMetadata,
 ) -> anyhow::Result<(HeaderMap, String)> {
   let dt: DateTime<Utc> = Utc::now();
 
-  let header = Header {
-    ..Default::default()
-  };
   let mut claims = BTreeMap::new();
 
   let temp_date_isostring = dt.format("%+").to_string();
@@ -82,12 +273,18 @@ pub fn create_webhook_headers(
   claims.insert("timestamp", date_isostring);
   claims.insert("subcription_id", &sub_id);
 
-  let key: Hmac<Sha256> = Hmac::new_from_slice(subscription.get_str("apikey").unwrap().as_bytes())?;
-  let token = Token::new(header, claims)
-    .sign_with_key(&key)
-    .unwrap()
-    .as_str()
-    .to_string();
+  let secrets = get_signing_secrets(subscription);
+  let mut tokens: Vec<String> = vec![];
+  for secret in &secrets {
+    let key: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes())?;
+    tokens.push(
+      Token::new(Header::default(), claims.clone())
+        .sign_with_key(&key)?
+        .as_str()
+        .to_string(),
+    );
+  }
+  let token = tokens.first().cloned().unwrap_or_default();
   let mut headers = HeaderMap::new();
 
   headers.insert(
@@ -108,9 +305,12 @@ pub fn create_webhook_headers(
   );
   headers.insert(
     "x-msl-webhook-signature-type",
-    HeaderValue::from_str("jwt.light.v1").unwrap(),
+    HeaderValue::from_str(JWT_SIGNATURE_TYPE).unwrap(),
   );
-  headers.insert("x-msl-webhook-nonce", HeaderValue::from_str("-1").unwrap());
+  headers.insert("x-msl-webhook-nonce", HeaderValue::from(batch.sequence));
+  if batch.replay {
+    headers.insert("x-msl-webhook-replay", HeaderValue::from_static("true"));
+  }
   headers.insert(
     "x-msl-webhook-timestamp",
     HeaderValue::from_str(date_isostring).unwrap(),
@@ -119,9 +319,830 @@ pub fn create_webhook_headers(
     "x-msl-webhook-jwt-signature",
     HeaderValue::from_str(token.as_str()).unwrap(),
   );
+  if let Some(previous_token) = tokens.get(1) {
+    headers.insert(
+      "x-msl-webhook-jwt-signature-previous",
+      HeaderValue::from_str(previous_token.as_str())?,
+    );
+  }
   Ok((headers, contract_id.to_string()))
 }
 
+pub fn get_signature_tolerance_secs() -> i64 {
+  env::var("WEBHOOK_SIGNATURE_TOLERANCE_SECS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_SIGNATURE_TOLERANCE_SECS)
+}
+
+pub fn uses_body_signature(subscription: &Document) -> bool {
+  subscription.get_str("signature_type") == std::result::Result::Ok(BODY_SIGNATURE_TYPE)
+}
+
+fn body_mac(secret: &str, timestamp: i64, body: &[u8]) -> anyhow::Result<Hmac<Sha256>> {
+  let mut mac: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes())?;
+  mac.update(timestamp.to_string().as_bytes());
+  mac.update(b".");
+  mac.update(body);
+  Ok(mac)
+}
+
+// hex encoded HMAC-SHA256 of `<timestamp>.<raw body>`
+pub fn sign_body(secret: &str, timestamp: i64, body: &[u8]) -> anyhow::Result<String> {
+  let signature = body_mac(secret, timestamp, body)?.finalize().into_bytes();
+  Ok(
+    signature
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect(),
+  )
+}
+
+// the check a subscriber runs on `x-msl-webhook-signature`, stale timestamps are rejected so a
+// captured request cannot be replayed later
+pub fn verify_body_signature(
+  signature_header: &str,
+  body: &[u8],
+  secret: &str,
+  tolerance_secs: i64,
+  now: i64,
+) -> bool {
+  let mut timestamp: Option<i64> = None;
+  let mut signatures: Vec<&str> = vec![];
+  for part in signature_header.split(',') {
+    match part.trim().split_once('=') {
+      Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+      Some(("v1", value)) => signatures.push(value),
+      _ => {}
+    }
+  }
+  let timestamp = match timestamp {
+    Some(timestamp) if (now - timestamp).abs() <= tolerance_secs => timestamp,
+    _ => return false,
+  };
+  signatures.iter().any(|signature| {
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+      .step_by(2)
+      .map(|index| {
+        signature
+          .get(index..index + 2)
+          .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+      })
+      .collect();
+    match (bytes, body_mac(secret, timestamp, body)) {
+      (Some(bytes), std::result::Result::Ok(mac)) => mac.verify_slice(&bytes).is_ok(),
+      _ => false,
+    }
+  })
+}
+
+pub fn add_body_signature(
+  headers: &mut HeaderMap,
+  subscription: &Document,
+  body: &[u8],
+) -> anyhow::Result<()> {
+  let timestamp = Utc::now().timestamp();
+  let mut signature_header = format!("t={}", timestamp);
+  for secret in get_signing_secrets(subscription) {
+    signature_header.push_str(&format!(",v1={}", sign_body(&secret, timestamp, body)?));
+  }
+  headers.insert(
+    "x-msl-webhook-signature-type",
+    HeaderValue::from_str(BODY_SIGNATURE_TYPE)?,
+  );
+  headers.insert(
+    "x-msl-webhook-signature",
+    HeaderValue::from_str(signature_header.as_str())?,
+  );
+  headers.insert(
+    "x-msl-webhook-signature-tolerance",
+    HeaderValue::from(get_signature_tolerance_secs()),
+  );
+  Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+  Timeout,
+  Dns,
+  Connect,
+  Transport,
+  RateLimited,
+  Gone,
+  PayloadTooLarge,
+  PayloadRejected,
+  ClientError,
+  ServerError,
+  Misconfigured,
+}
+
+impl FailureKind {
+  pub fn from_status(status: StatusCode) -> Self {
+    match status {
+      StatusCode::TOO_MANY_REQUESTS => FailureKind::RateLimited,
+      StatusCode::GONE => FailureKind::Gone,
+      StatusCode::PAYLOAD_TOO_LARGE => FailureKind::PayloadTooLarge,
+      StatusCode::BAD_REQUEST
+      | StatusCode::UNSUPPORTED_MEDIA_TYPE
+      | StatusCode::UNPROCESSABLE_ENTITY => FailureKind::PayloadRejected,
+      status if status.is_client_error() => FailureKind::ClientError,
+      _ => FailureKind::ServerError,
+    }
+  }
+
+  pub fn from_transport_error(err: &reqwest::Error) -> Self {
+    if err.is_timeout() {
+      return FailureKind::Timeout;
+    }
+    // hyper reports resolver failures as connect errors, only the message tells them apart
+    let mut source: Option<&dyn std::error::Error> = Some(err);
+    while let Some(current) = source {
+      if current.to_string().contains("dns error") {
+        return FailureKind::Dns;
+      }
+      source = current.source();
+    }
+    if err.is_connect() {
+      FailureKind::Connect
+    } else {
+      FailureKind::Transport
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      FailureKind::Timeout => "timeout",
+      FailureKind::Dns => "dns",
+      FailureKind::Connect => "connect",
+      FailureKind::Transport => "transport",
+      FailureKind::RateLimited => "rate_limited",
+      FailureKind::Gone => "gone",
+      FailureKind::PayloadTooLarge => "payload_too_large",
+      FailureKind::PayloadRejected => "payload_rejected",
+      FailureKind::ClientError => "client_error",
+      FailureKind::ServerError => "server_error",
+      FailureKind::Misconfigured => "misconfigured",
+    }
+  }
+
+  // the endpoint answered and is fine, only this request was refused
+  pub fn is_endpoint_healthy(&self) -> bool {
+    matches!(
+      self,
+      FailureKind::RateLimited
+        | FailureKind::Gone
+        | FailureKind::PayloadTooLarge
+        | FailureKind::PayloadRejected
+    )
+  }
+
+  // misconfigurations are found before anything is sent, they say nothing about the endpoint
+  pub fn reaches_endpoint(&self) -> bool {
+    *self != FailureKind::Misconfigured
+  }
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+  let value = value.trim();
+  let retry_after = match value.parse::<u64>() {
+    std::result::Result::Ok(seconds) => Duration::from_secs(seconds),
+    Err(_) => {
+      let date = DateTime::parse_from_rfc2822(value).ok()?;
+      (date.with_timezone(&Utc) - now)
+        .to_std()
+        .unwrap_or(Duration::ZERO)
+    }
+  };
+  Some(cmp::min(
+    retry_after,
+    Duration::from_secs(MAX_RETRY_AFTER_SECS),
+  ))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryOutcome {
+  pub is_good: bool,
+  pub status: Option<u16>,
+  pub error: Option<String>,
+  pub failure: Option<FailureKind>,
+  pub retry_after: Option<Duration>,
+  pub latency_ms: i64,
+  pub response_excerpt: Option<String>,
+}
+
+impl DeliveryOutcome {
+  // a `Retry-After` only schedules the next attempt, a 503 deferring it still counts as down
+  pub fn is_endpoint_healthy(&self) -> bool {
+    self.is_good
+      || self
+        .failure
+        .is_some_and(|failure| failure.is_endpoint_healthy())
+  }
+
+  pub fn reached_endpoint(&self) -> bool {
+    self
+      .failure
+      .is_none_or(|failure| failure.reaches_endpoint())
+  }
+}
+
+pub fn truncate_response(body: &str, max_chars: usize) -> String {
+  match body.char_indices().nth(max_chars) {
+    Some((index, _)) => format!("{}...", &body[..index]),
+    None => body.to_string(),
+  }
+}
+
+pub fn block_payload(block: &Document) -> Value {
+  let transactions: Vec<Document> = block
+    .get_array("transactions")
+    .unwrap()
+    .iter()
+    .map(|tx| {
+      let mut doc = tx.as_document().unwrap().to_owned();
+      doc.remove("_id");
+      doc
+    })
+    .collect();
+
+  let mut payload = json!({
+      "transactions": transactions,
+      "block_number": get_i64_from_doc(block, "block_number".to_string()),
+      "event_name": block.get_str("event_name").unwrap(),
+  });
+  if let std::result::Result::Ok(block_hash) = block.get_str("block_hash") {
+    payload["block_hash"] = json!(block_hash);
+  }
+  // retractions of blocks that were orphaned by a reorg after being delivered
+  if block.get_bool("removed") == std::result::Result::Ok(true) {
+    payload["removed"] = json!(true);
+  }
+  payload
+}
+
+// kept on the delivery so a reorg knows which blocks the subscriber already has
+pub fn delivered_block_summary(block: &Document) -> Document {
+  let mut summary = doc! {
+    "block_number": get_i64_from_doc(block, "block_number".to_string()),
+    "event_name": block.get_str("event_name").unwrap_or_default(),
+  };
+  for key in ["block_hash", "removed"] {
+    if let Some(value) = block.get(key) {
+      summary.insert(key, value.clone());
+    }
+  }
+  summary
+}
+
+pub async fn record_delivery(
+  db: &Database,
+  subscription: &Document,
+  blocks: &[Document],
+  batch: &BatchMetadata,
+  outcome: &DeliveryOutcome,
+) -> anyhow::Result<()> {
+  if blocks.is_empty() {
+    return Ok(());
+  }
+  let status = outcome.status.map(|status| status as i32);
+  db.collection::<Document>("deliveries")
+    .insert_one(
+      doc! {
+        "subid": subscription.get_object_id("_id")?.to_string(),
+        "contract_id": subscription.get_str("contract_id").unwrap_or_default(),
+        "block_from": get_i64_from_doc(&blocks[0], "block_number".to_string()),
+        "block_to": get_i64_from_doc(&blocks[blocks.len() - 1], "block_number".to_string()),
+        "block_count": blocks.len() as i64,
+        "blocks": blocks.iter().map(delivered_block_summary).collect::<Vec<Document>>(),
+        "attempt": get_i64_from_doc(&blocks[0], "attempts".to_string()) + 1,
+        "sequence": batch.sequence,
+        "replay": batch.replay,
+        "success": outcome.is_good,
+        "status": status,
+        "failure": outcome.failure.map(|failure| failure.as_str()),
+        "latency_ms": outcome.latency_ms,
+        "response_excerpt": outcome.response_excerpt.clone(),
+        "error": outcome.error.clone(),
+        "created_at": bson::DateTime::now(),
+      },
+      None,
+    )
+    .await?;
+  Ok(())
+}
+
+pub fn get_max_attempts() -> i64 {
+  env::var("DISPATCHER_MAX_ATTEMPTS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+}
+
+pub fn get_max_age_millis() -> i64 {
+  env::var("DISPATCHER_MAX_AGE_SECS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_MAX_AGE_SECS)
+    * 1000
+}
+
+// optional overrides stored under `delivery_policy` on the subscription document
+#[derive(Clone, Debug)]
+pub struct DeliveryPolicy {
+  pub max_blocks: i64,
+  pub max_payload_bytes: Option<usize>,
+  pub timeout: Duration,
+  pub backoff_base_ms: u64,
+  pub backoff_cap_ms: u64,
+}
+
+impl DeliveryPolicy {
+  pub fn from_subscription(subscription: &Document) -> Self {
+    let policy = subscription.get_document("delivery_policy").ok();
+    let get_positive = |key: &str| {
+      let value = match policy?.get(key)? {
+        Bson::Int32(value) => *value as i64,
+        Bson::Int64(value) => *value,
+        _ => return None,
+      };
+      (value > 0).then_some(value)
+    };
+
+    let backoff_base_ms =
+      get_positive("backoff_base_ms").map_or(DEFAULT_BACKOFF_BASE_MS, |value| value as u64);
+    DeliveryPolicy {
+      max_blocks: get_positive("max_blocks").unwrap_or(DEFAULT_MAX_BLOCKS),
+      max_payload_bytes: get_positive("max_payload_bytes").map(|value| value as usize),
+      timeout: get_positive("timeout_ms").map_or_else(get_request_timeout, |value| {
+        Duration::from_millis(value as u64)
+      }),
+      backoff_base_ms,
+      backoff_cap_ms: get_positive("backoff_cap_ms")
+        .map_or(MAX_RETRY_DELAY_MS, |value| value as u64)
+        .max(backoff_base_ms),
+    }
+  }
+
+  // exponential backoff derived from the persisted attempts, so it survives restarts
+  pub fn retry_delay(&self, attempts: i64) -> u64 {
+    let exponent = attempts.clamp(0, 16) as u32;
+    cmp::min(
+      self.backoff_base_ms.saturating_mul(2u64.pow(exponent)),
+      self.backoff_cap_ms,
+    )
+  }
+
+  // the lease and the block lock outlive the slowest request, else another replica resends the batch
+  pub fn lock_ttl_millis(&self) -> i64 {
+    cmp::max(
+      get_lease_ttl_millis(),
+      self.timeout.as_millis() as i64 + DELIVERY_LOCK_MARGIN_MS,
+    )
+  }
+
+  pub fn next_retry_delay(&self, current_delay: u64) -> u64 {
+    current_delay
+      .saturating_mul(2)
+      .clamp(self.backoff_base_ms, self.backoff_cap_ms)
+  }
+}
+
+// number of leading blocks whose payload fits in max_bytes, at least one so the queue always moves
+pub fn blocks_within_payload_bytes(blocks: &[Value], max_bytes: usize) -> usize {
+  let mut size = PAYLOAD_ENVELOPE_BYTES;
+  for (index, block) in blocks.iter().enumerate() {
+    size += serde_json::to_vec(block).map_or(0, |bytes| bytes.len()) + 1;
+    if size > max_bytes {
+      return cmp::max(index, 1);
+    }
+  }
+  blocks.len()
+}
+
+pub fn should_dead_letter(attempts: i64, first_attempt_at: i64, now: i64) -> bool {
+  attempts >= get_max_attempts() || now - first_attempt_at >= get_max_age_millis()
+}
+
+pub fn get_suspend_after_millis() -> i64 {
+  env::var("DISPATCHER_SUSPEND_AFTER_SECS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_SUSPEND_AFTER_SECS)
+    * 1000
+}
+
+// `failing_since` marks the first failure after the last successful delivery
+pub fn should_suspend(subscription: &Document, now: i64) -> bool {
+  subscription
+    .get_datetime("failing_since")
+    .is_ok_and(|failing_since| now - failing_since.timestamp_millis() >= get_suspend_after_millis())
+}
+
+pub async fn track_failing_since(
+  db: &Database,
+  subscription: &Document,
+  is_good: bool,
+) -> anyhow::Result<()> {
+  let id = subscription.get_object_id("_id")?;
+  let is_failing = subscription.get_datetime("failing_since").is_ok();
+  if is_good && is_failing {
+    update_many(
+      db.collection("subscriptions"),
+      doc! { "_id": id },
+      doc! { "$unset": { "failing_since": "" } },
+      UpdateOptions::default(),
+    )
+    .await?;
+  } else if !is_good && !is_failing {
+    update_many(
+      db.collection("subscriptions"),
+      doc! { "_id": id, "failing_since": { "$exists": false } },
+      doc! { "$set": { "failing_since": bson::DateTime::now() } },
+      UpdateOptions::default(),
+    )
+    .await?;
+  }
+  Ok(())
+}
+
+pub fn failing_reason(subscription: &Document) -> String {
+  let now = bson::DateTime::now();
+  let failing_since = subscription.get_datetime("failing_since").unwrap_or(&now);
+  format!(
+    "webhook deliveries failing continuously since {}",
+    failing_since.to_chrono().to_rfc3339()
+  )
+}
+
+// deactivates the subscription, its queued blocks stay until the customer reactivates it
+pub async fn suspend_subscription(
+  db: &Database,
+  subscription: &Document,
+  reason: &str,
+) -> anyhow::Result<()> {
+  let now = bson::DateTime::now();
+  update_many(
+    db.collection("subscriptions"),
+    doc! { "_id": subscription.get_object_id("_id")?, "isActive": true },
+    doc! { "$set": {
+      "isActive": false,
+      "suspended_reason": reason,
+      "suspended_at": now,
+      "updatedAt": now,
+    } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok(())
+}
+
+// a `retry_after` from the webhook replaces the backoff, the attempt still counts toward the limits
+pub async fn record_failed_attempt(
+  db: &Database,
+  subscription: &Document,
+  blocks: &[Document],
+  last_error: &str,
+  retry_after: Option<Duration>,
+) -> anyhow::Result<bool> {
+  if blocks.is_empty() {
+    return Ok(false);
+  }
+  let now = bson::DateTime::now();
+  let ids: Vec<ObjectId> = blocks
+    .iter()
+    .map(|block| block.get_object_id("_id").unwrap())
+    .collect();
+
+  update_many(
+    db.collection("transactionblocks"),
+    doc! { "_id": { "$in": ids.clone() } },
+    doc! {
+      "$inc": { "attempts": 1 },
+      "$set": { "last_error": last_error, "last_attempt_at": now },
+      "$min": { "first_attempt_at": now },
+      "$unset": { "locked_by": "" },
+    },
+    UpdateOptions::default(),
+  )
+  .await?;
+
+  // the first block of the batch is always the oldest one, it drives the batch state
+  let attempts = get_i64_from_doc(&blocks[0], "attempts".to_string()) + 1;
+  let first_attempt_at = blocks[0]
+    .get_datetime("first_attempt_at")
+    .map(|date| date.timestamp_millis())
+    .unwrap_or_else(|_| now.timestamp_millis());
+
+  if should_dead_letter(attempts, first_attempt_at, now.timestamp_millis()) {
+    move_to_dead_letters(db, subscription, blocks, attempts, last_error).await?;
+    return Ok(true);
+  }
+
+  let retry_delay = retry_after.map_or_else(
+    || DeliveryPolicy::from_subscription(subscription).retry_delay(attempts),
+    |delay| delay.as_millis() as u64,
+  );
+  update_many(
+    db.collection("transactionblocks"),
+    doc! { "_id": { "$in": ids } },
+    doc! { "$set": { "locked_until": bson::DateTime::from_millis(now.timestamp_millis() + retry_delay as i64) } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok(false)
+}
+
+// a batch the webhook found too large is retried as two halves, the second one gets its own sequence
+pub async fn split_batch(db: &Database, blocks: &[Document]) -> anyhow::Result<()> {
+  let ids: Vec<ObjectId> = blocks
+    .iter()
+    .map(|block| block.get_object_id("_id").unwrap())
+    .collect();
+  let second_half = ids[ids.len() / 2..].to_vec();
+  update_many(
+    db.collection("transactionblocks"),
+    doc! { "_id": { "$in": second_half } },
+    doc! { "$unset": { "sequence": "" } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  update_many(
+    db.collection("transactionblocks"),
+    doc! { "_id": { "$in": ids } },
+    doc! { "$set": { "locked_until": bson::DateTime::now() }, "$unset": { "locked_by": "" } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok(())
+}
+
+pub async fn move_to_dead_letters(
+  db: &Database,
+  subscription: &Document,
+  blocks: &[Document],
+  attempts: i64,
+  last_error: &str,
+) -> anyhow::Result<()> {
+  let ids: Vec<ObjectId> = blocks
+    .iter()
+    .map(|block| block.get_object_id("_id").unwrap())
+    .collect();
+  let first_attempt_at = blocks[0]
+    .get_datetime("first_attempt_at")
+    .map(|date| date.to_owned())
+    .unwrap_or_else(|_| bson::DateTime::now());
+  // the sequence is left behind too, a redriven batch is numbered after the ones delivered since
+  let stored_blocks: Vec<Document> = blocks
+    .iter()
+    .map(|block| {
+      let mut block = block.clone();
+      for key in [
+        "_id",
+        "sequence",
+        "locked_until",
+        "locked_by",
+        "attempts",
+        "last_error",
+        "first_attempt_at",
+        "last_attempt_at",
+      ] {
+        block.remove(key);
+      }
+      block
+    })
+    .collect();
+
+  let dead_letter = doc! {
+    "subid": subscription.get_object_id("_id")?.to_string(),
+    "contract_id": subscription.get_str("contract_id").unwrap_or_default(),
+    "block_from": get_i64_from_doc(&blocks[0], "block_number".to_string()),
+    "block_to": get_i64_from_doc(&blocks[blocks.len() - 1], "block_number".to_string()),
+    "block_count": blocks.len() as i64,
+    "attempts": attempts,
+    "last_error": last_error,
+    "first_attempt_at": first_attempt_at,
+    "dead_lettered_at": bson::DateTime::now(),
+    "blocks": stored_blocks,
+  };
+
+  // the batch is either dead-lettered or still queued, a crash in between cannot deliver it again
+  let mut session = db
+    .collection::<Document>("deadletters")
+    .client()
+    .start_session(None)
+    .await?;
+  let mut attempt = 1;
+  loop {
+    session.start_transaction(None).await?;
+    let err = match dead_letter_with_session(db, &mut session, &dead_letter, &ids).await {
+      std::result::Result::Ok(()) => {
+        match commit_transaction(&mut session, MAX_DEAD_LETTER_TRANSACTION_ATTEMPTS).await {
+          std::result::Result::Ok(()) => return Ok(()),
+          Err(err) => err,
+        }
+      }
+      Err(err) => {
+        // the server may have aborted the transaction already
+        _ = session.abort_transaction().await;
+        err
+      }
+    };
+    if !is_transient_transaction_error(&err) || attempt >= MAX_DEAD_LETTER_TRANSACTION_ATTEMPTS {
+      return Err(err.into());
+    }
+    warn!("Retrying to dead-letter {:?} after {:?}", ids, err);
+    attempt += 1;
+  }
+}
+
+async fn dead_letter_with_session(
+  db: &Database,
+  session: &mut ClientSession,
+  dead_letter: &Document,
+  ids: &[ObjectId],
+) -> Result<(), MongoErr> {
+  insert_one_with_session(db.collection("deadletters"), dead_letter, session).await?;
+  delete_many_with_session(
+    db.collection("transactionblocks"),
+    doc! { "_id": { "$in": ids } },
+    session,
+  )
+  .await?;
+  std::result::Result::Ok(())
+}
+
+pub fn get_fallback_poll_interval() -> Duration {
+  Duration::from_secs(
+    env::var("DISPATCHER_FALLBACK_POLL_SECS")
+      .ok()
+      .and_then(|value| value.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_FALLBACK_POLL_SECS),
+  )
+}
+
+pub fn transaction_blocks_pipeline() -> Vec<Document> {
+  vec![
+    doc! { "$match": { "operationType": "insert" } },
+    doc! { "$project": { "operationType": 1, "fullDocument.subid": 1 } },
+  ]
+}
+
+pub fn subscriptions_pipeline() -> Vec<Document> {
+  vec![
+    doc! { "$match": { "operationType": { "$in": ["insert", "update", "replace", "delete"] } } },
+    doc! { "$project": { "operationType": 1, "documentKey": 1 } },
+  ]
+}
+
+pub fn sub_id_from_block_change(event: &ChangeStreamEvent<Document>) -> Option<String> {
+  event
+    .full_document
+    .as_ref()?
+    .get_str("subid")
+    .ok()
+    .map(str::to_string)
+}
+
+pub fn sub_id_from_subscription_change(event: &ChangeStreamEvent<Document>) -> Option<String> {
+  event
+    .document_key
+    .as_ref()?
+    .get_object_id("_id")
+    .ok()
+    .map(|id| id.to_string())
+}
+
+// forwards the subscription ids touched in a collection to the dispatcher loop,
+// reopening the change stream from the last resume token when it breaks
+pub async fn watch_collection(
+  db: Database,
+  collection_name: &'static str,
+  pipeline: Vec<Document>,
+  get_sub_id: fn(&ChangeStreamEvent<Document>) -> Option<String>,
+  wake_sender: UnboundedSender<String>,
+) {
+  let mut resume_token: Option<ResumeToken> = None;
+  loop {
+    let options = ChangeStreamOptions::builder()
+      .resume_after(resume_token.clone())
+      .build();
+    match db
+      .collection::<Document>(collection_name)
+      .watch(pipeline.clone(), options)
+      .await
+    {
+      std::result::Result::Ok(mut stream) => {
+        info!("Watching {} for changes", collection_name);
+        loop {
+          match stream.try_next().await {
+            std::result::Result::Ok(Some(event)) => {
+              if let Some(sub_id) = get_sub_id(&event) {
+                if wake_sender.send(sub_id).is_err() {
+                  return;
+                }
+              }
+            }
+            std::result::Result::Ok(None) => break,
+            Err(err) => {
+              error!("Change stream on {} failed: {:?}", collection_name, err);
+              break;
+            }
+          }
+        }
+        resume_token = stream.resume_token();
+      }
+      Err(err) => {
+        error!(
+          "Could not watch {}, relying on fallback poll: {:?}",
+          collection_name, err
+        );
+        resume_token = None;
+      }
+    }
+    sleep(Duration::from_millis(WATCH_RETRY_DELAY_MS)).await;
+  }
+}
+
+pub fn get_worker_count() -> usize {
+  env::var("DISPATCHER_WORKERS")
+    .ok()
+    .and_then(|value| value.parse::<usize>().ok())
+    .unwrap_or(DEFAULT_WORKERS)
+    .max(1)
+}
+
+pub fn get_connect_timeout() -> Duration {
+  Duration::from_millis(
+    env::var("WEBHOOK_CONNECT_TIMEOUT_MS")
+      .ok()
+      .and_then(|value| value.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS),
+  )
+}
+
+pub fn get_request_timeout() -> Duration {
+  Duration::from_millis(
+    env::var("WEBHOOK_TIMEOUT_MS")
+      .ok()
+      .and_then(|value| value.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS),
+  )
+}
+
+// shared by every worker so connections to the same webhook host are reused
+pub fn http_client() -> &'static reqwest::Client {
+  HTTP_CLIENT.get_or_init(|| {
+    reqwest::Client::builder()
+      .connect_timeout(get_connect_timeout())
+      .timeout(get_request_timeout())
+      .build()
+      .expect("Failed to build the webhook http client")
+  })
+}
+
+pub fn delay_times_in(delay: u64) -> DelayTimes {
+  DelayTimes {
+    increase_timeout: delay,
+    wait_until: bson::DateTime::from_millis(
+      bson::DateTime::now().timestamp_millis() + delay as i64,
+    ),
+  }
+}
+
+// runs one delivery round for a subscription and returns when it should run again, a
+// subscription is only ever handled by one worker at a time which keeps its batches in order
+pub async fn deliver_subscription(
+  db: Database,
+  sub_id: String,
+  increase_timeout: u64,
+) -> (String, Option<DelayTimes>) {
+  let mut queue_map = HashMap::new();
+  let mut worker = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut queue_map,
+  };
+  let result = async {
+    if worker.any_transaction_pending(&db, sub_id.clone()).await? {
+      worker
+        .try_send_transactions(&db, sub_id.clone(), increase_timeout)
+        .await?;
+    }
+    Ok(())
+  }
+  .await;
+  // only rounds abandoned at shutdown leave their lock behind
+  round_locks().lock().unwrap().remove(&sub_id);
+
+  if let Err(err) = result {
+    error!("Delivery round for {} failed: {:?}", sub_id, err);
+    let next = delay_times_in(cmp::min(increase_timeout * 2, MAX_RETRY_DELAY_MS));
+    return (sub_id, Some(next));
+  }
+  let next = worker.queue_map.remove(&sub_id);
+  (sub_id, next)
+}
+
 #[async_trait]
 pub trait Dispatcher {
   async fn fill_queue(&mut self, db: &Database) -> anyhow::Result<()>;
@@ -130,7 +1151,8 @@ pub trait Dispatcher {
     transactions: Vec<Value>,
     subscription: &Document,
     sub_id: String,
-  ) -> anyhow::Result<bool>;
+    batch: &BatchMetadata,
+  ) -> anyhow::Result<DeliveryOutcome>;
   async fn any_transaction_pending(
     &mut self,
     db: &Database,
@@ -138,6 +1160,7 @@ pub trait Dispatcher {
   ) -> anyhow::Result<bool>;
   async fn start_dispatcher(&mut self, db: &Database) -> anyhow::Result<()>;
   fn merge_queues(&mut self, new_items: Vec<String>) -> anyhow::Result<()>;
+  fn requeue(&mut self, sub_id: String, next_delay: u64);
   async fn try_send_transactions(
     &mut self,
     db: &Database,
@@ -150,28 +1173,30 @@ pub trait Dispatcher {
 impl Dispatcher for DispatcherData<'_> {
   async fn fill_queue(&mut self, db: &Database) -> anyhow::Result<()> {
     info!("Filling queue");
-    let filter = doc! { "isActive": true };
+    // only subscriptions with queued blocks, so idle subscriptions cost nothing
+    let pending_ids: Vec<ObjectId> = distinct(db.collection("transactionblocks"), "subid", doc! {})
+      .await?
+      .iter()
+      .filter_map(|sub_id| ObjectId::parse_str(sub_id.as_str()?).ok())
+      .collect();
+    if pending_ids.is_empty() {
+      return Ok(());
+    }
+
+    let filter = doc! {
+      "isActive": true,
+      "delivery_paused": { "$ne": true },
+      "_id": { "$in": pending_ids },
+    };
     let mut find_option = FindOptions::default();
     find_option.projection = Some(doc! { "_id": 1 });
 
-    find_all(db.collection("subscriptions"), filter, find_option)
-      .await
-      .unwrap()
+    let sub_ids: Vec<String> = find_all(db.collection("subscriptions"), filter, find_option)
+      .await?
       .iter()
-      .for_each(|tx| {
-        //info!("doc: {:?}", tx);
-        let id_str: &String = &tx.get_object_id("_id").unwrap().to_string();
-        self.queue_list.push_back(id_str.to_string());
-
-        self.queue_map.insert(
-          id_str.to_string(),
-          DelayTimes {
-            increase_timeout: 100,
-            wait_until: bson::DateTime::now(),
-          },
-        );
-      });
-    //_ = dispatcher_data.start_dispatcher(db).await;
+      .map(|subscription| subscription.get_object_id("_id").unwrap().to_string())
+      .collect();
+    self.merge_queues(sub_ids)?;
     info!("self.queue_list: {:?}", self.queue_list);
     Ok(())
   }
@@ -182,67 +1207,159 @@ impl Dispatcher for DispatcherData<'_> {
       self.queue_list
     );
 
+    let (wake_sender, mut wake_receiver) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(watch_collection(
+      db.clone(),
+      "transactionblocks",
+      transaction_blocks_pipeline(),
+      sub_id_from_block_change,
+      wake_sender.clone(),
+    ));
+    tokio::spawn(watch_collection(
+      db.clone(),
+      "subscriptions",
+      subscriptions_pipeline(),
+      sub_id_from_subscription_change,
+      wake_sender,
+    ));
+    let (command_sender, mut command_receiver) = mpsc::unbounded_channel::<DispatcherCommand>();
+    if DISPATCHER_COMMANDS.set(command_sender).is_err() {
+      error!("Another dispatcher loop already takes admin commands");
+    }
+
+    let max_workers = get_worker_count();
+    let mut delay_queue: DelayQueue<String> = DelayQueue::new();
+    let mut delay_keys: HashMap<String, delay_queue::Key> = HashMap::new();
+    let mut workers: JoinSet<(String, Option<DelayTimes>)> = JoinSet::new();
+    let mut in_flight: HashMap<task::Id, String> = HashMap::new();
+    let mut woken_in_flight: HashSet<String> = HashSet::new();
+    let mut fallback_poll = interval(get_fallback_poll_interval());
+    fallback_poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
     loop {
-      // inside loop
-      let mut number_retries: i64 = MAX_RETRIES;
-      while !self.queue_list.is_empty() {
-        let next_sub_id: String = self.queue_list.pop_front().unwrap();
-        let next_sub_id_clone = next_sub_id.clone();
-        let next_sub_id_clone2 = next_sub_id_clone.clone();
-        let next_item: &DelayTimes = self.queue_map.get(&next_sub_id).unwrap();
-        //self.queue_map.remove(&next_sub_id);
-
-        let increase_timeout = &next_item.increase_timeout;
-        let wait_until = next_item.wait_until.to_chrono().timestamp_millis();
-        let current_date = SystemTime::now()
-          .duration_since(SystemTime::UNIX_EPOCH)
-          .expect("Time went backwards")
-          .as_millis();
-        if current_date < wait_until.try_into().unwrap() {
-          self.queue_list.push_back(next_sub_id);
-          let next = next_item.clone();
-          self.queue_map.insert(next_sub_id_clone, next);
-
-          number_retries -= 1;
-          if number_retries <= 0 {
-            let filter = doc! { "isActive": true };
-            let find_option = FindOptions::default();
-
-            let subscriptions: Vec<String> =
-              find_all(db.collection("subscriptions"), filter, find_option)
-                .await
-                .unwrap()
-                .iter()
-                .map(|subs| subs.get_object_id("_id").unwrap().to_string())
-                .collect();
-            _ = self.merge_queues(subscriptions);
-            number_retries = MAX_RETRIES;
-          }
-          sleep(Duration::from_millis(50)).await;
+      dispatcher_metrics()
+        .lock()
+        .unwrap()
+        .set_queue(delay_keys.len() + self.queue_list.len(), in_flight.len());
+      // newly queued subscriptions go to the delay queue, or wait for their running delivery
+      while let Some(sub_id) = self.queue_list.pop_front() {
+        if in_flight.values().any(|running| running == &sub_id) {
+          self.queue_map.remove(&sub_id);
+          woken_in_flight.insert(sub_id);
           continue;
         }
-        number_retries = MAX_RETRIES;
-        let increase_timeout_clone = *increase_timeout;
-
-        if self
-          .any_transaction_pending(db, next_sub_id_clone)
-          .await
-          .unwrap()
-        {
-          self
-            .try_send_transactions(db, next_sub_id_clone2, increase_timeout_clone)
-            .await?;
+        let delay_times = match self.queue_map.get(&sub_id) {
+          Some(delay_times) => delay_times,
+          None => continue,
+        };
+        if delay_keys.contains_key(&sub_id) {
+          continue;
         }
-
-        sleep(Duration::from_millis(200)).await;
+        let wait_millis =
+          delay_times.wait_until.timestamp_millis() - bson::DateTime::now().timestamp_millis();
+        let key = delay_queue.insert(
+          sub_id.clone(),
+          Duration::from_millis(cmp::max(wait_millis, 0) as u64),
+        );
+        delay_keys.insert(sub_id, key);
       }
 
-      let init_queue = self.fill_queue(db);
-      let sleep_1000 = sleep(Duration::from_millis(1000));
-
-      info!("Queue cool off 1sec");
-      let (_, _) = tokio::join!(init_queue, sleep_1000);
+      tokio::select! {
+        Some(sub_id) = wake_receiver.recv() => {
+          let mut woken = vec![sub_id];
+          while let std::result::Result::Ok(sub_id) = wake_receiver.try_recv() {
+            woken.push(sub_id);
+          }
+          self.merge_queues(woken)?;
+        }
+        Some(command) = command_receiver.recv() => match command {
+          DispatcherCommand::Snapshot(reply) => {
+            _ = reply.send(queue_snapshot(self.queue_map, in_flight.values()));
+          }
+          DispatcherCommand::Wake(sub_ids) => self.merge_queues(sub_ids)?,
+          DispatcherCommand::Retry(sub_ids) => {
+            for sub_id in sub_ids {
+              if in_flight.values().any(|running| running == &sub_id) {
+                woken_in_flight.insert(sub_id);
+                continue;
+              }
+              if let Some(key) = delay_keys.remove(&sub_id) {
+                delay_queue.remove(&key);
+              }
+              self.queue_map.remove(&sub_id);
+              self.merge_queues(vec![sub_id])?;
+            }
+          }
+        },
+        Some(expired) = delay_queue.next(), if workers.len() < max_workers => {
+          let sub_id = expired.into_inner();
+          delay_keys.remove(&sub_id);
+          let increase_timeout = self
+            .queue_map
+            .remove(&sub_id)
+            .map_or(100, |delay_times| delay_times.increase_timeout);
+          let worker = workers.spawn(deliver_subscription(db.clone(), sub_id.clone(), increase_timeout));
+          in_flight.insert(worker.id(), sub_id);
+        }
+        Some(finished) = workers.join_next_with_id() => {
+          let (sub_id, next) = match finished {
+            std::result::Result::Ok((id, (sub_id, next))) => {
+              in_flight.remove(&id);
+              (sub_id, next)
+            }
+            Err(err) => {
+              let sub_id = in_flight.remove(&err.id()).unwrap_or_default();
+              error!("Delivery worker for {} panicked: {:?}", sub_id, err);
+              (sub_id, Some(delay_times_in(MAX_RETRY_DELAY_MS)))
+            }
+          };
+          let woken = woken_in_flight.remove(&sub_id);
+          match next {
+            Some(next) => {
+              self.queue_list.push_back(sub_id.clone());
+              self.queue_map.insert(sub_id, next);
+            }
+            None if woken => self.merge_queues(vec![sub_id])?,
+            None => {}
+          }
+        }
+        _ = fallback_poll.tick() => {
+          if let Err(err) = self.fill_queue(db).await {
+            error!("Fallback poll failed: {:?}", err);
+          }
+        }
+        _ = shutdown_token().cancelled() => break,
+      }
     }
+
+    // no new rounds start, the running ones get until the deadline to finish
+    info!(
+      "Dispatcher shutting down, waiting for {} deliveries",
+      in_flight.len()
+    );
+    let held: Vec<String> = self
+      .queue_map
+      .keys()
+      .chain(in_flight.values())
+      .cloned()
+      .collect();
+    let mut finished = 0;
+    _ = timeout(get_shutdown_deadline(), async {
+      while let Some(joined) = workers.join_next_with_id().await {
+        let id = match joined {
+          std::result::Result::Ok((id, _)) => id,
+          Err(err) => err.id(),
+        };
+        in_flight.remove(&id);
+        finished += 1;
+      }
+    })
+    .await;
+    workers.abort_all();
+    while workers.join_next().await.is_some() {}
+    let abandoned: Vec<String> = in_flight.into_values().collect();
+    release_dispatcher_state(db, get_instance_id(), &held, finished, abandoned).await?;
+    Ok(())
   }
 
   fn merge_queues(&mut self, new_items: Vec<String>) -> anyhow::Result<()> {
@@ -278,16 +1395,120 @@ impl Dispatcher for DispatcherData<'_> {
   ) -> anyhow::Result<()> {
     //info!("trySendTransactions called on sub_id {}", &sub_id);
 
-    let filter = doc! { "subid": &sub_id };
+    // another replica owns this subscription, it will be picked up again once the lease expires
+    if !acquire_lease(db, &sub_id, get_instance_id()).await? {
+      info!("Subscription {} is leased by another dispatcher", sub_id);
+      return Ok(());
+    }
+
+    let filter = doc! { "_id": ObjectId::parse_str(sub_id.as_str()).unwrap() };
+    let find_option = FindOneOptions::default();
+
+    let subscription = find_one(db.collection("subscriptions"), filter, find_option)
+      .await
+      .unwrap();
+
+    if subscription.is_none() {
+      delete_many(db.collection("transactionblocks"), doc! { "subid": sub_id })
+        .await
+        .unwrap();
+      error!("Subscription does not exit anymore");
+      return Ok(());
+    }
+    let subscription = subscription.unwrap();
+    let policy = DeliveryPolicy::from_subscription(&subscription);
+
+    // deactivated and suspended subscriptions keep their blocks until they are reactivated
+    if subscription.get_bool("isActive") == std::result::Result::Ok(false) {
+      info!("Subscription {} is not active", sub_id);
+      release_lease(db, &sub_id, get_instance_id()).await?;
+      return Ok(());
+    }
+
+    // paused subscriptions keep their blocks, resuming wakes them again
+    if is_delivery_paused(db, &subscription).await? {
+      info!("Delivery to {} is paused", sub_id);
+      release_lease(db, &sub_id, get_instance_id()).await?;
+      return Ok(());
+    }
+
+    let host = Destination::from_subscription(&subscription)
+      .ok()
+      .and_then(|destination| destination.breaker_key(&subscription));
+    let admission = host.as_ref().map(|host| {
+      circuit_breakers()
+        .lock()
+        .unwrap()
+        .admit(host, Instant::now())
+    });
+    // held for the whole round so a probe that never reports a result is released on the way out
+    let _probe = match (&host, &admission) {
+      (Some(host), Some(std::result::Result::Ok(Some(probe)))) => {
+        Some(ProbeGuard::new(host.clone(), *probe))
+      }
+      _ => None,
+    };
+    let open_for = admission.and_then(|admission| admission.err());
+    if let Some(open_for) = open_for {
+      if should_suspend(&subscription, bson::DateTime::now().timestamp_millis()) {
+        suspend_subscription(db, &subscription, &failing_reason(&subscription)).await?;
+        error!(
+          "Suspended subscription {} after failing continuously",
+          sub_id
+        );
+        release_lease(db, &sub_id, get_instance_id()).await?;
+        return Ok(());
+      }
+      info!(
+        "Circuit of {} is open, failing fast for {}",
+        host.unwrap_or_default(),
+        sub_id
+      );
+      let next_delay = cmp::max(
+        open_for.as_millis() as u64,
+        policy.next_retry_delay(current_time_increase),
+      );
+      self.requeue(sub_id, next_delay);
+      return Ok(());
+    }
+
+    // topics may have changed after the blocks were queued, drop the ones no longer wanted
+    let topics = get_topics_from_doc(&subscription);
+    if !topics.is_empty() {
+      let dropped = delete_many(
+        db.collection("transactionblocks"),
+        doc! { "subid": &sub_id, "event_name": { "$nin": &topics } },
+      )
+      .await
+      .unwrap();
+      if dropped.deleted_count > 0 {
+        info!(
+          "Dropped {} transaction blocks outside of topics {:?}",
+          dropped.deleted_count, topics
+        );
+      }
+    }
+
+    // blocks stay queued until the chain is `confirmations` blocks past them
+    let head = if get_confirmations(&subscription) > 0 {
+      get_chain_head(db, subscription.get_str("contract_id").unwrap_or_default()).await?
+    } else {
+      None
+    };
+    let confirmed_height = confirmed_height(&subscription, head);
+    let filter = deliverable_blocks_filter(&sub_id, confirmed_height);
     let mut find_option = FindOptions::default();
-    find_option.sort = Some(doc! { "subid": 1, "block_number": 1 });
-    find_option.limit = Some(50);
+    // a retraction goes out before the block replacing it
+    find_option.sort = Some(doc! { "subid": 1, "block_number": 1, "removed": -1 });
+    find_option.limit = Some(policy.max_blocks);
     //find_option.projection = Some(doc! {"_id": 0});
 
     let transaction_blocks_collection = db.collection("transactionblocks");
-    let transaction_group = find_all(transaction_blocks_collection, filter, find_option)
+    let mut transaction_group = find_all(transaction_blocks_collection, filter, find_option)
       .await
       .unwrap();
+    let is_held = transaction_group.is_empty() && confirmed_height.is_some();
+    transaction_group.truncate(leading_batch_len(&transaction_group));
     let transaction_group_clone = transaction_group.clone();
 
     info!(
@@ -296,43 +1517,57 @@ impl Dispatcher for DispatcherData<'_> {
     );
 
     let mut with_problems: bool = false;
+    let mut retry_after: Option<Duration> = None;
 
-    let (current_date, new_date, sent_date) = generate_dates(10000, 60000);
+    let lock_ttl = policy.lock_ttl_millis();
+    if !acquire_lease_for(db, &sub_id, get_instance_id(), lock_ttl).await? {
+      info!("Subscription {} is leased by another dispatcher", sub_id);
+      return Ok(());
+    }
+    let (current_date, new_date, sent_date) = generate_dates(lock_ttl as u64, 60000);
 
+    // the whole batch is locked, a partial lock means someone else holds part of it
+    let batch_ids: Vec<ObjectId> = transaction_group
+      .iter()
+      .map(|block| block.get_object_id("_id").unwrap())
+      .collect();
+    let lock_id = ObjectId::new();
     let mut update_result: Option<UpdateResult> = None;
-    if !transaction_group.is_empty() {
-      update_result = Some(update_one(
+    if !batch_ids.is_empty() {
+      update_result = Some(update_many(
             db.collection("transactionblocks"),
             doc! {
-              "_id": transaction_group[0].get_object_id("_id").unwrap(),
+              "_id": { "$in": &batch_ids },
               "locked_until": { "$lte": bson::DateTime::from_millis(current_date.try_into().unwrap()) }
             },
-            doc! { "$set": { "locked_until": bson::DateTime::from_millis(new_date.try_into().unwrap()) } },
+            doc! { "$set": { "locked_until": bson::DateTime::from_millis(new_date.try_into().unwrap()), "locked_by": lock_id } },
             UpdateOptions::default(),
           )
           .await
         .unwrap());
     }
     info!("update result: {:?}", update_result);
-    let filter = doc! { "_id": ObjectId::parse_str(sub_id.as_str()).unwrap() };
-    let find_option = FindOneOptions::default();
-
-    let subscription = find_one(db.collection("subscriptions"), filter, find_option)
-      .await
-      .unwrap();
-
-    if subscription.is_none() {
-      delete_many(db.collection("transactionblocks"), doc! { "subid": sub_id })
-        .await
-        .unwrap();
-      error!("Subscription does not exit anymore");
-      return Ok(());
-    }
 
     let mut transaction_vec: Vec<Value> = Vec::new();
     let mut ack_ids: Vec<ObjectId> = Vec::new();
 
-    let is_locked = update_result.is_none() || update_result.unwrap().matched_count == 0;
+    let locked_count = update_result.map_or(0, |result| result.matched_count as usize);
+    let is_locked = batch_ids.is_empty() || locked_count != batch_ids.len();
+    if !is_locked {
+      round_locks()
+        .lock()
+        .unwrap()
+        .insert(sub_id.clone(), lock_id);
+    }
+    if is_locked && locked_count > 0 {
+      update_many(
+        db.collection("transactionblocks"),
+        doc! { "_id": { "$in": &batch_ids }, "locked_by": lock_id },
+        doc! { "$set": { "locked_until": bson::DateTime::now() }, "$unset": { "locked_by": "" } },
+        UpdateOptions::default(),
+      )
+      .await?;
+    }
     for item in &transaction_group_clone {
       let transaction_block = item;
 
@@ -342,32 +1577,78 @@ impl Dispatcher for DispatcherData<'_> {
 
         break;
       }
-      let transactions: Vec<Document> = transaction_block
-        .get_array("transactions")
-        .unwrap()
-        .iter()
-        .map(|tx| {
-          let mut doc = tx.as_document().unwrap().to_owned();
-          doc.remove("_id");
-          doc
-        })
-        .collect();
-
-      transaction_vec.push(json!({
-          "transactions": transactions,
-          "block_number":  get_i64_from_doc(&transactions[0], "block_number".to_string()),
-          "event_name": transactions[0].get_str("event_name").unwrap(),
-      }));
+      transaction_vec.push(block_payload(transaction_block));
 
       ack_ids.push(transaction_block.get_object_id("_id").unwrap());
     }
 
+    // oversized batches are cut down, the rest goes out in the next round
+    if let Some(max_payload_bytes) = policy.max_payload_bytes {
+      let fitting_blocks = blocks_within_payload_bytes(&transaction_vec, max_payload_bytes);
+      if fitting_blocks < transaction_vec.len() {
+        transaction_vec.truncate(fitting_blocks);
+        let deferred_ids = ack_ids.split_off(fitting_blocks);
+        update_many(
+          db.collection("transactionblocks"),
+          doc! { "_id": { "$in": deferred_ids }, "locked_by": lock_id },
+          doc! { "$set": { "locked_until": bson::DateTime::now() }, "$unset": { "locked_by": "" } },
+          UpdateOptions::default(),
+        )
+        .await?;
+      }
+    }
+
     if !transaction_vec.is_empty() {
-      if self
-        .dispatch_transactions(transaction_vec, &subscription.unwrap(), sub_id.clone())
+      // a retried batch goes out with the sequence of its first attempt so subscribers can dedupe it
+      let batch = match transaction_group_clone
+        .first()
+        .and_then(BatchMetadata::from_block)
+      {
+        Some(batch) => batch,
+        None => {
+          let batch = BatchMetadata {
+            sequence: next_delivery_sequence(db, &sub_id).await?,
+            replay: is_replay_block(&transaction_group_clone[0]),
+          };
+          update_many(
+            db.collection("transactionblocks"),
+            doc! { "_id": { "$in": &ack_ids } },
+            doc! { "$set": { "sequence": batch.sequence } },
+            UpdateOptions::default(),
+          )
+          .await?;
+          batch
+        }
+      };
+      let outcome = self
+        .dispatch_transactions(transaction_vec, &subscription, sub_id.clone(), &batch)
         .await
+        .unwrap();
+      let sent_blocks: Vec<Document> = transaction_group_clone
+        .into_iter()
+        .filter(|block| ack_ids.contains(&block.get_object_id("_id").unwrap()))
+        .collect();
+      if let Err(err) = record_delivery(db, &subscription, &sent_blocks, &batch, &outcome).await {
+        error!("Failed to record delivery: {:?}", err);
+      }
+      dispatcher_metrics()
+        .lock()
         .unwrap()
-      {
+        .record_delivery(&outcome);
+      // refused payloads and throttling do not say anything about the endpoint being down
+      let is_endpoint_healthy = outcome.is_endpoint_healthy();
+      if outcome.reached_endpoint() {
+        if let Some(host) = &host {
+          let mut breakers = circuit_breakers().lock().unwrap();
+          if is_endpoint_healthy {
+            breakers.record_success(host);
+          } else {
+            breakers.record_failure(host, Instant::now());
+          }
+        }
+        track_failing_since(db, &subscription, is_endpoint_healthy).await?;
+      }
+      if outcome.is_good {
         let _ = update_many(
           db.collection("transactionblocks"),
           doc! { "_id": { "$in": ack_ids.clone() } },
@@ -382,10 +1663,85 @@ impl Dispatcher for DispatcherData<'_> {
         )
         .await;
       } else {
-        error!("Failed to dispatch! ");
-        let unlock_result = update_one(db.collection("transactionblocks"), doc! { "_id":  ack_ids[0] }, doc! { "$set": { "locked_until": bson::DateTime::from_millis(current_date.try_into().unwrap()) } }, UpdateOptions::default()).await;
-        error!("unlock result: {:?}", unlock_result);
+        let failed_blocks = sent_blocks;
+        let last_error = outcome
+          .error
+          .clone()
+          .unwrap_or_else(|| "unknown delivery error".to_string());
+        error!(
+          "Failed to dispatch to {} ({}): {}",
+          sub_id,
+          outcome
+            .failure
+            .map_or("unknown", |failure| failure.as_str()),
+          last_error
+        );
+        match (outcome.failure, outcome.retry_after) {
+          // an endpoint that keeps deferring is dead-lettered like any other failing one
+          (_, Some(delay)) => {
+            let dead_lettered =
+              record_failed_attempt(db, &subscription, &failed_blocks, &last_error, Some(delay))
+                .await?;
+            if dead_lettered {
+              error!(
+                "Moved {} deferred transaction blocks of {} to deadletters",
+                failed_blocks.len(),
+                sub_id
+              );
+            } else {
+              retry_after = Some(delay);
+            }
+          }
+          (Some(FailureKind::Gone), _) => {
+            let reason = "webhook responded with HTTP 410 Gone";
+            suspend_subscription(db, &subscription, reason).await?;
+            error!("Deactivated subscription {}: {}", sub_id, reason);
+            release_lease(db, &sub_id, get_instance_id()).await?;
+            return Ok(());
+          }
+          // retrying cannot fix the subscription, its blocks wait until it is corrected and activated
+          (Some(FailureKind::Misconfigured), _) => {
+            let reason = format!("subscription is misconfigured: {}", last_error);
+            suspend_subscription(db, &subscription, &reason).await?;
+            error!("Deactivated subscription {}: {}", sub_id, reason);
+            release_lease(db, &sub_id, get_instance_id()).await?;
+            return Ok(());
+          }
+          (Some(FailureKind::PayloadTooLarge), _) if failed_blocks.len() > 1 => {
+            split_batch(db, &failed_blocks).await?;
+          }
+          // retrying a refused payload cannot succeed, it goes straight to the dead letters
+          (Some(FailureKind::PayloadTooLarge | FailureKind::PayloadRejected), _) => {
+            let attempts = get_i64_from_doc(&failed_blocks[0], "attempts".to_string()) + 1;
+            move_to_dead_letters(db, &subscription, &failed_blocks, attempts, &last_error).await?;
+            error!(
+              "Moved {} refused transaction blocks of {} to deadletters",
+              failed_blocks.len(),
+              sub_id
+            );
+          }
+          _ => {
+            let dead_lettered =
+              record_failed_attempt(db, &subscription, &failed_blocks, &last_error, None).await?;
+            if dead_lettered {
+              error!(
+                "Moved {} transaction blocks of {} to deadletters",
+                failed_blocks.len(),
+                sub_id
+              );
+            }
+          }
+        }
         with_problems = true;
+        if should_suspend(&subscription, bson::DateTime::now().timestamp_millis()) {
+          suspend_subscription(db, &subscription, &failing_reason(&subscription)).await?;
+          error!(
+            "Suspended subscription {} after failing continuously",
+            sub_id
+          );
+          release_lease(db, &sub_id, get_instance_id()).await?;
+          return Ok(());
+        }
       }
     } else {
       with_problems = true;
@@ -398,35 +1754,44 @@ impl Dispatcher for DispatcherData<'_> {
     {
       //info!("Automatic added subid {} to the queue", sub_id.clone());
 
-      let next_delay = if with_problems {
-        cmp::min(current_time_increase * 2, 10000)
+      let next_delay = if let Some(retry_after) = retry_after {
+        retry_after.as_millis() as u64
+      } else if is_held {
+        get_confirmation_poll_millis()
+      } else if with_problems {
+        policy.next_retry_delay(current_time_increase)
       } else {
-        150
+        SUCCESS_DELAY_MS
       };
-
-      if !self.queue_map.contains_key(&sub_id.clone()) {
-        self.queue_list.push_back(sub_id.clone());
-      }
-      self.queue_map.insert(
-        sub_id,
-        DelayTimes {
-          increase_timeout: next_delay,
-          wait_until: bson::DateTime::from_millis(
-            (SystemTime::now()
-              .duration_since(SystemTime::UNIX_EPOCH)
-              .expect("Time went backwards")
-              .as_millis()
-              + u128::try_from(next_delay).unwrap())
-            .try_into()
-            .unwrap(),
-          ),
-        },
-      );
+      self.requeue(sub_id, next_delay);
+    } else {
+      release_lease(db, &sub_id, get_instance_id()).await?;
     }
 
     Ok(())
   }
 
+  fn requeue(&mut self, sub_id: String, next_delay: u64) {
+    if !self.queue_map.contains_key(&sub_id.clone()) {
+      self.queue_list.push_back(sub_id.clone());
+    }
+    self.queue_map.insert(
+      sub_id,
+      DelayTimes {
+        increase_timeout: next_delay,
+        wait_until: bson::DateTime::from_millis(
+          (SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis()
+            + u128::from(next_delay))
+          .try_into()
+          .unwrap(),
+        ),
+      },
+    );
+  }
+
   async fn any_transaction_pending(
     &mut self,
     db: &Database,
@@ -447,30 +1812,87 @@ impl Dispatcher for DispatcherData<'_> {
     transactions: Vec<Value>,
     subscription: &Document,
     sub_id: String,
-  ) -> anyhow::Result<bool> {
-    let (headers, contract_id) = create_webhook_headers(sub_id, subscription)?;
-
-    let client = reqwest::Client::new();
-    let res = client
-      .post(subscription.get_str("url").unwrap())
-      .headers(headers)
-      .json(&json!({
-        "metadata": {
-          "contract_id": contract_id
-        },
-        "payload_count": transactions.len(),
-        "payload": transactions
-      }))
-      .send()
-      .await;
+    batch: &BatchMetadata,
+  ) -> anyhow::Result<DeliveryOutcome> {
+    let transform = match Transform::from_subscription(subscription) {
+      std::result::Result::Ok(transform) => transform,
+      Err(err) => {
+        return Ok(failed_outcome(
+          FailureKind::Misconfigured,
+          format!("invalid transform: {}", err),
+          Instant::now(),
+        ))
+      }
+    };
+    let format = match PayloadFormat::from_subscription(subscription) {
+      std::result::Result::Ok(format) => format,
+      Err(err) => {
+        return Ok(failed_outcome(
+          FailureKind::Misconfigured,
+          format!("invalid payload format: {}", err),
+          Instant::now(),
+        ))
+      }
+    };
+    let (mut headers, contract_id) = create_webhook_headers(sub_id.clone(), subscription, batch)?;
+    format.apply_headers(&mut headers);
+    let body = format.encode(&delivery_body(
+      transactions,
+      transform.as_ref(),
+      &contract_id,
+      batch,
+    ))?;
+    // opted in subscriptions also get the raw body signed, the jwt header stays for compatibility,
+    // the signature covers the encoded and compressed bytes as sent
+    if uses_body_signature(subscription) {
+      add_body_signature(&mut headers, subscription, &body)?;
+    }
 
-    let is_good = res.is_ok() && res.as_ref().unwrap().status().is_success();
+    let policy = DeliveryPolicy::from_subscription(subscription);
+    let started_at = Instant::now();
+    let destination = match Destination::from_subscription(subscription) {
+      std::result::Result::Ok(destination) if destination.is_enabled() => destination,
+      std::result::Result::Ok(destination) => {
+        let error = format!("{:?} delivery is not enabled in this build", destination);
+        return Ok(failed_outcome(
+          FailureKind::Misconfigured,
+          error,
+          started_at,
+        ));
+      }
+      Err(err) => {
+        let error = format!("invalid destination: {}", err);
+        return Ok(failed_outcome(
+          FailureKind::Misconfigured,
+          error,
+          started_at,
+        ));
+      }
+    };
+    let sink = match sink_for(&destination, subscription).await {
+      std::result::Result::Ok(sink) => sink,
+      Err(err) => {
+        return Ok(failed_outcome(
+          FailureKind::Connect,
+          err.to_string(),
+          started_at,
+        ))
+      }
+    };
+    let outcome = sink
+      .deliver(Delivery {
+        sub_id: &sub_id,
+        headers,
+        body,
+        timeout: policy.timeout,
+      })
+      .await;
 
-    info!("transaction was sent? {}", is_good);
-    if !is_good {
-      info!("response was {:?}", res);
+    info!("transaction was sent? {}", outcome.is_good);
+    if !outcome.is_good {
+      info!("delivery failed with {:?}", outcome.error);
     }
-    Ok(is_good)
+    Ok(outcome)
   }
 }
 
@@ -538,7 +1960,11 @@ mod tests {
         "apikey": "supersecretapikey"
     };
 
-    let (headers, contract_id) = create_webhook_headers(sub_id.clone(), &subscription)?;
+    let batch = BatchMetadata {
+      sequence: 7,
+      replay: false,
+    };
+    let (headers, contract_id) = create_webhook_headers(sub_id.clone(), &subscription, &batch)?;
 
     // Check contract_id
     assert_eq!(contract_id, "contract123");
@@ -566,8 +1992,9 @@ mod tests {
     );
     assert_eq!(
       headers.get("x-msl-webhook-nonce").unwrap(),
-      HeaderValue::from_str("-1").unwrap()
+      HeaderValue::from_str("7").unwrap()
     );
+    assert!(headers.get("x-msl-webhook-replay").is_none());
 
     // Check x-msl-webhook-timestamp
     let timestamp = headers.get("x-msl-webhook-timestamp").unwrap().to_str()?;
@@ -17,6 +17,22 @@ pub fn get_i64_from_doc(doc: &Document, key: String) -> i64 {
   }
 }
 
+pub fn get_topics_from_doc(subscription: &Document) -> Vec<String> {
+  match subscription.get_array("topics") {
+    Ok(topics) => topics
+      .iter()
+      .filter_map(|topic| topic.as_str().map(|t| t.to_string()))
+      .collect(),
+    Err(_) => vec![],
+  }
+}
+
+// an empty topics list means the subscription wants every event of the contract
+pub fn subscription_wants_event(subscription: &Document, event_name: &str) -> bool {
+  let topics = get_topics_from_doc(subscription);
+  topics.is_empty() || topics.iter().any(|topic| topic == event_name)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SingleTransaction {
   pub _id: String, //is it really needed?
@@ -41,7 +57,7 @@ pub struct Transaction {
 #[cfg(test)]
 mod tests {
   use super::*;
-  use bson::{doc, Bson};
+  use bson::doc;
 
   #[test]
   fn test_get_i64_from_doc() {
@@ -54,4 +70,16 @@ mod tests {
     assert_eq!(get_i64_from_doc(&doc, "bar".to_string()), 23);
     assert_eq!(get_i64_from_doc(&doc, "baz".to_string()), 0);
   }
+
+  #[test]
+  fn test_subscription_wants_event() {
+    let all_events = doc! { "topics": [] };
+    let no_topics_field = doc! {};
+    let transfers_only = doc! { "topics": ["Transfer"] };
+
+    assert!(subscription_wants_event(&all_events, "Approval"));
+    assert!(subscription_wants_event(&no_topics_field, "Approval"));
+    assert!(subscription_wants_event(&transfers_only, "Transfer"));
+    assert!(!subscription_wants_event(&transfers_only, "Approval"));
+  }
 }
@@ -0,0 +1,432 @@
+use crate::{
+  consumer_api::{
+    accept_transactions, allows_unauthenticated_ingest, constant_time_eq,
+    get_ingest_max_body_bytes, get_ingest_secret, IngestReport, TransactionBlock, Transactions,
+  },
+  dispatcher::{get_signature_tolerance_secs, verify_body_signature},
+  shutdown::shutdown_token,
+};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use mongodb::{bson::Document, Database};
+use serde::{Deserialize, Serialize};
+use snailquote::unescape;
+use std::{
+  collections::HashMap,
+  env,
+  sync::{Mutex, OnceLock},
+  time::{Duration, Instant},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{
+  accept_hdr_async_with_config,
+  tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+    Message,
+  },
+  WebSocketStream,
+};
+use validator::Validate;
+
+const DEFAULT_INGEST_WS_PORT: u16 = 3004;
+const DEFAULT_INGEST_WS_CREDITS: u32 = 32;
+const DEFAULT_INGEST_WS_SLOW_MS: u64 = 1000;
+const DEFAULT_INGEST_WS_MAX_QUEUED: u64 = 1_000_000;
+const DEFAULT_INGEST_WS_POLL_MS: u64 = 1000;
+
+// `Sec-WebSocket-Key` of every signed handshake accepted within the signature tolerance
+static SIGNED_HANDSHAKE_KEYS: OnceLock<Mutex<HashMap<String, i64>>> = OnceLock::new();
+
+// one block of one contract, `seq` is echoed in the ack
+#[derive(Deserialize, Debug, Validate)]
+pub struct IngestFrame {
+  pub seq: u64,
+  #[validate(length(min = 1))]
+  pub contract_id: String,
+  pub reset_nonce: i64,
+  #[serde(default)]
+  #[validate(range(min = 0))]
+  pub head_block: Option<i64>,
+  #[validate]
+  pub block: TransactionBlock,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IngestMessage {
+  Credit {
+    credits: u32,
+  },
+  Ack {
+    seq: u64,
+    accepted: bool,
+    event_name: String,
+    reset_nonce: i64,
+    // the last block accepted for the event, the writer resumes after it
+    checkpoint: i64,
+    credits: u32,
+  },
+  Nack {
+    seq: Option<u64>,
+    message: String,
+    credits: u32,
+  },
+}
+
+// every frame spends a credit, credits come back with the ack unless the dispatcher is behind
+#[derive(Debug, PartialEq)]
+pub struct FlowControl {
+  pub available: u32,
+  pub withheld: u32,
+}
+
+impl FlowControl {
+  pub fn new(window: u32) -> Self {
+    FlowControl {
+      available: window,
+      withheld: 0,
+    }
+  }
+
+  // false when the writer sent a frame without holding a credit
+  pub fn spend(&mut self) -> bool {
+    if self.available == 0 {
+      return false;
+    }
+    self.available -= 1;
+    true
+  }
+
+  // the credits to return after a frame, everything held back so far once the dispatcher caught up
+  pub fn settle(&mut self, behind: bool) -> u32 {
+    if behind {
+      self.withheld += 1;
+      return 0;
+    }
+    let credits = self.withheld + 1;
+    self.withheld = 0;
+    self.available += credits;
+    credits
+  }
+
+  pub fn release(&mut self) -> u32 {
+    let credits = self.withheld;
+    self.withheld = 0;
+    self.available += credits;
+    credits
+  }
+}
+
+pub fn get_ingest_ws_port() -> u16 {
+  env::var("INGEST_WS_PORT")
+    .ok()
+    .and_then(|value| value.parse::<u16>().ok())
+    .unwrap_or(DEFAULT_INGEST_WS_PORT)
+}
+
+pub fn get_ingest_ws_credits() -> u32 {
+  env::var("INGEST_WS_CREDITS")
+    .ok()
+    .and_then(|value| value.parse::<u32>().ok())
+    .filter(|credits| *credits > 0)
+    .unwrap_or(DEFAULT_INGEST_WS_CREDITS)
+}
+
+pub fn get_ingest_ws_slow_millis() -> u64 {
+  env::var("INGEST_WS_SLOW_MS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_INGEST_WS_SLOW_MS)
+}
+
+pub fn get_ingest_ws_max_queued() -> u64 {
+  env::var("INGEST_WS_MAX_QUEUED")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_INGEST_WS_MAX_QUEUED)
+}
+
+pub fn get_ingest_ws_poll_millis() -> u64 {
+  env::var("INGEST_WS_POLL_MS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_INGEST_WS_POLL_MS)
+}
+
+pub fn is_behind(elapsed: Duration, queued_blocks: u64) -> bool {
+  elapsed > Duration::from_millis(get_ingest_ws_slow_millis())
+    || queued_blocks > get_ingest_ws_max_queued()
+}
+
+// times a cheap query to see how Mongo keeps up, next to the size of the delivery queue
+async fn is_dispatcher_behind(db: &Database, elapsed: Duration) -> bool {
+  let started = Instant::now();
+  match db
+    .collection::<Document>("transactionblocks")
+    .estimated_document_count(None)
+    .await
+  {
+    Ok(queued_blocks) => is_behind(elapsed.max(started.elapsed()), queued_blocks),
+    Err(err) => {
+      warn!("Could not measure the delivery queue: {:?}", err);
+      true
+    }
+  }
+}
+
+pub fn ack_from_report(
+  frame_seq: u64,
+  block: &TransactionBlock,
+  reset_nonce: i64,
+  report: &IngestReport,
+  credits: u32,
+) -> IngestMessage {
+  let skipped = report.skipped.first();
+  IngestMessage::Ack {
+    seq: frame_seq,
+    accepted: skipped.is_none(),
+    event_name: block.event_name.clone(),
+    reset_nonce,
+    checkpoint: skipped.map_or(block.block_number, |skipped| skipped.checkpoint),
+    credits,
+  }
+}
+
+// what the signature of a handshake covers in place of a body, `websocket_key` is the
+// `Sec-WebSocket-Key` the client picked for this connection
+pub fn handshake_signing_payload(method: &str, path: &str, websocket_key: &str) -> String {
+  format!("{} {}\n{}", method, path, websocket_key)
+}
+
+// false when the key was already used by a signed handshake, so a captured one cannot be replayed
+fn remember_handshake_key(websocket_key: &str, now: i64) -> bool {
+  let tolerance = get_signature_tolerance_secs();
+  let mut keys = SIGNED_HANDSHAKE_KEYS
+    .get_or_init(|| Mutex::new(HashMap::new()))
+    .lock()
+    .unwrap();
+  // a signature is accepted up to `tolerance` on either side of its timestamp
+  keys.retain(|_, seen_at| now - *seen_at <= 2 * tolerance);
+  keys.insert(websocket_key.to_string(), now).is_none()
+}
+
+// same credentials as the HTTP endpoints, a signature covers the method, the path and the
+// `Sec-WebSocket-Key` of the handshake
+fn is_handshake_authorized(req: &Request, secret: &str, now: i64) -> bool {
+  if let Some(key) = req.headers().get("x-msl-ingest-key") {
+    return constant_time_eq(key.as_bytes(), secret.as_bytes());
+  }
+  let header = |name: &str| {
+    req
+      .headers()
+      .get(name)
+      .and_then(|value| value.to_str().ok())
+  };
+  match (
+    header("x-msl-ingest-signature"),
+    header("sec-websocket-key"),
+  ) {
+    (Some(signature), Some(websocket_key)) => {
+      let payload =
+        handshake_signing_payload(req.method().as_str(), req.uri().path(), websocket_key);
+      verify_body_signature(
+        signature,
+        payload.as_bytes(),
+        secret,
+        get_signature_tolerance_secs(),
+        now,
+      ) && remember_handshake_key(websocket_key, now)
+    }
+    _ => false,
+  }
+}
+
+// the error type is fixed by tungstenite's handshake callback
+#[allow(clippy::result_large_err)]
+fn authorize_handshake(req: &Request, response: Response) -> Result<Response, ErrorResponse> {
+  let has_credentials = ["x-msl-ingest-key", "x-msl-ingest-signature"]
+    .iter()
+    .any(|name| req.headers().contains_key(*name));
+  let authorized = match get_ingest_secret() {
+    _ if !has_credentials && allows_unauthenticated_ingest() => true,
+    Some(secret) => is_handshake_authorized(req, &secret, Utc::now().timestamp()),
+    None => {
+      error!("INGEST_SECRET is not set, refusing ingestion");
+      false
+    }
+  };
+  if authorized {
+    return Ok(response);
+  }
+  let mut error_response = ErrorResponse::new(Some("invalid ingest credentials".to_string()));
+  *error_response.status_mut() = StatusCode::UNAUTHORIZED;
+  Err(error_response)
+}
+
+async fn process_frame(db: &Database, text: &str, flow: &mut FlowControl) -> IngestMessage {
+  let started = Instant::now();
+  let frame: IngestFrame = match serde_json::from_str(text) {
+    Ok(frame) => frame,
+    Err(err) => {
+      return IngestMessage::Nack {
+        seq: None,
+        message: format!("invalid frame: {}", err),
+        credits: flow.settle(false),
+      }
+    }
+  };
+  let seq = frame.seq;
+  if let Err(err) = frame.validate() {
+    return IngestMessage::Nack {
+      seq: Some(seq),
+      message: format!("invalid frame: {}", err),
+      credits: flow.settle(false),
+    };
+  }
+  let contract_id = match unescape(&frame.contract_id) {
+    Ok(contract_id) => contract_id,
+    Err(err) => {
+      return IngestMessage::Nack {
+        seq: Some(seq),
+        message: format!("invalid contract_id: {}", err),
+        credits: flow.settle(false),
+      }
+    }
+  };
+
+  let payload = Transactions {
+    contract_id: frame.contract_id,
+    reset_nonce: frame.reset_nonce,
+    data: vec![frame.block],
+    head_block: frame.head_block,
+  };
+  match accept_transactions(db, &contract_id, &payload).await {
+    Ok(report) => {
+      let behind = is_dispatcher_behind(db, started.elapsed()).await;
+      ack_from_report(
+        seq,
+        &payload.data[0],
+        payload.reset_nonce,
+        &report,
+        flow.settle(behind),
+      )
+    }
+    Err(err) => {
+      error!(
+        "Failed to ingest frame {} of {:?}: {:?}",
+        seq, contract_id, err
+      );
+      IngestMessage::Nack {
+        seq: Some(seq),
+        message: "ingestion failed, the block was not accepted".to_string(),
+        credits: flow.settle(true),
+      }
+    }
+  }
+}
+
+async fn send_message(
+  ws: &mut WebSocketStream<TcpStream>,
+  message: &IngestMessage,
+) -> anyhow::Result<()> {
+  ws.send(Message::Text(serde_json::to_string(message)?))
+    .await?;
+  Ok(())
+}
+
+async fn handle_connection(db: Database, stream: TcpStream) -> anyhow::Result<()> {
+  let config = WebSocketConfig {
+    max_message_size: Some(get_ingest_max_body_bytes()),
+    ..Default::default()
+  };
+  let mut ws = accept_hdr_async_with_config(stream, authorize_handshake, Some(config)).await?;
+  let mut flow = FlowControl::new(get_ingest_ws_credits());
+  send_message(
+    &mut ws,
+    &IngestMessage::Credit {
+      credits: flow.available,
+    },
+  )
+  .await?;
+
+  let poll = Duration::from_millis(get_ingest_ws_poll_millis());
+  loop {
+    let message = tokio::select! {
+      message = ws.next() => message,
+      _ = tokio::time::sleep(poll), if flow.withheld > 0 => {
+        if !is_dispatcher_behind(&db, Duration::ZERO).await {
+          let credits = flow.release();
+          send_message(&mut ws, &IngestMessage::Credit { credits }).await?;
+        }
+        continue;
+      }
+      // every frame acked so far is committed, the writer resumes on another replica
+      _ = shutdown_token().cancelled() => {
+        ws.close(Some(CloseFrame {
+          code: CloseCode::Away,
+          reason: "dispatcher shutting down".into(),
+        }))
+        .await?;
+        break;
+      }
+    };
+
+    let text = match message {
+      Some(message) => match message? {
+        Message::Text(text) => text,
+        Message::Binary(_) => {
+          send_message(
+            &mut ws,
+            &IngestMessage::Nack {
+              seq: None,
+              message: "frames are JSON text messages".to_string(),
+              credits: 0,
+            },
+          )
+          .await?;
+          continue;
+        }
+        Message::Close(_) => break,
+        // pings are answered by tungstenite
+        _ => continue,
+      },
+      None => break,
+    };
+    if !flow.spend() {
+      ws.close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "frame sent without credit".into(),
+      }))
+      .await?;
+      break;
+    }
+    let reply = process_frame(&db, &text, &mut flow).await;
+    send_message(&mut ws, &reply).await?;
+  }
+  Ok(())
+}
+
+pub async fn serve_ingest_connections(listener: TcpListener, db: Database) -> anyhow::Result<()> {
+  loop {
+    let (stream, peer) = tokio::select! {
+      accepted = listener.accept() => accepted?,
+      _ = shutdown_token().cancelled() => return Ok(()),
+    };
+    let db = db.clone();
+    tokio::spawn(async move {
+      if let Err(err) = handle_connection(db, stream).await {
+        warn!("Ingestion connection from {} failed: {:?}", peer, err);
+      }
+    });
+  }
+}
+
+pub async fn run_ingest_server(db: Database) -> anyhow::Result<()> {
+  let port = get_ingest_ws_port();
+  let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+  info!("Ingestion WebSocket listening on port {}", port);
+  serve_ingest_connections(listener, db).await
+}
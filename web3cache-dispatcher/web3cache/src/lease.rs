@@ -0,0 +1,90 @@
+use crate::database::is_duplicate_key_error;
+use bson::{doc, oid::ObjectId, Document};
+use mongodb::{options::UpdateOptions, Database};
+use std::{env, sync::OnceLock};
+
+const DEFAULT_LEASE_TTL_SECS: i64 = 30;
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+// identifies this replica as lease owner, the pod name when running in kubernetes
+pub fn get_instance_id() -> &'static str {
+  INSTANCE_ID.get_or_init(|| {
+    env::var("DISPATCHER_INSTANCE_ID")
+      .or_else(|_| env::var("HOSTNAME"))
+      .unwrap_or_else(|_| ObjectId::new().to_string())
+  })
+}
+
+pub fn get_lease_ttl_millis() -> i64 {
+  env::var("DISPATCHER_LEASE_TTL_SECS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_LEASE_TTL_SECS)
+    * 1000
+}
+
+// takes or renews the lease of a subscription, false while another replica holds a live one
+pub async fn acquire_lease(db: &Database, sub_id: &str, owner: &str) -> anyhow::Result<bool> {
+  acquire_lease_for(db, sub_id, owner, get_lease_ttl_millis()).await
+}
+
+// same as `acquire_lease`, held for `ttl_millis` instead of the configured TTL
+pub async fn acquire_lease_for(
+  db: &Database,
+  sub_id: &str,
+  owner: &str,
+  ttl_millis: i64,
+) -> anyhow::Result<bool> {
+  let now = bson::DateTime::now();
+  let expires_at = bson::DateTime::from_millis(now.timestamp_millis() + ttl_millis);
+  let result = db
+    .collection::<Document>("dispatcherleases")
+    .update_one(
+      doc! {
+        "_id": sub_id,
+        "$or": [{ "owner": owner }, { "expires_at": { "$lte": now } }],
+      },
+      doc! { "$set": { "owner": owner, "expires_at": expires_at } },
+      UpdateOptions::builder().upsert(true).build(),
+    )
+    .await;
+  match result {
+    Ok(_) => Ok(true),
+    Err(err) if is_duplicate_key_error(&err) => Ok(false),
+    Err(err) => Err(err.into()),
+  }
+}
+
+// takes the lease whoever holds it, a round another replica has in flight keeps its block lock
+pub async fn take_over_lease(db: &Database, sub_id: &str, owner: &str) -> anyhow::Result<()> {
+  let expires_at =
+    bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + get_lease_ttl_millis());
+  db.collection::<Document>("dispatcherleases")
+    .update_one(
+      doc! { "_id": sub_id },
+      doc! { "$set": { "owner": owner, "expires_at": expires_at } },
+      UpdateOptions::builder().upsert(true).build(),
+    )
+    .await?;
+  Ok(())
+}
+
+// the leases among `sub_ids` this owner holds, returns how many were released
+pub async fn release_leases(db: &Database, sub_ids: &[String], owner: &str) -> anyhow::Result<u64> {
+  if sub_ids.is_empty() {
+    return Ok(0);
+  }
+  let result = db
+    .collection::<Document>("dispatcherleases")
+    .delete_many(doc! { "_id": { "$in": sub_ids }, "owner": owner }, None)
+    .await?;
+  Ok(result.deleted_count)
+}
+
+pub async fn release_lease(db: &Database, sub_id: &str, owner: &str) -> anyhow::Result<()> {
+  db.collection::<Document>("dispatcherleases")
+    .delete_one(doc! { "_id": sub_id, "owner": owner }, None)
+    .await?;
+  Ok(())
+}
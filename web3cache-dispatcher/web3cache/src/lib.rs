@@ -1,4 +1,15 @@
+pub mod admin_api;
+pub mod chain_head;
+pub mod circuit_breaker;
 pub mod consumer_api;
 pub mod database;
+pub mod delivery_sink;
 pub mod dispatcher;
 pub mod helper_functions;
+pub mod ingest_ws;
+pub mod lease;
+pub mod metrics;
+pub mod payload_encoding;
+pub mod realtime_outbox;
+pub mod shutdown;
+pub mod transform;
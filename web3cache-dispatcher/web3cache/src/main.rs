@@ -1,12 +1,7 @@
-mod consumer_api;
-mod database;
-mod dispatcher;
-mod helper_functions;
+use actix_web::{rt::signal, web, App, HttpServer};
+use web3cache::helper_functions::AppState;
 
-use actix_web::{web, App, HttpServer};
-use helper_functions::AppState;
-
-use log::info;
+use log::{error, info, warn};
 use std::{
   collections::{HashMap, LinkedList},
   env,
@@ -14,13 +9,40 @@ use std::{
 extern crate dotenv;
 use dotenv::dotenv;
 
-use crate::database::connect_to_mongodb;
-use crate::{
+use web3cache::database::connect_to_mongodb;
+use web3cache::{
+  admin_api::{
+    admin_pause_contract, admin_pause_subscription, admin_purge_blocks, admin_queue,
+    admin_resume_contract, admin_resume_subscription, admin_retry_subscription,
+  },
   database::setup_indexes,
   dispatcher::{Dispatcher, DispatcherData},
+  ingest_ws::run_ingest_server,
+  metrics::run_pending_blocks_refresher,
+  realtime_outbox::{get_realtime_url, run_realtime_publisher},
+  shutdown::shutdown_token,
+};
+
+use web3cache::consumer_api::{
+  allows_unauthenticated_ingest, consumer_health_check, get_checkpoint, metrics, push_head,
+  push_reorg, push_transactions, start_checkpoint_epoch,
 };
 
-use crate::consumer_api::{consumer_health_check, push_transactions};
+// kubernetes sends SIGTERM before stopping the pod, SIGINT covers running locally
+async fn wait_for_shutdown_signal() {
+  let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+    Ok(terminate) => terminate,
+    Err(err) => {
+      error!("Could not listen for SIGTERM: {:?}", err);
+      _ = signal::ctrl_c().await;
+      return;
+    }
+  };
+  tokio::select! {
+    _ = terminate.recv() => {}
+    _ = signal::ctrl_c() => {}
+  }
+}
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -36,6 +58,9 @@ async fn main() -> anyhow::Result<()> {
 
   info!("Connected to mongodb");
   setup_indexes(&db).await?;
+  if allows_unauthenticated_ingest() {
+    warn!("ALLOW_UNAUTHENTICATED_INGEST is set, ingestion without credentials is accepted");
+  }
 
   //Subscription API
   let db_clone = db.clone();
@@ -47,13 +72,31 @@ async fn main() -> anyhow::Result<()> {
         db: db_clone.clone(),
       }))
       .service(consumer_health_check)
+      .service(metrics)
       .service(push_transactions)
+      .service(push_head)
+      .service(push_reorg)
+      .service(get_checkpoint)
+      .service(start_checkpoint_epoch)
+      .service(
+        web::scope("/admin")
+          .service(admin_queue)
+          .service(admin_pause_subscription)
+          .service(admin_resume_subscription)
+          .service(admin_pause_contract)
+          .service(admin_resume_contract)
+          .service(admin_retry_subscription)
+          .service(admin_purge_blocks),
+      )
   })
   .bind(format!("0.0.0.0:{consumer_port}"))? //hardcoded TODO
   .workers(1)
+  .disable_signals()
   .run();
+  let consumer_handle = consumer_server.handle();
+  let consumer_task = tokio::spawn(consumer_server);
 
-  tokio::spawn(async move {
+  let dispatcher_task = tokio::spawn(async move {
     let db3 = connect_to_mongodb(false).await.unwrap();
     let mut dispatcher_data = DispatcherData {
       queue_list: LinkedList::new(),
@@ -64,7 +107,42 @@ async fn main() -> anyhow::Result<()> {
       .await
       .unwrap();
   });
+  let realtime_task = match get_realtime_url() {
+    Some(realtime_url) => Some(tokio::spawn(run_realtime_publisher(
+      db.clone(),
+      realtime_url,
+    ))),
+    None => {
+      info!("REALTIME_URL is not set, realtime notifications are off");
+      None
+    }
+  };
+  let pending_blocks_task = tokio::spawn(run_pending_blocks_refresher(db.clone()));
+  let db_ingest = db.clone();
+  tokio::spawn(async move {
+    if let Err(err) = run_ingest_server(db_ingest).await {
+      error!("Ingestion WebSocket stopped: {:?}", err);
+    }
+  });
   info!("CI/CD working");
-  consumer_server.await?;
+
+  wait_for_shutdown_signal().await;
+  info!("Shutdown requested, no longer accepting transactions");
+  shutdown_token().cancel();
+  // the consumer keeps answering until the deliveries are wound down, ingestion with a 503
+  if let Err(err) = dispatcher_task.await {
+    error!("Dispatcher stopped abnormally: {:?}", err);
+  }
+  if let Some(realtime_task) = realtime_task {
+    if let Err(err) = realtime_task.await {
+      error!("Realtime publisher stopped abnormally: {:?}", err);
+    }
+  }
+  if let Err(err) = pending_blocks_task.await {
+    error!("Pending blocks refresher stopped abnormally: {:?}", err);
+  }
+  consumer_handle.stop(true).await;
+  consumer_task.await??;
+  info!("Shutdown complete");
   Ok(())
 }
@@ -0,0 +1,397 @@
+use crate::{dispatcher::DeliveryOutcome, shutdown::shutdown_token};
+use bson::{doc, Document};
+use futures::stream::TryStreamExt;
+use log::warn;
+use mongodb::Database;
+use std::{
+  collections::BTreeMap,
+  env,
+  fmt::Write,
+  sync::{Mutex, OnceLock},
+  time::Duration,
+};
+
+const DELIVERY_LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0];
+const DEFAULT_PENDING_BLOCKS_REFRESH_SECS: u64 = 30;
+
+static METRICS: OnceLock<Mutex<Metrics>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+  pub bounds: Vec<f64>,
+  // observations per bucket, the last one is +Inf
+  pub counts: Vec<u64>,
+  pub sum: f64,
+}
+
+impl Histogram {
+  pub fn new(bounds: &[f64]) -> Self {
+    Histogram {
+      bounds: bounds.to_vec(),
+      counts: vec![0; bounds.len() + 1],
+      sum: 0.0,
+    }
+  }
+
+  pub fn observe(&mut self, value: f64) {
+    let bucket = self
+      .bounds
+      .iter()
+      .position(|bound| value <= *bound)
+      .unwrap_or(self.bounds.len());
+    self.counts[bucket] += 1;
+    self.sum += value;
+  }
+
+  pub fn count(&self) -> u64 {
+    self.counts.iter().sum()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+  // subscriptions waiting in the delay queue and the ones being delivered right now
+  pub queue_depth: usize,
+  pub in_flight: usize,
+  pub delivery_attempts: BTreeMap<String, u64>,
+  pub delivery_successes: BTreeMap<String, u64>,
+  // keyed by status class and failure kind
+  pub delivery_failures: BTreeMap<(String, String), u64>,
+  pub delivery_latency: Histogram,
+  pub ingest_requests: BTreeMap<String, u64>,
+  // keyed by contract and whether the block was accepted or skipped
+  pub ingested_blocks: BTreeMap<(String, String), u64>,
+  // last snapshot of the pending blocks, scrapes never query mongo themselves
+  pub pending_blocks: Vec<PendingBlocks>,
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Metrics {
+      queue_depth: 0,
+      in_flight: 0,
+      delivery_attempts: BTreeMap::new(),
+      delivery_successes: BTreeMap::new(),
+      delivery_failures: BTreeMap::new(),
+      delivery_latency: Histogram::new(&DELIVERY_LATENCY_BUCKETS),
+      ingest_requests: BTreeMap::new(),
+      ingested_blocks: BTreeMap::new(),
+      pending_blocks: Vec::new(),
+    }
+  }
+}
+
+impl Metrics {
+  pub fn set_queue(&mut self, queue_depth: usize, in_flight: usize) {
+    self.queue_depth = queue_depth;
+    self.in_flight = in_flight;
+  }
+
+  pub fn set_pending_blocks(&mut self, pending_blocks: Vec<PendingBlocks>) {
+    self.pending_blocks = pending_blocks;
+  }
+
+  pub fn record_delivery(&mut self, outcome: &DeliveryOutcome) {
+    let status_class = status_class(outcome.status).to_string();
+    *self
+      .delivery_attempts
+      .entry(status_class.clone())
+      .or_default() += 1;
+    if outcome.is_good {
+      *self.delivery_successes.entry(status_class).or_default() += 1;
+    } else {
+      let failure = outcome
+        .failure
+        .map_or("unknown", |failure| failure.as_str())
+        .to_string();
+      *self
+        .delivery_failures
+        .entry((status_class, failure))
+        .or_default() += 1;
+    }
+    self
+      .delivery_latency
+      .observe(outcome.latency_ms.max(0) as f64 / 1000.0);
+  }
+
+  pub fn record_ingestion(&mut self, contract_id: &str, accepted: usize, skipped: usize) {
+    *self
+      .ingest_requests
+      .entry(contract_id.to_string())
+      .or_default() += 1;
+    for (result, blocks) in [("accepted", accepted), ("skipped", skipped)] {
+      *self
+        .ingested_blocks
+        .entry((contract_id.to_string(), result.to_string()))
+        .or_default() += blocks as u64;
+    }
+  }
+}
+
+// shared by the consumer server, the ingestion socket and every delivery worker
+pub fn dispatcher_metrics() -> &'static Mutex<Metrics> {
+  METRICS.get_or_init(|| Mutex::new(Metrics::default()))
+}
+
+// "error" when the request failed before a response came back
+pub fn status_class(status: Option<u16>) -> &'static str {
+  match status {
+    Some(100..=199) => "1xx",
+    Some(200..=299) => "2xx",
+    Some(300..=399) => "3xx",
+    Some(400..=499) => "4xx",
+    Some(_) => "5xx",
+    None => "error",
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingBlocks {
+  pub subid: String,
+  pub count: i64,
+  // creation time of the oldest queued block, taken from its ObjectId
+  pub oldest_millis: i64,
+}
+
+pub async fn load_pending_blocks(db: &Database) -> anyhow::Result<Vec<PendingBlocks>> {
+  let pipeline = vec![doc! {
+    "$group": { "_id": "$subid", "count": { "$sum": 1 }, "oldest": { "$min": "$_id" } },
+  }];
+  let groups: Vec<Document> = db
+    .collection::<Document>("transactionblocks")
+    .aggregate(pipeline, None)
+    .await?
+    .try_collect()
+    .await?;
+  Ok(
+    groups
+      .iter()
+      .filter_map(|group| {
+        Some(PendingBlocks {
+          subid: group.get_str("_id").ok()?.to_string(),
+          count: group
+            .get_i32("count")
+            .map(i64::from)
+            .or_else(|_| group.get_i64("count"))
+            .ok()?,
+          oldest_millis: group
+            .get_object_id("oldest")
+            .ok()?
+            .timestamp()
+            .timestamp_millis(),
+        })
+      })
+      .collect(),
+  )
+}
+
+pub fn get_pending_blocks_refresh_secs() -> u64 {
+  env::var("METRICS_PENDING_REFRESH_SECS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .filter(|secs| *secs > 0)
+    .unwrap_or(DEFAULT_PENDING_BLOCKS_REFRESH_SECS)
+}
+
+// the $group over transactionblocks runs here on an interval, not once per scrape
+pub async fn run_pending_blocks_refresher(db: Database) {
+  let refresh = Duration::from_secs(get_pending_blocks_refresh_secs());
+  while !shutdown_token().is_cancelled() {
+    match load_pending_blocks(&db).await {
+      Ok(pending) => dispatcher_metrics()
+        .lock()
+        .unwrap()
+        .set_pending_blocks(pending),
+      // the previous snapshot is served until a refresh succeeds
+      Err(err) => warn!("Could not refresh the pending blocks: {:?}", err),
+    }
+    tokio::select! {
+      _ = tokio::time::sleep(refresh) => {}
+      _ = shutdown_token().cancelled() => {}
+    }
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+  _ = writeln!(out, "# HELP {} {}", name, help);
+  _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Prometheus text exposition format
+pub fn render_metrics(metrics: &Metrics, pending: &[PendingBlocks], now_millis: i64) -> String {
+  let mut out = String::new();
+
+  write_header(
+    &mut out,
+    "web3cache_dispatcher_queue_depth",
+    "gauge",
+    "Subscriptions waiting in the dispatcher queue.",
+  );
+  _ = writeln!(
+    out,
+    "web3cache_dispatcher_queue_depth {}",
+    metrics.queue_depth
+  );
+  write_header(
+    &mut out,
+    "web3cache_dispatcher_in_flight",
+    "gauge",
+    "Subscriptions being delivered right now.",
+  );
+  _ = writeln!(out, "web3cache_dispatcher_in_flight {}", metrics.in_flight);
+
+  write_header(
+    &mut out,
+    "web3cache_pending_blocks",
+    "gauge",
+    "Transaction blocks queued for delivery per subscription.",
+  );
+  for blocks in pending {
+    _ = writeln!(
+      out,
+      "web3cache_pending_blocks{{subid=\"{}\"}} {}",
+      escape_label(&blocks.subid),
+      blocks.count
+    );
+  }
+  write_header(
+    &mut out,
+    "web3cache_oldest_pending_block_age_seconds",
+    "gauge",
+    "Age of the oldest undelivered block per subscription.",
+  );
+  for blocks in pending {
+    _ = writeln!(
+      out,
+      "web3cache_oldest_pending_block_age_seconds{{subid=\"{}\"}} {}",
+      escape_label(&blocks.subid),
+      (now_millis - blocks.oldest_millis).max(0) as f64 / 1000.0
+    );
+  }
+  write_header(
+    &mut out,
+    "web3cache_oldest_undelivered_block_age_seconds",
+    "gauge",
+    "Age of the oldest undelivered block of any subscription.",
+  );
+  let oldest_age = pending
+    .iter()
+    .map(|blocks| (now_millis - blocks.oldest_millis).max(0))
+    .max()
+    .unwrap_or(0);
+  _ = writeln!(
+    out,
+    "web3cache_oldest_undelivered_block_age_seconds {}",
+    oldest_age as f64 / 1000.0
+  );
+
+  write_header(
+    &mut out,
+    "web3cache_delivery_attempts_total",
+    "counter",
+    "Webhook delivery attempts by response status class.",
+  );
+  for (status_class, count) in &metrics.delivery_attempts {
+    _ = writeln!(
+      out,
+      "web3cache_delivery_attempts_total{{status_class=\"{}\"}} {}",
+      status_class, count
+    );
+  }
+  write_header(
+    &mut out,
+    "web3cache_delivery_successes_total",
+    "counter",
+    "Successful webhook deliveries by response status class.",
+  );
+  for (status_class, count) in &metrics.delivery_successes {
+    _ = writeln!(
+      out,
+      "web3cache_delivery_successes_total{{status_class=\"{}\"}} {}",
+      status_class, count
+    );
+  }
+  write_header(
+    &mut out,
+    "web3cache_delivery_failures_total",
+    "counter",
+    "Failed webhook deliveries by response status class and failure kind.",
+  );
+  for ((status_class, failure), count) in &metrics.delivery_failures {
+    _ = writeln!(
+      out,
+      "web3cache_delivery_failures_total{{status_class=\"{}\",failure=\"{}\"}} {}",
+      status_class, failure, count
+    );
+  }
+
+  let latency = &metrics.delivery_latency;
+  write_header(
+    &mut out,
+    "web3cache_delivery_latency_seconds",
+    "histogram",
+    "Webhook delivery latency.",
+  );
+  let mut cumulative = 0;
+  for (bound, count) in latency.bounds.iter().zip(&latency.counts) {
+    cumulative += count;
+    _ = writeln!(
+      out,
+      "web3cache_delivery_latency_seconds_bucket{{le=\"{}\"}} {}",
+      bound, cumulative
+    );
+  }
+  _ = writeln!(
+    out,
+    "web3cache_delivery_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+    latency.count()
+  );
+  _ = writeln!(
+    out,
+    "web3cache_delivery_latency_seconds_sum {}",
+    latency.sum
+  );
+  _ = writeln!(
+    out,
+    "web3cache_delivery_latency_seconds_count {}",
+    latency.count()
+  );
+
+  write_header(
+    &mut out,
+    "web3cache_ingest_requests_total",
+    "counter",
+    "Ingestion requests and frames per contract.",
+  );
+  for (contract_id, count) in &metrics.ingest_requests {
+    _ = writeln!(
+      out,
+      "web3cache_ingest_requests_total{{contract_id=\"{}\"}} {}",
+      escape_label(contract_id),
+      count
+    );
+  }
+  write_header(
+    &mut out,
+    "web3cache_ingested_blocks_total",
+    "counter",
+    "Transaction blocks received per contract, accepted or skipped as already ingested.",
+  );
+  for ((contract_id, result), count) in &metrics.ingested_blocks {
+    _ = writeln!(
+      out,
+      "web3cache_ingested_blocks_total{{contract_id=\"{}\",result=\"{}\"}} {}",
+      escape_label(contract_id),
+      result,
+      count
+    );
+  }
+  out
+}
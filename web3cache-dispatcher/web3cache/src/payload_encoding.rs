@@ -0,0 +1,145 @@
+use actix_http::header::HeaderValue;
+use bson::Document;
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadEncoding {
+  #[default]
+  Json,
+  Cbor,
+  MessagePack,
+}
+
+impl PayloadEncoding {
+  pub fn parse(encoding: &str) -> anyhow::Result<Self> {
+    match encoding {
+      "json" => Ok(PayloadEncoding::Json),
+      "cbor" => Ok(PayloadEncoding::Cbor),
+      "msgpack" => Ok(PayloadEncoding::MessagePack),
+      _ => Err(anyhow::anyhow!("unknown encoding {:?}", encoding)),
+    }
+  }
+
+  // the value of `x-msl-webhook-format`
+  pub fn format(&self) -> &'static str {
+    match self {
+      PayloadEncoding::Json => "JSON",
+      PayloadEncoding::Cbor => "CBOR",
+      PayloadEncoding::MessagePack => "MSGPACK",
+    }
+  }
+
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      PayloadEncoding::Json => "application/json",
+      PayloadEncoding::Cbor => "application/cbor",
+      PayloadEncoding::MessagePack => "application/msgpack",
+    }
+  }
+
+  pub fn encode(&self, body: &Value) -> anyhow::Result<Vec<u8>> {
+    match self {
+      PayloadEncoding::Json => Ok(serde_json::to_vec(body)?),
+      PayloadEncoding::Cbor => {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(body, &mut bytes)?;
+        Ok(bytes)
+      }
+      PayloadEncoding::MessagePack => Ok(rmp_serde::to_vec_named(body)?),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadCompression {
+  Gzip,
+  Zstd,
+}
+
+impl PayloadCompression {
+  pub fn parse(compression: &str) -> anyhow::Result<Self> {
+    match compression {
+      "gzip" => Ok(PayloadCompression::Gzip),
+      "zstd" => Ok(PayloadCompression::Zstd),
+      _ => Err(anyhow::anyhow!("unknown compression {:?}", compression)),
+    }
+  }
+
+  pub fn content_encoding(&self) -> &'static str {
+    match self {
+      PayloadCompression::Gzip => "gzip",
+      PayloadCompression::Zstd => "zstd",
+    }
+  }
+
+  pub fn compress(&self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match self {
+      PayloadCompression::Gzip => {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+      }
+      // level 0 picks zstd's default
+      PayloadCompression::Zstd => Ok(zstd::stream::encode_all(bytes, 0)?),
+    }
+  }
+}
+
+// stored as `encoding` and `compression` on the subscription, JSON without compression when unset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PayloadFormat {
+  pub encoding: PayloadEncoding,
+  pub compression: Option<PayloadCompression>,
+}
+
+impl PayloadFormat {
+  pub fn from_subscription(subscription: &Document) -> anyhow::Result<Self> {
+    let encoding = match subscription.get_str("encoding") {
+      Ok(encoding) => PayloadEncoding::parse(encoding)?,
+      Err(_) => PayloadEncoding::Json,
+    };
+    // updates can only set fields, so "none" is how compression gets switched off again
+    let compression = match subscription.get_str("compression") {
+      Ok("none") | Err(_) => None,
+      Ok(compression) => Some(PayloadCompression::parse(compression)?),
+    };
+    Ok(PayloadFormat {
+      encoding,
+      compression,
+    })
+  }
+
+  // the bytes that go on the wire, body signatures are computed over them
+  pub fn encode(&self, body: &Value) -> anyhow::Result<Vec<u8>> {
+    let bytes = self.encoding.encode(body)?;
+    match self.compression {
+      Some(compression) => compression.compress(&bytes),
+      None => Ok(bytes),
+    }
+  }
+
+  pub fn apply_headers(&self, headers: &mut HeaderMap) {
+    headers.insert(
+      "Content-Type",
+      HeaderValue::from_static(self.encoding.content_type()),
+    );
+    headers.insert(
+      "x-msl-webhook-format",
+      HeaderValue::from_static(self.encoding.format()),
+    );
+    match self.compression {
+      Some(compression) => {
+        headers.insert(
+          "Content-Encoding",
+          HeaderValue::from_static(compression.content_encoding()),
+        );
+      }
+      None => {
+        headers.remove("Content-Encoding");
+      }
+    }
+  }
+}
@@ -0,0 +1,228 @@
+use crate::{
+  database::{delete_many, find_all, insert_many, is_duplicate_key_error, update_many},
+  lease::{acquire_lease, get_instance_id, release_lease},
+  shutdown::shutdown_token,
+};
+use bson::{doc, Bson, Document};
+use log::{error, info, warn};
+use mongodb::{
+  options::{FindOptions, InsertManyOptions, UpdateOptions},
+  Database,
+};
+use serde_json::{json, Value};
+use std::{env, time::Duration};
+
+const DEFAULT_REALTIME_BATCH: i64 = 100;
+const DEFAULT_REALTIME_POLL_MS: u64 = 500;
+const REALTIME_BACKOFF_BASE_MS: u64 = 500;
+const MAX_REALTIME_BACKOFF_MS: u64 = 10000;
+const REALTIME_REQUEST_TIMEOUT_MS: u64 = 20000;
+const DEFAULT_REALTIME_MAX_ATTEMPTS: i64 = 10;
+// one replica publishes at a time so notifications keep their order
+const REALTIME_LEASE_ID: &str = "realtime-outbox";
+
+// realtime notifications are optional, nothing is written to the outbox without a url
+pub fn get_realtime_url() -> Option<String> {
+  env::var("REALTIME_URL")
+    .ok()
+    .map(|url| url.trim_end_matches('/').to_string())
+    .filter(|url| !url.is_empty())
+}
+
+pub fn get_realtime_batch_size() -> i64 {
+  env::var("REALTIME_OUTBOX_BATCH")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .filter(|size| *size > 0)
+    .unwrap_or(DEFAULT_REALTIME_BATCH)
+}
+
+pub fn get_realtime_poll_millis() -> u64 {
+  env::var("REALTIME_OUTBOX_POLL_MS")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_REALTIME_POLL_MS)
+}
+
+// entries realtime keeps refusing are parked after this many attempts, so later ones still go out
+pub fn get_realtime_max_attempts() -> i64 {
+  env::var("REALTIME_OUTBOX_MAX_ATTEMPTS")
+    .ok()
+    .and_then(|value| value.parse::<i64>().ok())
+    .filter(|attempts| *attempts > 0)
+    .unwrap_or(DEFAULT_REALTIME_MAX_ATTEMPTS)
+}
+
+pub fn realtime_backoff_millis(failures: u32) -> u64 {
+  REALTIME_BACKOFF_BASE_MS
+    .saturating_mul(2_u64.saturating_pow(failures.saturating_sub(1)))
+    .min(MAX_REALTIME_BACKOFF_MS)
+}
+
+// written in the ingestion transaction, so a notification exists exactly when its blocks were queued
+pub fn outbox_doc(contract_id: &str, transactions: &[Value]) -> anyhow::Result<Option<Document>> {
+  if transactions.is_empty() {
+    return Ok(None);
+  }
+  Ok(Some(doc! {
+    "contract_id": contract_id,
+    "transactions": bson::to_bson(transactions)?,
+    "attempts": 0,
+    "created_at": bson::DateTime::now(),
+  }))
+}
+
+// the transactions of all entries in outbox order, in the body realtime always received
+pub fn notification_body(entries: &[Document]) -> Value {
+  let transactions: Vec<Value> = entries
+    .iter()
+    .filter_map(|entry| entry.get_array("transactions").ok())
+    .flatten()
+    .map(|transaction| transaction.clone().into_relaxed_extjson())
+    .collect();
+  json!({ "transactions": transactions })
+}
+
+// moves entries out of the outbox into `realtimedeadletters` once they used up their attempts,
+// they keep their `_id` so parking them twice after a crash is harmless
+pub async fn park_exhausted_entries(db: &Database, ids: &[Bson]) -> anyhow::Result<usize> {
+  let exhausted = find_all(
+    db.collection("realtimeoutbox"),
+    doc! { "_id": { "$in": ids }, "attempts": { "$gte": get_realtime_max_attempts() } },
+    FindOptions::default(),
+  )
+  .await?;
+  if exhausted.is_empty() {
+    return Ok(0);
+  }
+  let parked: Vec<Document> = exhausted
+    .iter()
+    .map(|entry| {
+      let mut entry = entry.clone();
+      entry.insert("parked_at", bson::DateTime::now());
+      entry
+    })
+    .collect();
+  let mut insert_many_options = InsertManyOptions::default();
+  insert_many_options.ordered = Some(false);
+  if let Err(err) = insert_many(
+    db.collection("realtimedeadletters"),
+    &parked,
+    insert_many_options,
+  )
+  .await
+  {
+    if !is_duplicate_key_error(&err) {
+      return Err(err.into());
+    }
+  }
+  let parked_ids: Vec<Bson> = exhausted
+    .iter()
+    .filter_map(|entry| entry.get("_id").cloned())
+    .collect();
+  delete_many(
+    db.collection("realtimeoutbox"),
+    doc! { "_id": { "$in": parked_ids } },
+  )
+  .await?;
+  Ok(exhausted.len())
+}
+
+// sends the oldest entries in one request and removes them once realtime took them
+pub async fn publish_outbox_batch(
+  db: &Database,
+  client: &reqwest::Client,
+  realtime_url: &str,
+) -> anyhow::Result<usize> {
+  let mut find_option = FindOptions::default();
+  find_option.sort = Some(doc! { "_id": 1 });
+  find_option.limit = Some(get_realtime_batch_size());
+  let entries = find_all(db.collection("realtimeoutbox"), doc! {}, find_option).await?;
+  if entries.is_empty() {
+    return Ok(0);
+  }
+  let ids: Vec<Bson> = entries
+    .iter()
+    .filter_map(|entry| entry.get("_id").cloned())
+    .collect();
+
+  let result = client
+    .post(format!("{}/notify-transactions", realtime_url))
+    .json(&notification_body(&entries))
+    .send()
+    .await
+    .and_then(|response| response.error_for_status());
+  if let Err(err) = result {
+    update_many(
+      db.collection("realtimeoutbox"),
+      doc! { "_id": { "$in": &ids } },
+      doc! {
+        "$inc": { "attempts": 1 },
+        "$set": { "last_error": err.to_string() },
+      },
+      UpdateOptions::default(),
+    )
+    .await?;
+    let parked = park_exhausted_entries(db, &ids).await?;
+    if parked > 0 {
+      error!(
+        "Moved {} realtime notifications to realtimedeadletters: {}",
+        parked, err
+      );
+    }
+    return Err(err.into());
+  }
+
+  delete_many(
+    db.collection("realtimeoutbox"),
+    doc! { "_id": { "$in": ids } },
+  )
+  .await?;
+  Ok(entries.len())
+}
+
+pub async fn run_realtime_publisher(db: Database, realtime_url: String) {
+  info!("Publishing realtime notifications to {}", realtime_url);
+  let client = reqwest::Client::builder()
+    .timeout(Duration::from_millis(REALTIME_REQUEST_TIMEOUT_MS))
+    .build()
+    .unwrap_or_default();
+  let poll_millis = get_realtime_poll_millis();
+  let mut failures = 0;
+  while !shutdown_token().is_cancelled() {
+    let delay = match acquire_lease(&db, REALTIME_LEASE_ID, get_instance_id()).await {
+      Ok(true) => match publish_outbox_batch(&db, &client, &realtime_url).await {
+        Ok(0) => {
+          failures = 0;
+          poll_millis
+        }
+        // keep draining while there is a backlog
+        Ok(_) => {
+          failures = 0;
+          0
+        }
+        Err(err) => {
+          failures += 1;
+          warn!("Realtime notification failed {} times: {:?}", failures, err);
+          realtime_backoff_millis(failures)
+        }
+      },
+      Ok(false) => poll_millis,
+      Err(err) => {
+        warn!("Could not take the realtime outbox lease: {:?}", err);
+        poll_millis
+      }
+    };
+    if delay > 0 {
+      tokio::select! {
+        _ = tokio::time::sleep(Duration::from_millis(delay)) => {}
+        _ = shutdown_token().cancelled() => {}
+      }
+    }
+  }
+  // another replica takes over publishing without waiting for the lease to expire
+  if let Err(err) = release_lease(&db, REALTIME_LEASE_ID, get_instance_id()).await {
+    warn!("Could not release the realtime outbox lease: {:?}", err);
+  }
+  info!("Realtime publisher stopped");
+}
@@ -0,0 +1,103 @@
+use crate::{database::update_many, lease::release_leases};
+use bson::{doc, oid::ObjectId};
+use log::info;
+use mongodb::{options::UpdateOptions, Database};
+use std::{
+  collections::HashMap,
+  env,
+  sync::{Mutex, OnceLock},
+  time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_SHUTDOWN_DEADLINE_SECS: u64 = 20;
+
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+static ROUND_LOCKS: OnceLock<Mutex<HashMap<String, ObjectId>>> = OnceLock::new();
+
+// cancelled once on SIGTERM or SIGINT, ingestion and the dispatcher loop wind down from it
+pub fn shutdown_token() -> &'static CancellationToken {
+  SHUTDOWN.get_or_init(CancellationToken::new)
+}
+
+pub fn is_shutting_down() -> bool {
+  shutdown_token().is_cancelled()
+}
+
+// how long running deliveries get to finish, it has to stay below the pod's termination grace period
+pub fn get_shutdown_deadline() -> Duration {
+  Duration::from_secs(
+    env::var("DISPATCHER_SHUTDOWN_DEADLINE_SECS")
+      .ok()
+      .and_then(|value| value.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_SHUTDOWN_DEADLINE_SECS),
+  )
+}
+
+// the lock id of every delivery round running in this process, keyed by subscription
+pub fn round_locks() -> &'static Mutex<HashMap<String, ObjectId>> {
+  ROUND_LOCKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ShutdownReport {
+  pub finished: usize,
+  pub abandoned: Vec<String>,
+  pub unlocked_blocks: u64,
+  pub released_leases: u64,
+}
+
+impl ShutdownReport {
+  pub fn summary(&self) -> String {
+    format!(
+      "{} deliveries finished, {} abandoned {:?}, {} blocks unlocked, {} leases released",
+      self.finished,
+      self.abandoned.len(),
+      self.abandoned,
+      self.unlocked_blocks,
+      self.released_leases
+    )
+  }
+}
+
+// blocks of abandoned rounds become deliverable again right away instead of when their lock expires
+pub async fn unlock_abandoned_blocks(db: &Database, abandoned: &[String]) -> anyhow::Result<u64> {
+  let lock_ids: Vec<ObjectId> = {
+    let mut locks = round_locks().lock().unwrap();
+    abandoned
+      .iter()
+      .filter_map(|sub_id| locks.remove(sub_id))
+      .collect()
+  };
+  if lock_ids.is_empty() {
+    return Ok(0);
+  }
+  let result = update_many(
+    db.collection("transactionblocks"),
+    doc! { "locked_by": { "$in": lock_ids } },
+    doc! { "$set": { "locked_until": bson::DateTime::now() }, "$unset": { "locked_by": "" } },
+    UpdateOptions::default(),
+  )
+  .await?;
+  Ok(result.modified_count)
+}
+
+// hands the subscriptions of this replica to the others without waiting for the leases to expire
+pub async fn release_dispatcher_state(
+  db: &Database,
+  owner: &str,
+  held: &[String],
+  finished: usize,
+  abandoned: Vec<String>,
+) -> anyhow::Result<ShutdownReport> {
+  let unlocked_blocks = unlock_abandoned_blocks(db, &abandoned).await?;
+  let released_leases = release_leases(db, held, owner).await?;
+  let report = ShutdownReport {
+    finished,
+    abandoned,
+    unlocked_blocks,
+    released_leases,
+  };
+  info!("Dispatcher shut down: {}", report.summary());
+  Ok(report)
+}
@@ -0,0 +1,282 @@
+use crate::dispatcher::BatchMetadata;
+use bson::Document;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+  Key(String),
+  // negative indexes count from the end
+  Index(i64),
+  Wildcard,
+}
+
+// the JSONPath subset subscriptions can use: `$`, `.key`, `['key']`, `[0]`, `[-1]` and `[*]`,
+// jq style paths starting with `.` work too
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+  pub segments: Vec<PathSegment>,
+}
+
+fn is_key_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+impl JsonPath {
+  pub fn parse(expression: &str) -> Result<Self, String> {
+    let invalid = |reason: &str| Err(format!("invalid path {:?}: {}", expression, reason));
+    // jq's identity
+    if expression == "." {
+      return Ok(JsonPath { segments: vec![] });
+    }
+    let rest = expression.strip_prefix('$').unwrap_or(expression);
+    if rest.len() == expression.len() && !rest.starts_with('.') {
+      return invalid("it has to start with '$' or '.'");
+    }
+
+    let mut segments = vec![];
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+      match c {
+        '.' => {
+          if chars.peek() == Some(&'*') {
+            chars.next();
+            segments.push(PathSegment::Wildcard);
+            continue;
+          }
+          let mut key = String::new();
+          while let Some(&c) = chars.peek().filter(|c| is_key_char(**c)) {
+            key.push(c);
+            chars.next();
+          }
+          if key.is_empty() {
+            return invalid("'.' has to be followed by a key");
+          }
+          segments.push(PathSegment::Key(key));
+        }
+        '[' => {
+          let segment = match chars.peek().copied() {
+            // quoted keys run to the closing quote, they may contain ']'
+            Some(quote) if quote == '"' || quote == '\'' => {
+              chars.next();
+              let mut key = String::new();
+              loop {
+                match chars.next() {
+                  Some(c) if c == quote => break,
+                  Some(c) => key.push(c),
+                  None => return invalid("unclosed quote"),
+                }
+              }
+              if chars.next() != Some(']') {
+                return invalid("a quoted key has to be followed by ']'");
+              }
+              PathSegment::Key(key)
+            }
+            _ => {
+              let mut inner = String::new();
+              loop {
+                match chars.next() {
+                  Some(']') => break,
+                  Some(c) => inner.push(c),
+                  None => return invalid("unclosed '['"),
+                }
+              }
+              if inner == "*" {
+                PathSegment::Wildcard
+              } else if let Ok(index) = inner.parse::<i64>() {
+                PathSegment::Index(index)
+              } else {
+                return invalid("brackets take an index, '*' or a quoted key");
+              }
+            }
+          };
+          segments.push(segment);
+        }
+        _ => return invalid("expected '.' or '['"),
+      }
+    }
+    Ok(JsonPath { segments })
+  }
+
+  pub fn has_wildcard(&self) -> bool {
+    self.segments.contains(&PathSegment::Wildcard)
+  }
+
+  pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+    let mut selected = vec![value];
+    for segment in &self.segments {
+      selected = selected
+        .into_iter()
+        .flat_map(|value| -> Vec<&Value> {
+          match (segment, value) {
+            (PathSegment::Key(key), Value::Object(object)) => object.get(key).into_iter().collect(),
+            (PathSegment::Index(index), Value::Array(array)) => {
+              let index = if *index < 0 {
+                array.len() as i64 + index
+              } else {
+                *index
+              };
+              usize::try_from(index)
+                .ok()
+                .and_then(|index| array.get(index))
+                .into_iter()
+                .collect()
+            }
+            (PathSegment::Wildcard, Value::Array(array)) => array.iter().collect(),
+            (PathSegment::Wildcard, Value::Object(object)) => object.values().collect(),
+            _ => vec![],
+          }
+        })
+        .collect();
+    }
+    selected
+  }
+
+  // a single value, null when nothing matches, or every match when the path has a wildcard
+  pub fn evaluate(&self, value: &Value) -> Value {
+    let selected = self.select(value);
+    if self.has_wildcard() {
+      Value::Array(selected.into_iter().cloned().collect())
+    } else {
+      selected
+        .first()
+        .map_or(Value::Null, |value| (*value).clone())
+    }
+  }
+}
+
+fn default_envelope() -> bool {
+  true
+}
+
+// stored as `transform` on the subscription, validated by the subscriptions service
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TransformConfig {
+  #[serde(default)]
+  pub flatten: bool,
+  #[serde(default)]
+  pub fields: Option<BTreeMap<String, String>>,
+  #[serde(default = "default_envelope")]
+  pub envelope: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+  // one item per transaction instead of one per block
+  pub flatten: bool,
+  // output key and the path its value is read from, the whole item is kept without them
+  pub fields: Option<Vec<(String, JsonPath)>>,
+  // false sends the bare list of items instead of the metadata envelope
+  pub envelope: bool,
+}
+
+impl Transform {
+  // None for subscriptions without a transform, an error for one that does not parse
+  pub fn from_subscription(subscription: &Document) -> anyhow::Result<Option<Self>> {
+    let config = match subscription.get_document("transform") {
+      Ok(config) => config,
+      Err(_) => return Ok(None),
+    };
+    let config: TransformConfig = bson::from_document(config.clone())?;
+    let fields = match config.fields {
+      Some(fields) => Some(
+        fields
+          .into_iter()
+          .map(|(name, path)| Ok((name, JsonPath::parse(&path).map_err(anyhow::Error::msg)?)))
+          .collect::<anyhow::Result<Vec<_>>>()?,
+      ),
+      None => None,
+    };
+    Ok(Some(Transform {
+      flatten: config.flatten,
+      fields,
+      envelope: config.envelope,
+    }))
+  }
+
+  pub fn apply(&self, blocks: Vec<Value>) -> Vec<Value> {
+    let items = if self.flatten {
+      blocks.into_iter().flat_map(flatten_block).collect()
+    } else {
+      blocks
+    };
+    match &self.fields {
+      Some(fields) => items
+        .iter()
+        .map(|item| {
+          let mut projected: Map<String, Value> = fields
+            .iter()
+            .map(|(name, path)| (name.clone(), path.evaluate(item)))
+            .collect();
+          // a retraction stays recognizable whatever the projection keeps
+          if let Some(removed) = item.get("removed") {
+            projected.insert("removed".to_string(), removed.clone());
+          }
+          Value::Object(projected)
+        })
+        .collect(),
+      None => items,
+    }
+  }
+}
+
+// every transaction with the block it came from, a retraction becomes one item without a transaction
+pub fn flatten_block(mut block: Value) -> Vec<Value> {
+  let transactions = match block.get_mut("transactions").map(Value::take) {
+    Some(Value::Array(transactions)) => transactions,
+    _ => vec![],
+  };
+  if let Some(block) = block.as_object_mut() {
+    block.remove("transactions");
+  }
+  if transactions.is_empty() {
+    let mut item = block;
+    item["transaction"] = Value::Null;
+    return vec![item];
+  }
+  transactions
+    .into_iter()
+    .map(|transaction| {
+      let mut item = block.clone();
+      item["transaction"] = transaction;
+      item
+    })
+    .collect()
+}
+
+pub fn delivery_body(
+  blocks: Vec<Value>,
+  transform: Option<&Transform>,
+  contract_id: &str,
+  batch: &BatchMetadata,
+) -> Value {
+  let (payload, envelope) = match transform {
+    Some(transform) => (transform.apply(blocks), transform.envelope),
+    None => (blocks, true),
+  };
+  // without the envelope every item carries what consumers order and dedupe batches by
+  if !envelope {
+    let metadata = json!({ "sequence": batch.sequence, "replay": batch.replay });
+    return Value::Array(
+      payload
+        .into_iter()
+        .map(|mut item| {
+          if let Some(item) = item.as_object_mut() {
+            item.insert("metadata".to_string(), metadata.clone());
+          }
+          item
+        })
+        .collect(),
+    );
+  }
+  json!({
+    "metadata": {
+      "contract_id": contract_id,
+      "sequence": batch.sequence,
+      "replay": batch.replay
+    },
+    "payload_count": payload.len(),
+    "payload": payload
+  })
+}
@@ -0,0 +1,111 @@
+use actix_web::{http::StatusCode, test as actix_test, web, App};
+use web3cache::admin_api::*;
+use web3cache::consumer_api::metrics;
+use web3cache::helper_functions::AppState;
+use web3cache::metrics::{dispatcher_metrics, PendingBlocks};
+
+#[actix_web::test]
+async fn test_reject_admin_request() {
+  std::env::set_var("ADMIN_SECRET", "test-admin-secret");
+  let req = actix_test::TestRequest::default()
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_http_request();
+  assert!(reject_admin_request(&req).is_none());
+
+  let req = actix_test::TestRequest::default()
+    .insert_header(("x-msl-admin-key", "guess"))
+    .to_http_request();
+  assert_eq!(
+    reject_admin_request(&req).unwrap().status(),
+    StatusCode::UNAUTHORIZED
+  );
+  let req = actix_test::TestRequest::default().to_http_request();
+  assert!(reject_admin_request(&req).is_some());
+}
+
+#[actix_web::test]
+async fn test_admin_requests_without_dispatcher() {
+  std::env::set_var("ADMIN_SECRET", "test-admin-secret");
+  // the client connects lazily, no request below reaches the database
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let app = actix_test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db }))
+      .service(
+        web::scope("/admin")
+          .service(admin_queue)
+          .service(admin_pause_subscription)
+          .service(admin_retry_subscription)
+          .service(admin_purge_blocks),
+      ),
+  )
+  .await;
+
+  let req = actix_test::TestRequest::get()
+    .uri("/admin/queue")
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/admin/subscriptions/not-an-id/pause")
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = actix_test::TestRequest::delete()
+    .uri("/admin/subscriptions/not-an-id/blocks")
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  // queue and retry need the dispatcher loop, which this process does not run
+  let req = actix_test::TestRequest::get()
+    .uri("/admin/queue")
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/admin/subscriptions/65a1b2c3d4e5f60718293a4b/retry")
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn test_metrics_requires_admin_key() {
+  std::env::set_var("ADMIN_SECRET", "test-admin-secret");
+  // scrapes serve the refresher's snapshot, nothing below reaches the database
+  dispatcher_metrics()
+    .lock()
+    .unwrap()
+    .set_pending_blocks(vec![PendingBlocks {
+      subid: "sub-a".to_string(),
+      count: 4,
+      oldest_millis: 0,
+    }]);
+  let app = actix_test::init_service(App::new().service(metrics)).await;
+
+  let req = actix_test::TestRequest::get().uri("/metrics").to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+  let req = actix_test::TestRequest::get()
+    .uri("/metrics")
+    .insert_header(("x-msl-admin-key", "test-admin-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::OK);
+  let body = actix_test::read_body(response).await;
+  assert!(String::from_utf8_lossy(&body)
+    .lines()
+    .any(|line| line == "web3cache_pending_blocks{subid=\"sub-a\"} 4"));
+}
@@ -0,0 +1,50 @@
+use bson::doc;
+use serial_test::serial;
+use web3cache::chain_head::*;
+use web3cache::database::{connect_to_mongodb_test, delete_many};
+
+#[test]
+fn test_confirmed_height() {
+  let fast = doc! { "url": "https://example.com" };
+  let safe = doc! { "confirmations": 12_i32 };
+  assert_eq!(confirmed_height(&fast, Some(100)), None);
+  assert_eq!(confirmed_height(&safe, Some(100)), Some(88));
+  assert_eq!(confirmed_height(&safe, None), Some(-1));
+  assert_eq!(get_confirmations(&doc! { "confirmations": -3_i64 }), 0);
+}
+
+#[test]
+fn test_deliverable_blocks_filter() {
+  assert_eq!(
+    deliverable_blocks_filter("sub", None),
+    doc! { "subid": "sub" }
+  );
+  assert_eq!(
+    deliverable_blocks_filter("sub", Some(88)),
+    doc! {
+      "subid": "sub",
+      "$or": [{ "block_number": { "$lte": 88_i64 } }, { "removed": true }],
+    }
+  );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_record_chain_head() {
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-chain-head";
+  assert_eq!(get_chain_head(&db, contract_id).await.unwrap(), None);
+
+  record_chain_head(&db, contract_id, 100).await.unwrap();
+  assert_eq!(get_chain_head(&db, contract_id).await.unwrap(), Some(100));
+  // a late report of an older head is ignored
+  record_chain_head(&db, contract_id, 90).await.unwrap();
+  assert_eq!(get_chain_head(&db, contract_id).await.unwrap(), Some(100));
+
+  delete_many(
+    db.collection("chainheads"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+}
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+use web3cache::circuit_breaker::*;
+
+#[test]
+fn test_breaker_key() {
+  assert_eq!(
+    breaker_key("https://hooks.example.com/webhook").unwrap(),
+    "hooks.example.com:443"
+  );
+  assert_eq!(
+    breaker_key("http://127.0.0.1:8080/a?b=c").unwrap(),
+    "127.0.0.1:8080"
+  );
+  assert_eq!(breaker_key("not a url"), None);
+}
+
+#[test]
+fn test_circuit_opens_after_consecutive_failures() {
+  let mut breakers = CircuitBreakers::new(3, Duration::from_secs(30));
+  let now = Instant::now();
+  let host = "hooks.example.com:443";
+  assert_eq!(breakers.check(host, now), None);
+
+  breakers.record_failure(host, now);
+  breakers.record_failure(host, now);
+  assert_eq!(breakers.check(host, now), None);
+  // a success resets the count
+  breakers.record_success(host);
+  breakers.record_failure(host, now);
+  breakers.record_failure(host, now);
+  assert!(!breakers.is_open(host, now));
+
+  breakers.record_failure(host, now);
+  assert!(breakers.is_open(host, now));
+  assert_eq!(
+    breakers.check(host, now + Duration::from_secs(10)),
+    Some(Duration::from_secs(20))
+  );
+  // other hosts are not affected
+  assert_eq!(breakers.check("other.example.com:443", now), None);
+}
+
+#[test]
+fn test_half_open_circuit_lets_one_probe_through() {
+  let mut breakers = CircuitBreakers::new(1, Duration::from_secs(30));
+  let now = Instant::now();
+  let host = "hooks.example.com:443";
+  breakers.record_failure(host, now);
+
+  let probe_at = now + Duration::from_secs(31);
+  assert_eq!(breakers.check(host, probe_at), None);
+  // concurrent deliveries keep failing fast while the probe is out
+  assert!(breakers.check(host, probe_at).is_some());
+
+  // a failed probe opens the circuit again
+  breakers.record_failure(host, probe_at);
+  assert!(breakers.is_open(host, probe_at + Duration::from_secs(29)));
+
+  let probe_at = probe_at + Duration::from_secs(31);
+  assert_eq!(breakers.check(host, probe_at), None);
+  breakers.record_success(host);
+  assert_eq!(breakers.check(host, probe_at), None);
+  assert!(!breakers.is_open(host, probe_at));
+}
+
+#[test]
+fn test_probe_without_result_is_released() {
+  let mut breakers = CircuitBreakers::new(1, Duration::from_secs(30));
+  let now = Instant::now();
+  let host = "hooks.example.com:443";
+  breakers.record_failure(host, now);
+
+  let probe_at = now + Duration::from_secs(31);
+  let probe = breakers.admit(host, probe_at).unwrap().unwrap();
+  // the probe is still out once its window is over, nobody else goes through
+  assert!(breakers
+    .admit(host, probe_at + Duration::from_secs(31))
+    .is_err());
+
+  // the round ended without recording a result, e.g. it lost its lease
+  let released_at = probe_at + Duration::from_secs(40);
+  breakers.release_probe(host, probe, released_at);
+  let next_probe = breakers.admit(host, released_at).unwrap().unwrap();
+  assert_ne!(next_probe, probe);
+  assert!(breakers.admit(host, released_at).is_err());
+
+  // a stale release does not free the probe that replaced it
+  breakers.release_probe(host, probe, released_at);
+  assert!(breakers.admit(host, released_at).is_err());
+  breakers.record_success(host);
+  assert_eq!(breakers.admit(host, released_at), Ok(None));
+}
+
+#[test]
+fn test_dropped_probe_guard_releases_the_probe() {
+  let host = "probe-guard.example.com:443";
+  let opened_at = Instant::now();
+  let probe_at = opened_at + get_breaker_open_duration();
+  let probe = {
+    let mut breakers = circuit_breakers().lock().unwrap();
+    for _ in 0..get_breaker_failure_threshold() {
+      breakers.record_failure(host, opened_at);
+    }
+    breakers.admit(host, probe_at).unwrap().unwrap()
+  };
+  assert!(circuit_breakers()
+    .lock()
+    .unwrap()
+    .check(host, probe_at)
+    .is_some());
+
+  drop(ProbeGuard::new(host.to_string(), probe));
+  assert!(matches!(
+    circuit_breakers()
+      .lock()
+      .unwrap()
+      .admit(host, Instant::now()),
+    Ok(Some(_))
+  ));
+}
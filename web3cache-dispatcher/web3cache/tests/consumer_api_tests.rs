@@ -1,7 +1,13 @@
+#![allow(clippy::get_first)]
+
+use actix_web::{http::StatusCode, test as actix_test, web, App};
 use bson::{doc, oid::ObjectId, Document};
 use serde_json::json;
 use std::str::FromStr;
+use validator::Validate;
 use web3cache::consumer_api::*;
+use web3cache::dispatcher::sign_body;
+use web3cache::helper_functions::AppState;
 
 fn create_test_transaction_blocks() -> Vec<TransactionBlock> {
   vec![
@@ -12,6 +18,7 @@ fn create_test_transaction_blocks() -> Vec<TransactionBlock> {
         json!({"transaction_id": "tx1", "amount": 100}),
         json!({"transaction_id": "tx2", "amount": 200}),
       ],
+      block_hash: None,
     },
     TransactionBlock {
       block_number: 2,
@@ -20,6 +27,7 @@ fn create_test_transaction_blocks() -> Vec<TransactionBlock> {
         json!({"transaction_id": "tx3", "amount": 300}),
         json!({"transaction_id": "tx4", "amount": 400}),
       ],
+      block_hash: None,
     },
   ]
 }
@@ -42,6 +50,7 @@ async fn test_filter_contract_info_empty_payload() -> anyhow::Result<()> {
   let payload = Transactions {
     contract_id: "test_contract".to_string(),
     reset_nonce: 1,
+    head_block: None,
     data: vec![],
   };
 
@@ -66,18 +75,20 @@ async fn test_filter_contract_info_new_transactions() -> anyhow::Result<()> {
   let payload = Transactions {
     contract_id: "test_contract".to_string(),
     reset_nonce: 1,
-    data: vec![
-      json!({
+    head_block: None,
+    data: serde_json::from_value(json!([
+      {
           "event_name": "Event1",
           "block_number": 5,
           "transactions": [],
-      }),
-      json!({
+      },
+      {
           "event_name": "Event2",
           "block_number": 3,
           "transactions": [],
-      }),
-    ],
+      },
+    ]))
+    .unwrap(),
   };
 
   let result = filter_contract_info(&payload, None)?;
@@ -172,3 +183,378 @@ fn test_generate_dbdata_from_records_send_transactions() {
 
   assert_eq!(send_transactions, &expected_send_transactions);
 }
+
+#[test]
+fn test_generate_dbdata_from_records_honors_topics() {
+  let transaction_blocks = create_test_transaction_blocks();
+  let subscriptions = vec![
+    doc! {
+        "_id": ObjectId::from_str("605c72ef1531a577f67dbe10").unwrap(),
+        "topics": ["Event1"],
+    },
+    doc! {
+        "_id": ObjectId::from_str("605c72ef1531a577f67dbe11").unwrap(),
+        "topics": [],
+    },
+  ];
+
+  let result = generate_dbdata_from_records(&transaction_blocks, &subscriptions).unwrap();
+  let insert_docs = &result.0;
+
+  // Event1 goes to both subscriptions, Event2 only to the one without topics
+  assert_eq!(insert_docs.len(), 3);
+  let event2_subids: Vec<&str> = insert_docs
+    .iter()
+    .filter(|doc| doc.get_str("event_name").unwrap() == "Event2")
+    .map(|doc| doc.get_str("subid").unwrap())
+    .collect();
+  assert_eq!(event2_subids, vec!["605c72ef1531a577f67dbe11"]);
+
+  // realtime notifications are not affected by subscription topics
+  assert_eq!(result.1.len(), 4);
+}
+
+#[test]
+fn test_generate_dbdata_from_records_keeps_block_hash() {
+  let mut transaction_blocks = create_test_transaction_blocks();
+  transaction_blocks[0].block_hash = Some("0xaaa".to_string());
+  let subscriptions = create_test_subscriptions();
+
+  let (insert_docs, _) = generate_dbdata_from_records(&transaction_blocks, &subscriptions).unwrap();
+
+  assert_eq!(insert_docs[0].get_str("block_hash").unwrap(), "0xaaa");
+  assert!(insert_docs[2].get("block_hash").is_none());
+}
+
+#[test]
+fn test_rollback_events_info() {
+  let events_info = doc! {
+    "_id": ObjectId::new(),
+    "contract_id": "test_contract",
+    "reset_nonce": 1_i64,
+    "Transfer": 105_i64,
+    "Approval": 99_i32,
+  };
+
+  assert_eq!(
+    rollback_events_info(&events_info, 100),
+    doc! { "Transfer": 100_i64 }
+  );
+  assert!(rollback_events_info(&events_info, 110).is_empty());
+}
+
+fn reorg_notice() -> ReorgNotice {
+  serde_json::from_value(json!({
+    "contract_id": "test_contract",
+    "fork_block": 100,
+    "removed_blocks": [
+      { "block_number": 101, "block_hash": "0x101" },
+      { "block_number": 102, "block_hash": "0x102" },
+    ],
+  }))
+  .unwrap()
+}
+
+#[test]
+fn test_build_checkpoint() {
+  let events_info = doc! {
+    "_id": ObjectId::from_str("605c72ef1531a577f67dbe10").unwrap(),
+    "contract_id": "test_contract",
+    "reset_nonce": 3,
+    "Transfer": 100_i64,
+    "Approval": 90,
+  };
+  assert_eq!(
+    event_checkpoints(&events_info)
+      .into_iter()
+      .collect::<Vec<_>>(),
+    vec![("Approval".to_string(), 90), ("Transfer".to_string(), 100)]
+  );
+
+  let checkpoint = build_checkpoint("test_contract", Some(&events_info), Some(95));
+  assert_eq!(checkpoint.reset_nonce, Some(3));
+  assert_eq!(
+    checkpoint.events["Approval"],
+    EventCheckpoint {
+      block_number: 90,
+      lag: Some(5)
+    }
+  );
+  assert_eq!(checkpoint.events["Transfer"].lag, Some(0));
+  assert_eq!(
+    serde_json::to_value(&checkpoint).unwrap(),
+    json!({
+      "contract_id": "test_contract",
+      "reset_nonce": 3,
+      "head_block": 95,
+      "events": {
+        "Approval": { "block_number": 90, "lag": 5 },
+        "Transfer": { "block_number": 100, "lag": 0 },
+      },
+    })
+  );
+
+  let checkpoint = build_checkpoint("new_contract", None, None);
+  assert_eq!(checkpoint.reset_nonce, None);
+  assert!(checkpoint.events.is_empty());
+}
+
+#[test]
+fn test_generate_retraction_docs() {
+  let seen_blocks = vec![
+    doc! { "block_number": 100_i64, "event_name": "Transfer" },
+    doc! { "block_number": 101_i64, "event_name": "Transfer" },
+    doc! { "block_number": 102_i64, "event_name": "Approval", "block_hash": "0xstored" },
+    doc! { "block_number": 103_i64, "event_name": "Transfer" },
+  ];
+
+  let retractions = generate_retraction_docs("sub", &seen_blocks, &reorg_notice());
+
+  // the block at the fork stays, the hash stored with a block wins over the notice
+  assert_eq!(retractions.len(), 3);
+  for retraction in &retractions {
+    assert_eq!(retraction.get_str("subid").unwrap(), "sub");
+    assert!(retraction.get_bool("removed").unwrap());
+    assert!(retraction.get_array("transactions").unwrap().is_empty());
+  }
+  assert_eq!(retractions[0].get_i64("block_number").unwrap(), 101);
+  assert_eq!(retractions[0].get_str("block_hash").unwrap(), "0x101");
+  assert_eq!(retractions[1].get_str("block_hash").unwrap(), "0xstored");
+  assert!(retractions[2].get("block_hash").is_none());
+}
+
+#[test]
+fn test_generate_retraction_docs_skips_retracted_blocks() {
+  let seen_blocks = vec![
+    doc! { "block_number": 101_i64, "event_name": "Transfer", "block_hash": "0x101" },
+    doc! { "block_number": 101_i64, "event_name": "Transfer", "block_hash": "0x101", "removed": true },
+    doc! { "block_number": 102_i64, "event_name": "Transfer", "block_hash": "0x102" },
+    doc! { "block_number": 102_i64, "event_name": "Transfer", "removed": true },
+    doc! { "block_number": 102_i64, "event_name": "Transfer", "block_hash": "0xnew" },
+  ];
+
+  let retractions = generate_retraction_docs("sub", &seen_blocks, &reorg_notice());
+
+  // only the replacement of 102 delivered after the first reorg is still with the subscriber
+  assert_eq!(retractions.len(), 1);
+  assert_eq!(retractions[0].get_i64("block_number").unwrap(), 102);
+  assert_eq!(retractions[0].get_str("block_hash").unwrap(), "0xnew");
+}
+
+#[test]
+fn test_drop_queued_duplicates() {
+  let insert_docs = vec![
+    doc! { "subid": "a", "block_number": 1_i64, "event_name": "Event1" },
+    doc! { "subid": "b", "block_number": 1_i64, "event_name": "Event1" },
+    doc! { "subid": "a", "block_number": 2_i64, "event_name": "Event2" },
+    doc! { "subid": "a", "block_number": 2_i64, "event_name": "Event2" },
+  ];
+  let queued = vec![doc! { "subid": "b", "block_number": 1_i64, "event_name": "Event1" }];
+
+  let (fresh, duplicates) = drop_queued_duplicates(insert_docs, &queued);
+
+  assert_eq!(fresh.len(), 2);
+  assert_eq!(fresh[0].get_str("subid").unwrap(), "a");
+  assert_eq!(fresh[1].get_i64("block_number").unwrap(), 2);
+  // one copy was queued before, the other one is repeated within the batch
+  assert_eq!(duplicates.len(), 2);
+}
+
+#[test]
+fn test_build_ingest_report() {
+  let payload = Transactions {
+    contract_id: "test_contract".to_string(),
+    reset_nonce: 1,
+    data: create_test_transaction_blocks(),
+    head_block: None,
+  };
+  let events_info = doc! { "contract_id": "test_contract", "reset_nonce": 1_i64, "Event1": 5_i64 };
+  let (records, _) = filter_contract_info(&payload, Some(events_info.clone())).unwrap();
+  let (insert_docs, _) =
+    generate_dbdata_from_records(&records, &create_test_subscriptions()).unwrap();
+  let queued = vec![insert_docs[0].clone()];
+  let (fresh, duplicates) = drop_queued_duplicates(insert_docs, &queued);
+
+  let report = build_ingest_report(&payload, &records, Some(&events_info), &fresh, &duplicates);
+
+  assert_eq!(
+    report,
+    IngestReport {
+      accepted: vec![AcceptedBlock {
+        block_number: 2,
+        event_name: "Event2".to_string(),
+        queued: 1,
+        duplicates: 1,
+      }],
+      skipped: vec![SkippedBlock {
+        block_number: 1,
+        event_name: "Event1".to_string(),
+        checkpoint: 5,
+      }],
+    }
+  );
+  assert_eq!(
+    serde_json::to_value(&report).unwrap()["skipped"][0]["checkpoint"],
+    json!(5)
+  );
+}
+
+#[test]
+fn test_validate_transactions() {
+  let valid: Transactions = serde_json::from_value(json!({
+    "contract_id": "test_contract",
+    "reset_nonce": 1,
+    "data": [{ "block_number": 5, "event_name": "Transfer", "transactions": [{ "tx": 1 }] }],
+  }))
+  .unwrap();
+  assert!(valid.validate().is_ok());
+
+  for invalid in [
+    json!({ "contract_id": "", "reset_nonce": 1, "data": [] }),
+    json!({ "contract_id": "c", "reset_nonce": 1, "data": [], "head_block": -1 }),
+    json!({
+      "contract_id": "c",
+      "reset_nonce": 1,
+      "data": [{ "block_number": 5, "event_name": "Transfer", "transactions": [] }],
+    }),
+    json!({
+      "contract_id": "c",
+      "reset_nonce": 1,
+      "data": [{ "block_number": 5, "event_name": "Transfer", "transactions": ["tx"] }],
+    }),
+    json!({
+      "contract_id": "c",
+      "reset_nonce": 1,
+      "data": [{ "block_number": -5, "event_name": "Transfer", "transactions": [{}] }],
+    }),
+  ] {
+    let payload: Transactions = serde_json::from_value(invalid.clone()).unwrap();
+    assert!(payload.validate().is_err(), "{}", invalid);
+  }
+}
+
+#[test]
+fn test_is_ingest_authorized() {
+  let body = br#"{"contract_id":"c"}"#;
+  let now = 1_700_000_000;
+  let signature = format!("t={},v1={}", now, sign_body("secret", now, body).unwrap());
+
+  let req = actix_test::TestRequest::default()
+    .insert_header(("x-msl-ingest-key", "secret"))
+    .to_http_request();
+  assert!(is_ingest_authorized(&req, body, "secret", now));
+  let req = actix_test::TestRequest::default()
+    .insert_header(("x-msl-ingest-key", "guess"))
+    .to_http_request();
+  assert!(!is_ingest_authorized(&req, body, "secret", now));
+
+  let req = actix_test::TestRequest::default()
+    .insert_header(("x-msl-ingest-signature", signature.as_str()))
+    .to_http_request();
+  assert!(is_ingest_authorized(&req, body, "secret", now));
+  assert!(!is_ingest_authorized(&req, b"{}", "secret", now));
+  assert!(!is_ingest_authorized(&req, body, "secret", now + 3600));
+
+  let req = actix_test::TestRequest::default().to_http_request();
+  assert!(!is_ingest_authorized(&req, body, "secret", now));
+}
+
+#[actix_web::test]
+async fn test_push_transactions_rejects_bad_requests() {
+  std::env::set_var("INGEST_SECRET", "test-ingest-secret");
+  // the client connects lazily, every request below is refused before it touches the database
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let app = actix_test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db }))
+      .service(push_transactions)
+      .service(push_reorg)
+      .service(get_checkpoint)
+      .service(start_checkpoint_epoch),
+  )
+  .await;
+
+  let req = actix_test::TestRequest::get()
+    .uri("/checkpoint/c")
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/checkpoint/c/epoch")
+    .insert_header(("x-msl-ingest-key", "wrong-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+  // the path contract id is unescaped like the pushed one, an invalid escape is refused
+  let req = actix_test::TestRequest::get()
+    .uri("/checkpoint/%22%5Cq%22")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/checkpoint/%22%5Cq%22/epoch")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .set_json(json!({ "contract_id": "c", "reset_nonce": 1, "data": [] }))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+  let body: serde_json::Value = actix_test::read_body_json(response).await;
+  assert_eq!(body["message"], "invalid ingest credentials");
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .set_payload("{\"contract_id\":")
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+  let body: serde_json::Value = actix_test::read_body_json(response).await;
+  assert!(body["message"]
+    .as_str()
+    .unwrap()
+    .starts_with("invalid payload"));
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .set_json(json!({
+      "contract_id": "c",
+      "reset_nonce": 1,
+      "data": [{ "block_number": 5, "event_name": "Transfer", "transactions": [] }],
+    }))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+  let body: serde_json::Value = actix_test::read_body_json(response).await;
+  assert!(body.to_string().contains("transactions"));
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-reorg")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .set_json(json!({ "contract_id": "c", "fork_block": -1 }))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let oversized = vec![b' '; get_ingest_max_body_bytes() + 1];
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .set_payload(oversized)
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
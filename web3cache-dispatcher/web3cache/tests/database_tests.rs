@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use bson::Document;
 use mongodb::bson::doc;
 use mongodb::error::Error as MongoErr;
@@ -1,3 +1,9 @@
+#![allow(
+  clippy::single_component_path_imports,
+  clippy::clone_on_copy,
+  clippy::unnecessary_to_owned
+)]
+
 use actix_http::header::HeaderValue;
 use anyhow::Result;
 use bson::doc;
@@ -7,6 +13,7 @@ use httpmock::Method::POST;
 use httpmock::MockServer;
 use jwt::{Header, Token};
 use log::info;
+use mongodb::change_stream::event::ChangeStreamEvent;
 use mongodb::options::{FindOneOptions, FindOptions, InsertManyOptions, InsertOneOptions};
 use mongodb::Database;
 use serde_json::json;
@@ -14,9 +21,18 @@ use serial_test::serial;
 use std::collections::{BTreeMap, HashMap, LinkedList};
 use std::str::FromStr;
 use tokio;
+use web3cache::admin_api::{
+  purge_pending_blocks, reset_retry_state, set_contract_paused, set_subscription_paused,
+};
+use web3cache::circuit_breaker::{breaker_key, circuit_breakers, get_breaker_failure_threshold};
+use web3cache::consumer_api::{
+  generate_dbdata_from_records, handle_reorg, ingest_transactions, load_checkpoint, start_epoch,
+  ReorgNotice, TransactionBlock, Transactions,
+};
 use web3cache::database::{connect_to_mongodb_test, delete_many};
 use web3cache::database::{find_all, find_one, insert_many};
 use web3cache::dispatcher::*;
+use web3cache::lease::{acquire_lease, release_lease};
 
 async fn cleanup_subscriptions(db: &Database, subscription_ids: &[ObjectId]) {
   let collection = db.collection::<Document>("subscriptions");
@@ -34,6 +50,86 @@ async fn cleanup_transactions(db: &Database, transaction_ids: &[ObjectId]) {
   }
 }
 
+async fn insert_topics_subscription(
+  db: &Database,
+  contract_id: &str,
+  url: String,
+  topics: Vec<&str>,
+) -> String {
+  db.collection("subscriptions")
+    .insert_one(
+      doc! {
+          "url": url,
+          "topics": topics,
+          "contract_id": contract_id,
+          "apikey" : "test_dispatcher_topics",
+          "isActive":true
+      },
+      InsertOneOptions::default(),
+    )
+    .await
+    .unwrap()
+    .inserted_id
+    .as_object_id()
+    .unwrap()
+    .to_string()
+}
+
+fn topic_transaction_blocks() -> Vec<TransactionBlock> {
+  vec![
+    TransactionBlock {
+      block_number: 100,
+      event_name: "Transfer".to_string(),
+      transactions: vec![
+        json!({"transaction_id": "tx1", "block_number": 100, "event_name": "Transfer"}),
+      ],
+      block_hash: None,
+    },
+    TransactionBlock {
+      block_number: 101,
+      event_name: "Approval".to_string(),
+      transactions: vec![
+        json!({"transaction_id": "tx2", "block_number": 101, "event_name": "Approval"}),
+      ],
+      block_hash: None,
+    },
+  ]
+}
+
+async fn queue_topic_blocks(db: &Database, sub_id: &str) -> usize {
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::parse_str(sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  let (insert_docs, _) =
+    generate_dbdata_from_records(&topic_transaction_blocks(), &vec![subscription]).unwrap();
+  if !insert_docs.is_empty() {
+    insert_many(
+      db.collection("transactionblocks"),
+      &insert_docs,
+      InsertManyOptions::default(),
+    )
+    .await
+    .unwrap();
+  }
+  insert_docs.len()
+}
+
+async fn update_topics(db: &Database, sub_id: &str, update: Document) {
+  db.collection::<Document>("subscriptions")
+    .update_one(
+      doc! { "_id": ObjectId::parse_str(sub_id).unwrap() },
+      update,
+      None,
+    )
+    .await
+    .unwrap();
+}
+
 #[test]
 fn test_merge_queues_with_duplicates_isolated() -> anyhow::Result<()> {
   let mut queue_map = HashMap::new();
@@ -89,7 +185,11 @@ fn test_create_webhook_headers() -> Result<()> {
       "apikey": "supersecretapikey"
   };
 
-  let (headers, contract_id) = create_webhook_headers(sub_id.clone(), &subscription)?;
+  let batch = BatchMetadata {
+    sequence: 7,
+    replay: false,
+  };
+  let (headers, contract_id) = create_webhook_headers(sub_id.clone(), &subscription, &batch)?;
 
   // Check contract_id
   assert_eq!(contract_id, "contract123");
@@ -117,8 +217,9 @@ fn test_create_webhook_headers() -> Result<()> {
   );
   assert_eq!(
     headers.get("x-msl-webhook-nonce").unwrap(),
-    HeaderValue::from_str("-1").unwrap()
+    HeaderValue::from_str("7").unwrap()
   );
+  assert!(headers.get("x-msl-webhook-replay").is_none());
 
   // Check x-msl-webhook-timestamp
   let timestamp = headers.get("x-msl-webhook-timestamp").unwrap().to_str()?;
@@ -160,6 +261,75 @@ fn test_generate_dates() {
     );
 }
 
+#[test]
+fn test_truncate_response() {
+  assert_eq!(truncate_response("ok", 10), "ok");
+  assert_eq!(truncate_response("0123456789abc", 10), "0123456789...");
+  // multi-byte characters are never split
+  assert_eq!(truncate_response("ééé", 2), "éé...");
+}
+
+#[test]
+fn test_retry_delay() {
+  let policy = DeliveryPolicy::from_subscription(&doc! {});
+  assert_eq!(policy.retry_delay(0), 100);
+  assert_eq!(policy.retry_delay(1), 200);
+  assert_eq!(policy.retry_delay(5), 3200);
+  assert_eq!(policy.retry_delay(7), 10000);
+  assert_eq!(policy.retry_delay(1000), 10000);
+
+  let policy = DeliveryPolicy::from_subscription(
+    &doc! { "delivery_policy": { "backoff_base_ms": 1000, "backoff_cap_ms": 60000_i64 } },
+  );
+  assert_eq!(policy.retry_delay(0), 1000);
+  assert_eq!(policy.retry_delay(3), 8000);
+  assert_eq!(policy.retry_delay(1000), 60000);
+  assert_eq!(policy.next_retry_delay(100), 1000);
+  assert_eq!(policy.next_retry_delay(40000), 60000);
+}
+
+#[test]
+#[serial]
+fn test_should_dead_letter() {
+  std::env::set_var("DISPATCHER_MAX_ATTEMPTS", "3");
+  std::env::set_var("DISPATCHER_MAX_AGE_SECS", "60");
+
+  assert!(!should_dead_letter(2, 0, 1000));
+  assert!(should_dead_letter(3, 0, 1000));
+  assert!(should_dead_letter(1, 0, 60000));
+
+  std::env::remove_var("DISPATCHER_MAX_ATTEMPTS");
+  std::env::remove_var("DISPATCHER_MAX_AGE_SECS");
+}
+
+#[test]
+fn test_sub_id_from_change_events() {
+  let block_event: ChangeStreamEvent<Document> = bson::from_document(doc! {
+    "_id": { "_data": "token" },
+    "operationType": "insert",
+    "fullDocument": { "subid": "6488bbf0fc6a7a2a2a3cbf6c" },
+  })
+  .unwrap();
+  assert_eq!(
+    sub_id_from_block_change(&block_event),
+    Some("6488bbf0fc6a7a2a2a3cbf6c".to_string())
+  );
+  assert_eq!(sub_id_from_subscription_change(&block_event), None);
+
+  let sub_id = ObjectId::new();
+  let subscription_event: ChangeStreamEvent<Document> = bson::from_document(doc! {
+    "_id": { "_data": "token" },
+    "operationType": "delete",
+    "documentKey": { "_id": sub_id },
+  })
+  .unwrap();
+  assert_eq!(
+    sub_id_from_subscription_change(&subscription_event),
+    Some(sub_id.to_string())
+  );
+  assert_eq!(sub_id_from_block_change(&subscription_event), None);
+}
+
 #[tokio::test]
 #[serial]
 async fn test_fill_queue_various_subscriptions() {
@@ -176,6 +346,9 @@ async fn test_fill_queue_various_subscriptions() {
       Ok(delete_response) => info!("Deleted {} documents", delete_response.deleted_count),
       Err(e) => info!("Error occurred during deletion: {}", e),
     }
+    delete_many(db.collection("transactionblocks"), doc! {})
+      .await
+      .unwrap();
   }
 
   // Test with 0 subscriptions
@@ -195,7 +368,20 @@ async fn test_fill_queue_various_subscriptions() {
     .unwrap();
   let subscription_id_1 = insert_result_1.inserted_id.as_object_id().unwrap().clone();
 
-  // Test with 1 subscription
+  // Idle subscriptions are not queued
+  let result = dispatcher_data.fill_queue(&db).await;
+  assert!(result.is_ok());
+  assert!(dispatcher_data.queue_list.is_empty());
+
+  // Test with 1 subscription with pending blocks
+  let transactions_collection = db.collection::<Document>("transactionblocks");
+  let block_1 = transactions_collection
+    .insert_one(
+      doc! { "subid": subscription_id_1.to_string(), "block_number": 1, "event_name": "Transfer" },
+      None,
+    )
+    .await
+    .unwrap();
   let result = dispatcher_data.fill_queue(&db).await;
   assert!(result.is_ok());
   assert_eq!(dispatcher_data.queue_list.len(), 1);
@@ -206,6 +392,13 @@ async fn test_fill_queue_various_subscriptions() {
     .await
     .unwrap();
   let subscription_id_2 = insert_result_2.inserted_id.as_object_id().unwrap().clone();
+  let block_2 = transactions_collection
+    .insert_one(
+      doc! { "subid": subscription_id_2.to_string(), "block_number": 1, "event_name": "Transfer" },
+      None,
+    )
+    .await
+    .unwrap();
   dispatcher_data.queue_list.clear();
   dispatcher_data.queue_map.clear();
 
@@ -214,7 +407,20 @@ async fn test_fill_queue_various_subscriptions() {
   assert!(result.is_ok());
   assert_eq!(dispatcher_data.queue_list.len(), 2);
 
+  // Filling again does not duplicate queued subscriptions
+  let result = dispatcher_data.fill_queue(&db).await;
+  assert!(result.is_ok());
+  assert_eq!(dispatcher_data.queue_list.len(), 2);
+
   // Clean up the test data
+  cleanup_transactions(
+    &db,
+    &[
+      block_1.inserted_id.as_object_id().unwrap(),
+      block_2.inserted_id.as_object_id().unwrap(),
+    ],
+  )
+  .await;
   cleanup_subscriptions(&db, &[subscription_id_1, subscription_id_2]).await;
 }
 
@@ -543,12 +749,17 @@ async fn test_dispatch_transactions() {
     queue_map: &mut HashMap::new(),
   };
   let result = dispatcher_data
-    .dispatch_transactions(transactions, &subscription, sub_id.clone())
+    .dispatch_transactions(
+      transactions,
+      &subscription,
+      sub_id.clone(),
+      &BatchMetadata::default(),
+    )
     .await;
 
   // Check if the result is Ok and returns true
   assert!(result.is_ok());
-  assert!(result.unwrap());
+  assert!(result.unwrap().is_good);
 
   // Ensure that the mock was called
   webhook_mock.assert();
@@ -612,3 +823,2065 @@ async fn test_any_transaction_pending_with_transactions() {
   // Clean up the test data
   cleanup_transactions(&db, &[transaction_id]).await;
 }
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_set_topics() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-set-topics",
+    mock_server.url("/webhook"),
+    vec!["Transfer", "Approval"],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  // topics changed while both blocks are still queued
+  update_topics(&db, &sub_id, doc! { "$set": { "topics": ["Approval"] } }).await;
+
+  let approval_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Approval");
+    then.status(200);
+  });
+  let transfer_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Transfer");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+
+  approval_mock.assert();
+  transfer_mock.assert_hits(0);
+  let remaining_transactions = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining_transactions.is_empty());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_add_topics() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-add-topics",
+    mock_server.url("/webhook"),
+    vec!["Transfer"],
+  )
+  .await;
+
+  // only the Transfer block is fanned out before Approval is added
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 1);
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$addToSet": { "topics": { "$each": ["Approval"] } } },
+  )
+  .await;
+  let transaction_blocks: Vec<TransactionBlock> = topic_transaction_blocks()
+    .into_iter()
+    .map(|mut block| {
+      block.block_number += 10;
+      block
+    })
+    .collect();
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::parse_str(&sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  let (insert_docs, _) =
+    generate_dbdata_from_records(&transaction_blocks, &vec![subscription]).unwrap();
+  assert_eq!(insert_docs.len(), 2);
+  insert_many(
+    db.collection("transactionblocks"),
+    &insert_docs,
+    InsertManyOptions::default(),
+  )
+  .await
+  .unwrap();
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Approval");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+
+  webhook_mock.assert();
+  let remaining_transactions = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining_transactions.is_empty());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_remove_topics() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-remove-topics",
+    mock_server.url("/webhook"),
+    vec!["Transfer", "Approval"],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$pull": { "topics": { "$in": ["Approval"] } } },
+  )
+  .await;
+
+  let transfer_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Transfer");
+    then.status(200);
+  });
+  let approval_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Approval");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+
+  transfer_mock.assert();
+  approval_mock.assert_hits(0);
+  let remaining_transactions = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining_transactions.is_empty());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_dead_letters_failed_batch() {
+  std::env::set_var("DISPATCHER_MAX_ATTEMPTS", "2");
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-dead-letters",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(500);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+
+  // first failure is persisted on the blocks and pushes the lock forward
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(pending.len(), 2);
+  for block in &pending {
+    assert_eq!(block.get_i32("attempts").unwrap(), 1);
+    assert!(block.get_str("last_error").unwrap().contains("500"));
+  }
+
+  // unlock the batch to simulate the backoff expiring
+  db.collection::<Document>("transactionblocks")
+    .update_many(
+      doc! { "subid": &sub_id },
+      doc! { "$set": { "locked_until": bson::DateTime::from_millis(0) } },
+      None,
+    )
+    .await
+    .unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+
+  webhook_mock.assert_hits(2);
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(pending.is_empty());
+
+  let dead_letters = find_all(
+    db.collection("deadletters"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(dead_letters.len(), 1);
+  assert_eq!(dead_letters[0].get_i64("attempts").unwrap(), 2);
+  assert_eq!(dead_letters[0].get_i64("block_from").unwrap(), 100);
+  assert_eq!(dead_letters[0].get_i64("block_to").unwrap(), 101);
+  assert_eq!(dead_letters[0].get_array("blocks").unwrap().len(), 2);
+
+  delete_many(db.collection("deadletters"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  std::env::remove_var("DISPATCHER_MAX_ATTEMPTS");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_records_deliveries() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-deliveries",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(202).body("accepted");
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert();
+
+  let deliveries = find_all(
+    db.collection("deliveries"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(deliveries.len(), 1);
+  let delivery = &deliveries[0];
+  assert!(delivery.get_bool("success").unwrap());
+  assert_eq!(delivery.get_i32("status").unwrap(), 202);
+  assert_eq!(delivery.get_i64("block_from").unwrap(), 100);
+  assert_eq!(delivery.get_i64("block_to").unwrap(), 101);
+  assert_eq!(delivery.get_i64("attempt").unwrap(), 1);
+  assert_eq!(delivery.get_str("response_excerpt").unwrap(), "accepted");
+  assert!(delivery.get_i64("latency_ms").is_ok());
+
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_skips_leased_subscription() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-leased",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  assert!(acquire_lease(&db, &sub_id, "another-dispatcher")
+    .await
+    .unwrap());
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(0);
+  assert!(dispatcher_data.queue_list.is_empty());
+
+  // once the lease is given up the whole batch is delivered
+  release_lease(&db, &sub_id, "another-dispatcher")
+    .await
+    .unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(1);
+  let remaining_transactions = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining_transactions.is_empty());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[test]
+fn test_delay_times_in() {
+  let before = bson::DateTime::now().timestamp_millis();
+  let delay_times = delay_times_in(500);
+  let wait_until = delay_times.wait_until.timestamp_millis();
+  assert_eq!(delay_times.increase_timeout, 500);
+  assert!(wait_until >= before + 500);
+  assert!(wait_until <= bson::DateTime::now().timestamp_millis() + 500);
+}
+
+#[test]
+fn test_queue_snapshot() {
+  let mut queue_map = HashMap::new();
+  queue_map.insert(
+    "sub-later".to_string(),
+    DelayTimes {
+      increase_timeout: 800,
+      wait_until: bson::DateTime::from_millis(2_000),
+    },
+  );
+  queue_map.insert(
+    "sub-sooner".to_string(),
+    DelayTimes {
+      increase_timeout: 100,
+      wait_until: bson::DateTime::from_millis(1_000),
+    },
+  );
+  let in_flight = ["sub-b".to_string(), "sub-a".to_string()];
+
+  let snapshot = queue_snapshot(&queue_map, in_flight.iter());
+  assert_eq!(
+    snapshot.scheduled,
+    vec![
+      ScheduledSubscription {
+        sub_id: "sub-sooner".to_string(),
+        increase_timeout: 100,
+        wait_until: "1970-01-01T00:00:01Z".to_string(),
+      },
+      ScheduledSubscription {
+        sub_id: "sub-later".to_string(),
+        increase_timeout: 800,
+        wait_until: "1970-01-01T00:00:02Z".to_string(),
+      },
+    ]
+  );
+  assert_eq!(snapshot.in_flight, vec!["sub-a", "sub-b"]);
+}
+
+#[tokio::test]
+async fn test_try_send_transactions_skips_paused_subscription() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-dispatcher-paused";
+  let sub_id =
+    insert_topics_subscription(&db, contract_id, mock_server.url("/webhook"), vec![]).await;
+  let object_id = ObjectId::from_str(&sub_id).unwrap();
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(200);
+  });
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+
+  // paused on its own and through the contract, the blocks stay queued either way
+  assert!(set_subscription_paused(&db, &object_id, true)
+    .await
+    .unwrap());
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  assert!(set_subscription_paused(&db, &object_id, false)
+    .await
+    .unwrap());
+  set_contract_paused(&db, contract_id, true).await.unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(0);
+  assert!(dispatcher_data.queue_list.is_empty());
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": object_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert!(subscription.get_bool("isActive").unwrap());
+  assert!(!subscription.contains_key("delivery_paused"));
+
+  set_contract_paused(&db, contract_id, false).await.unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(1);
+
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  assert_eq!(purge_pending_blocks(&db, &sub_id).await.unwrap(), 2);
+  assert!(!set_subscription_paused(&db, &ObjectId::new(), true)
+    .await
+    .unwrap());
+
+  cleanup_subscriptions(&db, &[object_id]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_forced_retry_delivers_a_backed_off_subscription() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let url = mock_server.url("/webhook");
+  let sub_id =
+    insert_topics_subscription(&db, "test-dispatcher-forced-retry", url.clone(), vec![]).await;
+  let object_id = ObjectId::from_str(&sub_id).unwrap();
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let mut failing_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(500);
+  });
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  failing_mock.assert();
+  failing_mock.delete();
+
+  // backed off, the circuit of the host is open and another replica holds the lease
+  let host = breaker_key(&url).unwrap();
+  for _ in 0..get_breaker_failure_threshold() {
+    circuit_breakers()
+      .lock()
+      .unwrap()
+      .record_failure(&host, std::time::Instant::now());
+  }
+  db.collection::<Document>("dispatcherleases")
+    .update_one(
+      doc! { "_id": &sub_id },
+      doc! { "$set": { "owner": "another-replica" } },
+      None,
+    )
+    .await
+    .unwrap();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(200);
+  });
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(0);
+
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": object_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(reset_retry_state(&db, &subscription).await.unwrap(), 2);
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert();
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(pending.is_empty());
+
+  cleanup_subscriptions(&db, &[object_id]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_deliver_subscription() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-worker",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+
+  // nothing queued, nothing to reschedule
+  let (worker_sub_id, next) = deliver_subscription(db.clone(), sub_id.clone(), 100).await;
+  assert_eq!(worker_sub_id, sub_id);
+  assert!(next.is_none());
+
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let mut failing_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(500);
+  });
+  let (_, next) = deliver_subscription(db.clone(), sub_id.clone(), 100).await;
+  failing_mock.assert();
+  assert_eq!(next.unwrap().increase_timeout, 200);
+  failing_mock.delete();
+
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(200);
+  });
+  db.collection::<Document>("transactionblocks")
+    .update_many(
+      doc! { "subid": &sub_id },
+      doc! { "$set": { "locked_until": bson::DateTime::now() } },
+      None,
+    )
+    .await
+    .unwrap();
+  let (_, next) = deliver_subscription(db.clone(), sub_id.clone(), 200).await;
+  webhook_mock.assert();
+  assert!(next.is_none());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[test]
+fn test_delivery_policy_from_subscription() {
+  let policy = DeliveryPolicy::from_subscription(&doc! { "url": "https://example.com" });
+  assert_eq!(policy.max_blocks, 50);
+  assert_eq!(policy.max_payload_bytes, None);
+  assert_eq!(policy.timeout, get_request_timeout());
+  assert_eq!(policy.backoff_base_ms, 100);
+  assert_eq!(policy.backoff_cap_ms, 10000);
+
+  let policy = DeliveryPolicy::from_subscription(&doc! {
+    "delivery_policy": {
+      "max_blocks": 5,
+      "max_payload_bytes": 2048_i64,
+      "timeout_ms": 3000,
+      "backoff_base_ms": 0,
+      "backoff_cap_ms": 50,
+    }
+  });
+  assert_eq!(policy.max_blocks, 5);
+  assert_eq!(policy.max_payload_bytes, Some(2048));
+  assert_eq!(policy.timeout, std::time::Duration::from_millis(3000));
+  // invalid values fall back to the defaults, and the cap never goes under the base
+  assert_eq!(policy.backoff_base_ms, 100);
+  assert_eq!(policy.backoff_cap_ms, 100);
+}
+
+#[test]
+fn test_delivery_policy_lock_ttl_outlives_the_request() {
+  let policy = DeliveryPolicy::from_subscription(&doc! { "url": "https://example.com" });
+  assert_eq!(policy.lock_ttl_millis(), 30000);
+
+  let policy = DeliveryPolicy::from_subscription(&doc! {
+    "delivery_policy": { "timeout_ms": 60000 }
+  });
+  assert!(policy.lock_ttl_millis() > 60000);
+}
+
+#[test]
+fn test_blocks_within_payload_bytes() {
+  let blocks: Vec<serde_json::Value> = (0..10)
+    .map(|block_number| json!({ "block_number": block_number, "data": "x".repeat(100) }))
+    .collect();
+  let block_bytes = serde_json::to_vec(&blocks[0]).unwrap().len() + 1;
+
+  assert_eq!(blocks_within_payload_bytes(&blocks, usize::MAX), 10);
+  assert_eq!(
+    blocks_within_payload_bytes(&blocks, 128 + 3 * block_bytes),
+    3
+  );
+  // a single oversized block is still sent on its own
+  assert_eq!(blocks_within_payload_bytes(&blocks, 10), 1);
+  assert_eq!(blocks_within_payload_bytes(&[], 10), 0);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_splits_by_payload_bytes() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-payload-bytes",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$set": { "delivery_policy": { "max_payload_bytes": 200 } } },
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let transfer_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Transfer");
+    then.status(200);
+  });
+  let approval_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").body_contains("Approval");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  transfer_mock.assert();
+  approval_mock.assert_hits(0);
+  assert!(dispatcher_data.queue_map.contains_key(&sub_id));
+
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  transfer_mock.assert_hits(1);
+  approval_mock.assert();
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[test]
+fn test_sign_and_verify_body_signature() {
+  let body = br#"{"metadata":{"contract_id":"c"},"payload_count":0,"payload":[]}"#;
+  let signature = sign_body("secret", 1700000000, body).unwrap();
+  assert_eq!(signature.len(), 64);
+  let header = format!("t={},v1={}", 1700000000, signature);
+
+  assert!(verify_body_signature(
+    &header, body, "secret", 300, 1700000100
+  ));
+  // outside of the tolerance window
+  assert!(!verify_body_signature(
+    &header, body, "secret", 300, 1700000301
+  ));
+  // another body or secret
+  assert!(!verify_body_signature(
+    &header, b"{}", "secret", 300, 1700000100
+  ));
+  assert!(!verify_body_signature(
+    &header, body, "other", 300, 1700000100
+  ));
+  // the timestamp is part of the signed content
+  let moved_header = format!("t={},v1={}", 1700000050, signature);
+  assert!(!verify_body_signature(
+    &moved_header,
+    body,
+    "secret",
+    300,
+    1700000100
+  ));
+  assert!(!verify_body_signature(
+    "v1=abc", body, "secret", 300, 1700000100
+  ));
+}
+
+#[test]
+fn test_add_body_signature() {
+  let subscription = doc! {
+    "apikey": "apikey",
+    "secret": "test_body_signature",
+    "contract_id": "c",
+    "signature_type": BODY_SIGNATURE_TYPE,
+  };
+  assert!(uses_body_signature(&subscription));
+  assert!(!uses_body_signature(&doc! { "apikey": "k" }));
+
+  let (mut headers, _) =
+    create_webhook_headers("sub".to_string(), &subscription, &BatchMetadata::default()).unwrap();
+  add_body_signature(&mut headers, &subscription, b"body").unwrap();
+  assert_eq!(
+    headers.get("x-msl-webhook-signature-type").unwrap(),
+    BODY_SIGNATURE_TYPE
+  );
+  // the jwt header keeps being sent
+  assert!(headers.get("x-msl-webhook-jwt-signature").is_some());
+  let signature = headers
+    .get("x-msl-webhook-signature")
+    .unwrap()
+    .to_str()
+    .unwrap();
+  let now = chrono::Utc::now().timestamp();
+  assert!(verify_body_signature(
+    signature,
+    b"body",
+    "test_body_signature",
+    get_signature_tolerance_secs(),
+    now
+  ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_dispatch_transactions_signs_body() {
+  let mock_server = MockServer::start();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .header("x-msl-webhook-signature-type", BODY_SIGNATURE_TYPE)
+      .header_exists("x-msl-webhook-signature")
+      .header_exists("x-msl-webhook-jwt-signature");
+    then.status(200);
+  });
+  let subscription = doc! {
+    "_id": ObjectId::new(),
+    "url": mock_server.url("/webhook"),
+    "apikey": "test_body_signature",
+    "contract_id": "test-body-signature",
+    "signature_type": BODY_SIGNATURE_TYPE,
+  };
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({ "block_number": 1 })],
+      &subscription,
+      subscription.get_object_id("_id").unwrap().to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  assert!(outcome.is_good);
+  webhook_mock.assert();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_dispatch_transactions_applies_transform() {
+  let mock_server = MockServer::start();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook").json_body(json!([
+      { "amount": "5", "block": 1, "metadata": { "sequence": 0, "replay": false } },
+      { "amount": "7", "block": 1, "metadata": { "sequence": 0, "replay": false } },
+    ]));
+    then.status(200);
+  });
+  let subscription = doc! {
+    "_id": ObjectId::new(),
+    "url": mock_server.url("/webhook"),
+    "apikey": "test_transform",
+    "contract_id": "test-transform",
+    "transform": {
+      "flatten": true,
+      "fields": { "amount": "$.transaction.value", "block": "$.block_number" },
+      "envelope": false,
+    },
+  };
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({
+        "block_number": 1,
+        "event_name": "Transfer",
+        "transactions": [{ "value": "5" }, { "value": "7" }],
+      })],
+      &subscription,
+      subscription.get_object_id("_id").unwrap().to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  assert!(outcome.is_good);
+  webhook_mock.assert();
+
+  // a transform that does not parse is a misconfiguration, nothing goes out
+  let mut subscription = subscription;
+  subscription.insert("transform", doc! { "fields": { "amount": "value" } });
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({ "block_number": 1 })],
+      &subscription,
+      subscription.get_object_id("_id").unwrap().to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  assert!(!outcome.is_good);
+  assert_eq!(outcome.failure, Some(FailureKind::Misconfigured));
+  assert!(!outcome.reached_endpoint());
+  webhook_mock.assert_hits(1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_dispatch_transactions_encodes_and_compresses() {
+  let mock_server = MockServer::start();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .header("content-type", "application/cbor")
+      .header("content-encoding", "zstd")
+      .header("x-msl-webhook-format", "CBOR")
+      .matches(|req| {
+        let body = req.body.clone().unwrap_or_default();
+        let signature = req
+          .headers
+          .iter()
+          .flatten()
+          .find(|(name, _)| name == "x-msl-webhook-signature")
+          .map(|(_, value)| value.clone())
+          .unwrap_or_default();
+        // signed as sent, before the subscriber decompresses anything
+        let signed = verify_body_signature(
+          &signature,
+          &body,
+          "test_encoding",
+          300,
+          chrono::Utc::now().timestamp(),
+        );
+        let decoded: Option<serde_json::Value> = zstd::stream::decode_all(body.as_slice())
+          .ok()
+          .and_then(|bytes| ciborium::de::from_reader(bytes.as_slice()).ok());
+        signed && decoded.is_some_and(|decoded| decoded["payload"][0]["block_number"] == 1)
+      });
+    then.status(200);
+  });
+  let subscription = doc! {
+    "_id": ObjectId::new(),
+    "url": mock_server.url("/webhook"),
+    "apikey": "test_encoding",
+    "contract_id": "test-encoding",
+    "signature_type": "hmac.sha256.v1",
+    "encoding": "cbor",
+    "compression": "zstd",
+  };
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({ "block_number": 1, "transactions": [{ "value": "5" }] })],
+      &subscription,
+      subscription.get_object_id("_id").unwrap().to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  assert!(outcome.is_good);
+  webhook_mock.assert();
+
+  let mut subscription = subscription;
+  subscription.insert("compression", "brotli");
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({ "block_number": 1 })],
+      &subscription,
+      subscription.get_object_id("_id").unwrap().to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(outcome.failure, Some(FailureKind::Misconfigured));
+  webhook_mock.assert_hits(1);
+}
+
+#[test]
+fn test_get_signing_secrets() {
+  // legacy subscriptions without a secret
+  assert_eq!(
+    get_signing_secrets(&doc! { "apikey": "apikey" }),
+    vec!["apikey".to_string()]
+  );
+  assert_eq!(
+    get_signing_secrets(&doc! { "apikey": "apikey", "secret": "new" }),
+    vec!["new".to_string()]
+  );
+
+  let in_grace = bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + 60000);
+  let expired = bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() - 60000);
+  assert_eq!(
+    get_signing_secrets(&doc! {
+      "apikey": "apikey",
+      "secret": "new",
+      "previous_secret": "old",
+      "previous_secret_expires_at": in_grace,
+    }),
+    vec!["new".to_string(), "old".to_string()]
+  );
+  assert_eq!(
+    get_signing_secrets(&doc! {
+      "apikey": "apikey",
+      "secret": "new",
+      "previous_secret": "old",
+      "previous_secret_expires_at": expired,
+    }),
+    vec!["new".to_string()]
+  );
+}
+
+#[test]
+fn test_rotated_secret_double_signs() {
+  let subscription = doc! {
+    "apikey": "apikey",
+    "contract_id": "c",
+    "secret": "new",
+    "previous_secret": "old",
+    "previous_secret_expires_at": bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + 60000),
+  };
+  let (mut headers, _) =
+    create_webhook_headers("sub".to_string(), &subscription, &BatchMetadata::default()).unwrap();
+  assert!(headers.get("x-msl-webhook-jwt-signature").is_some());
+  assert!(headers
+    .get("x-msl-webhook-jwt-signature-previous")
+    .is_some());
+
+  add_body_signature(&mut headers, &subscription, b"body").unwrap();
+  let signature = headers
+    .get("x-msl-webhook-signature")
+    .unwrap()
+    .to_str()
+    .unwrap()
+    .to_string();
+  let now = chrono::Utc::now().timestamp();
+  assert!(verify_body_signature(&signature, b"body", "new", 300, now));
+  assert!(verify_body_signature(&signature, b"body", "old", 300, now));
+  assert!(!verify_body_signature(
+    &signature, b"body", "apikey", 300, now
+  ));
+}
+
+#[test]
+fn test_leading_batch_len() {
+  let fresh = doc! { "block_number": 1 };
+  let sequenced = doc! { "block_number": 2, "sequence": 4_i64 };
+  let replayed = doc! { "block_number": 3, "replay": true };
+  assert_eq!(leading_batch_len(&[]), 0);
+  assert_eq!(leading_batch_len(&[fresh.clone(), fresh.clone()]), 2);
+  assert_eq!(
+    leading_batch_len(&[sequenced.clone(), sequenced.clone(), fresh.clone()]),
+    2
+  );
+  assert_eq!(leading_batch_len(&[fresh.clone(), sequenced.clone()]), 1);
+  assert_eq!(leading_batch_len(&[replayed.clone(), fresh.clone()]), 1);
+
+  assert_eq!(BatchMetadata::from_block(&fresh), None);
+  assert_eq!(
+    BatchMetadata::from_block(&doc! { "sequence": 4_i64, "replay": true }),
+    Some(BatchMetadata {
+      sequence: 4,
+      replay: true
+    })
+  );
+}
+
+#[test]
+fn test_block_payload() {
+  let block = doc! {
+    "block_number": 7_i64,
+    "event_name": "Transfer",
+    "transactions": [{ "_id": ObjectId::new(), "transaction_id": "tx1" }],
+  };
+  assert_eq!(
+    block_payload(&block),
+    json!({
+      "transactions": [{ "transaction_id": "tx1" }],
+      "block_number": 7,
+      "event_name": "Transfer",
+    })
+  );
+
+  let retraction = doc! {
+    "block_number": 7_i64,
+    "event_name": "Transfer",
+    "block_hash": "0x7",
+    "removed": true,
+    "transactions": [],
+  };
+  assert_eq!(
+    block_payload(&retraction),
+    json!({
+      "transactions": [],
+      "block_number": 7,
+      "event_name": "Transfer",
+      "block_hash": "0x7",
+      "removed": true,
+    })
+  );
+  assert_eq!(
+    delivered_block_summary(&retraction),
+    doc! { "block_number": 7_i64, "event_name": "Transfer", "block_hash": "0x7", "removed": true }
+  );
+}
+
+#[test]
+fn test_replay_batch_headers() {
+  let subscription = doc! { "apikey": "apikey", "contract_id": "c" };
+  let batch = BatchMetadata {
+    sequence: 12,
+    replay: true,
+  };
+  let (headers, _) = create_webhook_headers("sub".to_string(), &subscription, &batch).unwrap();
+  assert_eq!(headers.get("x-msl-webhook-nonce").unwrap(), "12");
+  assert_eq!(headers.get("x-msl-webhook-replay").unwrap(), "true");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_assigns_sequences() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-sequences",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$set": { "delivery_policy": { "max_blocks": 1 } } },
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let mut failing_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(500);
+  });
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  failing_mock.delete();
+
+  let first_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .header("x-msl-webhook-nonce", "1")
+      .body_contains(r#""sequence":1"#)
+      .body_contains(r#""replay":false"#);
+    then.status(200);
+  });
+  let second_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .header("x-msl-webhook-nonce", "2");
+    then.status(200);
+  });
+  // the failed batch is retried with its original sequence, the next one gets a new sequence
+  for _ in 0..2 {
+    db.collection::<Document>("transactionblocks")
+      .update_many(
+        doc! { "subid": &sub_id },
+        doc! { "$set": { "locked_until": bson::DateTime::from_millis(0) } },
+        None,
+      )
+      .await
+      .unwrap();
+    dispatcher_data
+      .try_send_transactions(&db, sub_id.clone(), 150)
+      .await
+      .unwrap();
+  }
+  first_mock.assert();
+  second_mock.assert();
+
+  let deliveries = find_all(
+    db.collection("deliveries"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  let sequences: Vec<i64> = deliveries
+    .iter()
+    .map(|delivery| delivery.get_i64("sequence").unwrap())
+    .collect();
+  assert_eq!(sequences, vec![1, 1, 2]);
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[test]
+fn test_should_suspend() {
+  let now = bson::DateTime::now().timestamp_millis();
+  assert!(!should_suspend(&doc! {}, now));
+  assert!(!should_suspend(
+    &doc! { "failing_since": bson::DateTime::from_millis(now - 60000) },
+    now
+  ));
+  assert!(should_suspend(
+    &doc! { "failing_since": bson::DateTime::from_millis(now - get_suspend_after_millis()) },
+    now
+  ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_fails_fast_on_open_circuit() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-circuit-breaker",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(503);
+  });
+
+  let mut queue_map = HashMap::new();
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut queue_map,
+  };
+  for _ in 0..get_breaker_failure_threshold() + 1 {
+    db.collection::<Document>("transactionblocks")
+      .update_many(
+        doc! { "subid": &sub_id },
+        doc! { "$set": { "locked_until": bson::DateTime::from_millis(0) } },
+        None,
+      )
+      .await
+      .unwrap();
+    dispatcher_data
+      .try_send_transactions(&db, sub_id.clone(), 150)
+      .await
+      .unwrap();
+  }
+
+  // the last round never reached the host and waits for the circuit to half open
+  webhook_mock.assert_hits(get_breaker_failure_threshold() as usize);
+  let next = dispatcher_data.queue_map.get(&sub_id).unwrap();
+  assert!(next.increase_timeout > 10000);
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::from_str(&sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert!(subscription.get_datetime("failing_since").is_ok());
+  assert!(subscription.get_bool("isActive").unwrap());
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_suspends_failing_subscription() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-suspension",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  let failing_since = bson::DateTime::from_millis(
+    bson::DateTime::now().timestamp_millis() - get_suspend_after_millis(),
+  );
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$set": { "failing_since": failing_since } },
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(500);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(1);
+  assert!(!dispatcher_data.queue_map.contains_key(&sub_id));
+
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::from_str(&sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert!(!subscription.get_bool("isActive").unwrap());
+  assert!(subscription
+    .get_str("suspended_reason")
+    .unwrap()
+    .contains("failing continuously"));
+
+  // suspended subscriptions are skipped and keep their blocks
+  db.collection::<Document>("transactionblocks")
+    .update_many(
+      doc! { "subid": &sub_id },
+      doc! { "$set": { "locked_until": bson::DateTime::from_millis(0) } },
+      None,
+    )
+    .await
+    .unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(1);
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(pending.len(), 2);
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[test]
+fn test_parse_retry_after() {
+  let now = chrono::DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+    .unwrap()
+    .with_timezone(&chrono::Utc);
+  assert_eq!(
+    parse_retry_after("120", now),
+    Some(std::time::Duration::from_secs(120))
+  );
+  assert_eq!(
+    parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+    Some(std::time::Duration::from_secs(30))
+  );
+  // dates in the past mean right away
+  assert_eq!(
+    parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+    Some(std::time::Duration::ZERO)
+  );
+  assert_eq!(
+    parse_retry_after("999999999", now),
+    Some(std::time::Duration::from_secs(86400))
+  );
+  assert_eq!(parse_retry_after("soon", now), None);
+}
+
+#[test]
+fn test_failure_kind_from_status() {
+  use reqwest::StatusCode;
+  assert_eq!(
+    FailureKind::from_status(StatusCode::TOO_MANY_REQUESTS),
+    FailureKind::RateLimited
+  );
+  assert_eq!(
+    FailureKind::from_status(StatusCode::GONE),
+    FailureKind::Gone
+  );
+  assert_eq!(
+    FailureKind::from_status(StatusCode::PAYLOAD_TOO_LARGE),
+    FailureKind::PayloadTooLarge
+  );
+  assert_eq!(
+    FailureKind::from_status(StatusCode::UNPROCESSABLE_ENTITY),
+    FailureKind::PayloadRejected
+  );
+  assert_eq!(
+    FailureKind::from_status(StatusCode::NOT_FOUND),
+    FailureKind::ClientError
+  );
+  assert_eq!(
+    FailureKind::from_status(StatusCode::BAD_GATEWAY),
+    FailureKind::ServerError
+  );
+  assert!(!FailureKind::ServerError.is_endpoint_healthy());
+  assert!(FailureKind::PayloadRejected.is_endpoint_healthy());
+  assert!(FailureKind::ServerError.reaches_endpoint());
+  assert!(!FailureKind::Misconfigured.reaches_endpoint());
+}
+
+#[tokio::test]
+async fn test_dispatch_transactions_classifies_transport_errors() {
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  for (url, failure) in [
+    ("http://127.0.0.1:1/webhook", FailureKind::Connect),
+    ("http://webhook.invalid/webhook", FailureKind::Dns),
+  ] {
+    let subscription = doc! { "url": url, "apikey": "apikey", "contract_id": "c" };
+    let outcome = dispatcher_data
+      .dispatch_transactions(
+        vec![json!({ "block_number": 1 })],
+        &subscription,
+        "sub".to_string(),
+        &BatchMetadata::default(),
+      )
+      .await
+      .unwrap();
+    assert!(!outcome.is_good);
+    assert_eq!(outcome.failure, Some(failure));
+    assert!(!outcome.is_endpoint_healthy());
+  }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_dispatch_transactions_reads_retry_after() {
+  let mock_server = MockServer::start();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(429).header("Retry-After", "7");
+  });
+  let subscription = doc! {
+    "url": mock_server.url("/webhook"),
+    "apikey": "apikey",
+    "contract_id": "c",
+  };
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  let outcome = dispatcher_data
+    .dispatch_transactions(
+      vec![json!({ "block_number": 1 })],
+      &subscription,
+      "sub".to_string(),
+      &BatchMetadata::default(),
+    )
+    .await
+    .unwrap();
+  webhook_mock.assert();
+  assert_eq!(outcome.failure, Some(FailureKind::RateLimited));
+  assert_eq!(outcome.retry_after, Some(std::time::Duration::from_secs(7)));
+  assert!(outcome.is_endpoint_healthy());
+}
+
+#[test]
+fn test_deferring_endpoint_is_not_healthy() {
+  // an unavailable endpoint stays unhealthy however long it asks to wait
+  let outcome = DeliveryOutcome {
+    status: Some(503),
+    failure: Some(FailureKind::ServerError),
+    retry_after: Some(std::time::Duration::from_secs(7)),
+    ..Default::default()
+  };
+  assert!(!outcome.is_endpoint_healthy());
+  // and the throttled one is healthy with or without a `Retry-After`
+  let outcome = DeliveryOutcome {
+    failure: Some(FailureKind::RateLimited),
+    ..outcome
+  };
+  assert!(outcome.is_endpoint_healthy());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_honors_retry_after() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-retry-after",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(503).header("Retry-After", "42");
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert();
+  assert_eq!(
+    dispatcher_data
+      .queue_map
+      .get(&sub_id)
+      .unwrap()
+      .increase_timeout,
+    42000
+  );
+
+  // deferring counts as an attempt, so an endpoint that always defers is dead-lettered eventually
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(pending.len(), 2);
+  for block in &pending {
+    assert_eq!(block.get_i32("attempts").unwrap(), 1);
+    let locked_for = block
+      .get_datetime("locked_until")
+      .unwrap()
+      .timestamp_millis()
+      - bson::DateTime::now().timestamp_millis();
+    assert!(locked_for > 40000 && locked_for <= 42000);
+  }
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_dead_letters_endless_retry_after() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-endless-retry-after",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  db.collection::<Document>("transactionblocks")
+    .update_many(
+      doc! { "subid": &sub_id },
+      doc! { "$set": { "attempts": get_max_attempts() - 1 } },
+      None,
+    )
+    .await
+    .unwrap();
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(429).header("Retry-After", "5");
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert();
+
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(pending.is_empty());
+  let dead_letters = find_all(
+    db.collection("deadletters"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(dead_letters.len(), 1);
+  assert_eq!(
+    dead_letters[0].get_i64("attempts").unwrap(),
+    get_max_attempts()
+  );
+
+  delete_many(db.collection("deadletters"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_deactivates_on_gone() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-gone",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(410);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert();
+  assert!(!dispatcher_data.queue_map.contains_key(&sub_id));
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::from_str(&sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert!(!subscription.get_bool("isActive").unwrap());
+  assert!(subscription
+    .get_str("suspended_reason")
+    .unwrap()
+    .contains("410"));
+
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_deactivates_misconfigured_subscriptions() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-misconfigured",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  let object_id = ObjectId::from_str(&sub_id).unwrap();
+  db.collection::<Document>("subscriptions")
+    .update_one(
+      doc! { "_id": object_id },
+      doc! { "$set": { "transform": { "fields": { "amount": "value" } } } },
+      None,
+    )
+    .await
+    .unwrap();
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(0);
+  assert!(!dispatcher_data.queue_map.contains_key(&sub_id));
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": object_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert!(!subscription.get_bool("isActive").unwrap());
+  assert!(subscription
+    .get_str("suspended_reason")
+    .unwrap()
+    .contains("invalid transform"));
+  // nothing is held against the endpoint and no retry is spent
+  assert!(subscription.get("failing_since").is_none());
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(pending.len(), 2);
+  assert!(pending.iter().all(|block| block.get("attempts").is_none()));
+
+  cleanup_subscriptions(&db, &[object_id]).await;
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_splits_and_dead_letters_too_large_batches() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = insert_topics_subscription(
+    &db,
+    "test-dispatcher-payload-too-large",
+    mock_server.url("/webhook"),
+    vec![],
+  )
+  .await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/webhook");
+    then.status(413);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  // the batch of two is split, then each half is refused on its own and dead-lettered
+  for _ in 0..3 {
+    dispatcher_data
+      .try_send_transactions(&db, sub_id.clone(), 150)
+      .await
+      .unwrap();
+  }
+  webhook_mock.assert_hits(3);
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(pending.is_empty());
+  let dead_letters = find_all(
+    db.collection("deadletters"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(dead_letters.len(), 2);
+  assert!(dead_letters
+    .iter()
+    .all(|dead_letter| dead_letter.get_i64("block_count").unwrap() == 1));
+
+  delete_many(db.collection("deadletters"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reorg_retracts_delivered_blocks() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-dispatcher-reorg";
+  let sub_id =
+    insert_topics_subscription(&db, contract_id, mock_server.url("/webhook"), vec![]).await;
+  update_topics(
+    &db,
+    &sub_id,
+    doc! { "$set": { "delivery_policy": { "max_blocks": 1 } } },
+  )
+  .await;
+  db.collection::<Document>("events_info")
+    .insert_one(
+      doc! { "contract_id": contract_id, "reset_nonce": 1_i64, "Transfer": 100_i64, "Approval": 101_i64 },
+      None,
+    )
+    .await
+    .unwrap();
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+
+  let delivered_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .body_contains(r#""block_number":100"#);
+    then.status(200);
+  });
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  delivered_mock.assert();
+
+  // block 100 was delivered and gets retracted, block 101 was still queued and is dropped
+  let notice: ReorgNotice = serde_json::from_value(json!({
+    "contract_id": contract_id,
+    "fork_block": 99,
+    "removed_blocks": [{ "block_number": 100, "block_hash": "0x100" }],
+  }))
+  .unwrap();
+  assert_eq!(handle_reorg(&db, &notice).await.unwrap(), (1, 1));
+
+  let retraction_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .body_contains(r#""block_hash":"0x100""#)
+      .body_contains(r#""removed":true"#);
+    then.status(200);
+  });
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  retraction_mock.assert();
+
+  // a repeated notice does not retract the block twice
+  assert_eq!(handle_reorg(&db, &notice).await.unwrap(), (0, 0));
+
+  let events_info = find_one(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(events_info.get_i64("Transfer").unwrap(), 99);
+  assert_eq!(events_info.get_i64("Approval").unwrap(), 99);
+
+  delete_many(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ingest_transactions_moves_checkpoint_with_blocks() {
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-dispatcher-ingest";
+  let sub_id = insert_topics_subscription(
+    &db,
+    contract_id,
+    "http://localhost/webhook".to_string(),
+    vec![],
+  )
+  .await;
+  let subscriptions = find_all(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::from_str(&sub_id).unwrap() },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  let mut payload = Transactions {
+    contract_id: contract_id.to_string(),
+    reset_nonce: 1,
+    data: topic_transaction_blocks(),
+    head_block: None,
+  };
+
+  let report = ingest_transactions(&db, contract_id, &payload, &subscriptions, false)
+    .await
+    .unwrap();
+  assert_eq!(report.accepted.len(), 2);
+  assert!(report.accepted.iter().all(|block| block.queued == 1));
+  assert!(report.skipped.is_empty());
+  let events_info = find_one(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(events_info.get_i64("Transfer").unwrap(), 100);
+
+  // the same push again is behind the checkpoint
+  let report = ingest_transactions(&db, contract_id, &payload, &subscriptions, false)
+    .await
+    .unwrap();
+  assert!(report.accepted.is_empty());
+  assert_eq!(report.skipped.len(), 2);
+
+  // a new reset nonce accepts the blocks again, the copies still queued are not duplicated
+  payload.reset_nonce = 2;
+  let report = ingest_transactions(&db, contract_id, &payload, &subscriptions, false)
+    .await
+    .unwrap();
+  assert_eq!(report.accepted.len(), 2);
+  assert!(report
+    .accepted
+    .iter()
+    .all(|block| block.queued == 0 && block.duplicates == 1));
+  let queued = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(queued.len(), 2);
+
+  let checkpoint = load_checkpoint(&db, contract_id).await.unwrap();
+  assert_eq!(checkpoint.reset_nonce, Some(2));
+  assert_eq!(checkpoint.events["Approval"].block_number, 101);
+
+  // a new epoch forgets the event checkpoints, so the next push is accepted again
+  assert_eq!(start_epoch(&db, contract_id).await.unwrap(), Some(3));
+  let checkpoint = load_checkpoint(&db, contract_id).await.unwrap();
+  assert_eq!(checkpoint.reset_nonce, Some(3));
+  assert!(checkpoint.events.is_empty());
+
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_try_send_transactions_waits_for_confirmations() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-dispatcher-confirmations";
+  let sub_id =
+    insert_topics_subscription(&db, contract_id, mock_server.url("/webhook"), vec![]).await;
+  update_topics(&db, &sub_id, doc! { "$set": { "confirmations": 5_i64 } }).await;
+  assert_eq!(queue_topic_blocks(&db, &sub_id).await, 2);
+  let webhook_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/webhook")
+      .body_contains(r#""payload_count":1"#)
+      .body_contains(r#""block_number":100"#);
+    then.status(200);
+  });
+
+  let mut dispatcher_data = DispatcherData {
+    queue_list: LinkedList::new(),
+    queue_map: &mut HashMap::new(),
+  };
+  // without a head nothing is confirmed, at 105 only block 100 is
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(0);
+  // held blocks are looked at again on the confirmation poll, not on the failure backoff
+  assert_eq!(
+    dispatcher_data.queue_map[&sub_id].increase_timeout,
+    web3cache::chain_head::get_confirmation_poll_millis()
+  );
+  web3cache::chain_head::record_chain_head(&db, contract_id, 105)
+    .await
+    .unwrap();
+  dispatcher_data
+    .try_send_transactions(&db, sub_id.clone(), 150)
+    .await
+    .unwrap();
+  webhook_mock.assert_hits(1);
+
+  let pending = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(pending.len(), 1);
+  assert_eq!(pending[0].get_i64("block_number").unwrap(), 101);
+
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+  delete_many(
+    db.collection("chainheads"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+  cleanup_subscriptions(&db, &[ObjectId::from_str(&sub_id).unwrap()]).await;
+  delete_many(db.collection("deliveries"), doc! { "subid": &sub_id })
+    .await
+    .unwrap();
+}
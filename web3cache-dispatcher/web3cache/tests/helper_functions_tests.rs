@@ -12,3 +12,26 @@ fn test_get_i64_from_doc() {
   assert_eq!(get_i64_from_doc(&doc, "bar".to_string()), 23);
   assert_eq!(get_i64_from_doc(&doc, "baz".to_string()), 0);
 }
+
+#[test]
+fn test_subscription_wants_event() {
+  let all_events = doc! { "topics": [] };
+  let no_topics_field = doc! {};
+  let transfers_only = doc! { "topics": ["Transfer", "Mint"] };
+
+  assert!(subscription_wants_event(&all_events, "Approval"));
+  assert!(subscription_wants_event(&no_topics_field, "Approval"));
+  assert!(subscription_wants_event(&transfers_only, "Mint"));
+  assert!(!subscription_wants_event(&transfers_only, "Approval"));
+}
+
+#[test]
+fn test_get_topics_from_doc() {
+  let subscription = doc! { "topics": ["Transfer", "Mint"] };
+
+  assert_eq!(
+    get_topics_from_doc(&subscription),
+    vec!["Transfer".to_string(), "Mint".to_string()]
+  );
+  assert!(get_topics_from_doc(&doc! {}).is_empty());
+}
@@ -0,0 +1,51 @@
+use actix_web::{http::StatusCode, test as actix_test, web, App};
+use futures_util::StreamExt;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use web3cache::consumer_api::push_transactions;
+use web3cache::helper_functions::AppState;
+use web3cache::ingest_ws::serve_ingest_connections;
+
+// a binary of its own, the migration switch would open ingestion for the other test binaries
+#[actix_web::test]
+async fn test_unauthenticated_ingest_while_migrating() {
+  std::env::set_var("INGEST_SECRET", "test-ingest-secret");
+  std::env::set_var("ALLOW_UNAUTHENTICATED_INGEST", "true");
+  // the client connects lazily, no request below reaches the database
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let app = actix_test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .service(push_transactions),
+  )
+  .await;
+
+  // past authentication, refused for the payload
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .set_payload("{\"contract_id\":")
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .insert_header(("x-msl-ingest-key", "wrong-secret"))
+    .set_json(json!({ "contract_id": "c", "reset_nonce": 1, "data": [] }))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("ws://{}", listener.local_addr().unwrap());
+  tokio::spawn(serve_ingest_connections(listener, db));
+  let (mut ws, _) = connect_async(url.as_str()).await.unwrap();
+  match ws.next().await.unwrap().unwrap() {
+    Message::Text(text) => assert!(text.contains("credit")),
+    message => panic!("unexpected message {:?}", message),
+  }
+}
@@ -0,0 +1,237 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::{
+  connect_async,
+  tungstenite::{
+    client::IntoClientRequest, handshake::client::generate_key, Error as WsError, Message,
+  },
+};
+use validator::Validate;
+use web3cache::consumer_api::{AcceptedBlock, IngestReport, SkippedBlock, TransactionBlock};
+use web3cache::dispatcher::sign_body;
+use web3cache::ingest_ws::*;
+
+fn transaction_block() -> TransactionBlock {
+  TransactionBlock {
+    block_number: 100,
+    event_name: "Transfer".to_string(),
+    transactions: vec![json!({ "transaction_id": "tx1" })],
+    block_hash: None,
+  }
+}
+
+#[test]
+fn test_flow_control() {
+  let mut flow = FlowControl::new(2);
+  assert!(flow.spend());
+  assert!(flow.spend());
+  assert!(!flow.spend());
+
+  // credits are held back while the dispatcher is behind and handed back together
+  assert_eq!(flow.settle(true), 0);
+  assert!(!flow.spend());
+  assert_eq!(flow.settle(false), 2);
+  assert_eq!(
+    flow,
+    FlowControl {
+      available: 2,
+      withheld: 0
+    }
+  );
+
+  assert!(flow.spend());
+  assert_eq!(flow.settle(true), 0);
+  assert_eq!(flow.release(), 1);
+  assert_eq!(flow.release(), 0);
+  assert_eq!(flow.available, 2);
+}
+
+#[test]
+fn test_is_behind() {
+  assert!(!is_behind(Duration::from_millis(10), 10));
+  assert!(is_behind(
+    Duration::from_millis(get_ingest_ws_slow_millis() + 1),
+    10
+  ));
+  assert!(is_behind(
+    Duration::from_millis(10),
+    get_ingest_ws_max_queued() + 1
+  ));
+}
+
+#[test]
+fn test_ack_from_report() {
+  let accepted = IngestReport {
+    accepted: vec![AcceptedBlock {
+      block_number: 100,
+      event_name: "Transfer".to_string(),
+      queued: 1,
+      duplicates: 0,
+    }],
+    skipped: vec![],
+  };
+  assert_eq!(
+    serde_json::to_value(ack_from_report(7, &transaction_block(), 2, &accepted, 1)).unwrap(),
+    json!({
+      "type": "ack",
+      "seq": 7,
+      "accepted": true,
+      "event_name": "Transfer",
+      "reset_nonce": 2,
+      "checkpoint": 100,
+      "credits": 1,
+    })
+  );
+
+  let skipped = IngestReport {
+    accepted: vec![],
+    skipped: vec![SkippedBlock {
+      block_number: 100,
+      event_name: "Transfer".to_string(),
+      checkpoint: 120,
+    }],
+  };
+  assert_eq!(
+    ack_from_report(8, &transaction_block(), 2, &skipped, 0),
+    IngestMessage::Ack {
+      seq: 8,
+      accepted: false,
+      event_name: "Transfer".to_string(),
+      reset_nonce: 2,
+      checkpoint: 120,
+      credits: 0,
+    }
+  );
+}
+
+#[test]
+fn test_validate_ingest_frame() {
+  let frame: IngestFrame = serde_json::from_value(json!({
+    "seq": 1,
+    "contract_id": "c",
+    "reset_nonce": 1,
+    "block": { "block_number": 5, "event_name": "Transfer", "transactions": [{ "id": 1 }] },
+  }))
+  .unwrap();
+  assert!(frame.validate().is_ok());
+
+  let frame: IngestFrame = serde_json::from_value(json!({
+    "seq": 2,
+    "contract_id": "c",
+    "reset_nonce": 1,
+    "block": { "block_number": 5, "event_name": "Transfer", "transactions": [] },
+  }))
+  .unwrap();
+  assert!(frame.validate().is_err());
+}
+
+async fn next_json<S>(ws: &mut S) -> Value
+where
+  S: StreamExt<Item = Result<Message, WsError>> + Unpin,
+{
+  match ws.next().await.unwrap().unwrap() {
+    Message::Text(text) => serde_json::from_str(&text).unwrap(),
+    message => panic!("unexpected message {:?}", message),
+  }
+}
+
+#[tokio::test]
+async fn test_ingest_connection_handshake_and_invalid_frames() {
+  std::env::set_var("INGEST_SECRET", "test-ingest-secret");
+  // the client connects lazily, no frame below reaches the database
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("ws://{}", listener.local_addr().unwrap());
+  tokio::spawn(serve_ingest_connections(listener, db));
+
+  match connect_async(url.as_str()).await {
+    Err(WsError::Http(response)) => assert_eq!(response.status(), 401),
+    other => panic!("expected a refused handshake, got {:?}", other.map(|_| ())),
+  }
+
+  let mut request = url.as_str().into_client_request().unwrap();
+  request
+    .headers_mut()
+    .insert("x-msl-ingest-key", "test-ingest-secret".parse().unwrap());
+  let (mut ws, _) = connect_async(request).await.unwrap();
+  assert_eq!(
+    next_json(&mut ws).await,
+    json!({ "type": "credit", "credits": get_ingest_ws_credits() })
+  );
+
+  ws.send(Message::Text("{\"seq\":".to_string()))
+    .await
+    .unwrap();
+  let nack = next_json(&mut ws).await;
+  assert_eq!(nack["type"], "nack");
+  assert_eq!(nack["seq"], Value::Null);
+  assert_eq!(nack["credits"], 1);
+
+  let frame = json!({
+    "seq": 3,
+    "contract_id": "c",
+    "reset_nonce": 1,
+    "block": { "block_number": -1, "event_name": "Transfer", "transactions": [{ "id": 1 }] },
+  });
+  ws.send(Message::Text(frame.to_string())).await.unwrap();
+  let nack = next_json(&mut ws).await;
+  assert_eq!(nack["type"], "nack");
+  assert_eq!(nack["seq"], 3);
+  assert!(nack["message"].as_str().unwrap().contains("block_number"));
+}
+
+#[tokio::test]
+async fn test_ingest_connection_accepts_signed_handshakes() {
+  std::env::set_var("INGEST_SECRET", "test-ingest-secret");
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("ws://{}/ingest", listener.local_addr().unwrap());
+  tokio::spawn(serve_ingest_connections(listener, db));
+
+  // signs the handshake for `signed_key` and sends it with `sent_key`
+  let signed_request = |secret: &str, timestamp: i64, signed_key: &str, sent_key: &str| {
+    let payload = handshake_signing_payload("GET", "/ingest", signed_key);
+    let signature = sign_body(secret, timestamp, payload.as_bytes()).unwrap();
+    let mut request = url.as_str().into_client_request().unwrap();
+    let headers = request.headers_mut();
+    headers.insert("sec-websocket-key", sent_key.parse().unwrap());
+    headers.insert(
+      "x-msl-ingest-signature",
+      format!("t={},v1={}", timestamp, signature).parse().unwrap(),
+    );
+    request
+  };
+  let now = chrono::Utc::now().timestamp();
+  let key = generate_key();
+
+  let (mut ws, _) = connect_async(signed_request("test-ingest-secret", now, &key, &key))
+    .await
+    .unwrap();
+  assert_eq!(
+    next_json(&mut ws).await,
+    json!({ "type": "credit", "credits": get_ingest_ws_credits() })
+  );
+
+  // a stale timestamp, another secret, a signature made for another key and a replayed
+  // handshake are refused
+  let other_key = generate_key();
+  for request in [
+    signed_request("test-ingest-secret", now - 3600, &other_key, &other_key),
+    signed_request("wrong-secret", now, &other_key, &other_key),
+    signed_request("test-ingest-secret", now, &key, &other_key),
+    signed_request("test-ingest-secret", now, &key, &key),
+  ] {
+    match connect_async(request).await {
+      Err(WsError::Http(response)) => assert_eq!(response.status(), 401),
+      other => panic!("expected a refused handshake, got {:?}", other.map(|_| ())),
+    }
+  }
+}
@@ -0,0 +1,44 @@
+use bson::{doc, Document};
+use serial_test::serial;
+use web3cache::database::connect_to_mongodb_test;
+use web3cache::lease::*;
+
+#[test]
+fn test_get_instance_id_is_stable() {
+  assert!(!get_instance_id().is_empty());
+  assert_eq!(get_instance_id(), get_instance_id());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_acquire_lease() {
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = "test-acquire-lease";
+  release_lease(&db, sub_id, "replica-a").await.unwrap();
+
+  assert!(acquire_lease(&db, sub_id, "replica-a").await.unwrap());
+  // renewing an owned lease
+  assert!(acquire_lease(&db, sub_id, "replica-a").await.unwrap());
+  // a live lease is not taken over
+  assert!(!acquire_lease(&db, sub_id, "replica-b").await.unwrap());
+
+  // an expired lease is taken over
+  db.collection::<Document>("dispatcherleases")
+    .update_one(
+      doc! { "_id": sub_id },
+      doc! { "$set": { "expires_at": bson::DateTime::from_millis(0) } },
+      None,
+    )
+    .await
+    .unwrap();
+  assert!(acquire_lease(&db, sub_id, "replica-b").await.unwrap());
+  assert!(!acquire_lease(&db, sub_id, "replica-a").await.unwrap());
+
+  // releasing someone else's lease does nothing
+  release_lease(&db, sub_id, "replica-a").await.unwrap();
+  assert!(!acquire_lease(&db, sub_id, "replica-a").await.unwrap());
+  release_lease(&db, sub_id, "replica-b").await.unwrap();
+  assert!(acquire_lease(&db, sub_id, "replica-a").await.unwrap());
+
+  release_lease(&db, sub_id, "replica-a").await.unwrap();
+}
@@ -0,0 +1,116 @@
+use bson::{doc, oid::ObjectId, Document};
+use web3cache::database::{connect_to_mongodb_test, delete_many, insert_many};
+use web3cache::dispatcher::{DeliveryOutcome, FailureKind};
+use web3cache::metrics::*;
+
+#[test]
+fn test_status_class() {
+  assert_eq!(status_class(Some(204)), "2xx");
+  assert_eq!(status_class(Some(429)), "4xx");
+  assert_eq!(status_class(Some(503)), "5xx");
+  assert_eq!(status_class(None), "error");
+}
+
+#[test]
+fn test_histogram_observe() {
+  let mut histogram = Histogram::new(&[0.1, 1.0]);
+  histogram.observe(0.05);
+  histogram.observe(0.1);
+  histogram.observe(0.5);
+  histogram.observe(3.0);
+
+  assert_eq!(histogram.counts, vec![2, 1, 1]);
+  assert_eq!(histogram.count(), 4);
+  assert!((histogram.sum - 3.65).abs() < 1e-9);
+}
+
+#[test]
+fn test_render_metrics() {
+  let mut metrics = Metrics::default();
+  metrics.set_queue(3, 1);
+  metrics.record_delivery(&DeliveryOutcome {
+    is_good: true,
+    status: Some(200),
+    latency_ms: 120,
+    ..Default::default()
+  });
+  metrics.record_delivery(&DeliveryOutcome {
+    is_good: false,
+    status: None,
+    failure: Some(FailureKind::Timeout),
+    latency_ms: 20000,
+    ..Default::default()
+  });
+  metrics.record_ingestion("my_contract", 2, 1);
+  metrics.record_ingestion("my_contract", 1, 0);
+  let pending = vec![
+    PendingBlocks {
+      subid: "sub-a".to_string(),
+      count: 4,
+      oldest_millis: 10_000,
+    },
+    PendingBlocks {
+      subid: "sub-b".to_string(),
+      count: 1,
+      oldest_millis: 55_000,
+    },
+  ];
+
+  let rendered = render_metrics(&metrics, &pending, 60_000);
+  let lines: Vec<&str> = rendered.lines().collect();
+
+  for expected in [
+    "web3cache_dispatcher_queue_depth 3",
+    "web3cache_dispatcher_in_flight 1",
+    "web3cache_pending_blocks{subid=\"sub-a\"} 4",
+    "web3cache_oldest_pending_block_age_seconds{subid=\"sub-b\"} 5",
+    "web3cache_oldest_undelivered_block_age_seconds 50",
+    "web3cache_delivery_attempts_total{status_class=\"2xx\"} 1",
+    "web3cache_delivery_attempts_total{status_class=\"error\"} 1",
+    "web3cache_delivery_successes_total{status_class=\"2xx\"} 1",
+    "web3cache_delivery_failures_total{status_class=\"error\",failure=\"timeout\"} 1",
+    "web3cache_delivery_latency_seconds_bucket{le=\"0.25\"} 1",
+    "web3cache_delivery_latency_seconds_bucket{le=\"20\"} 2",
+    "web3cache_delivery_latency_seconds_bucket{le=\"+Inf\"} 2",
+    "web3cache_delivery_latency_seconds_count 2",
+    "web3cache_ingest_requests_total{contract_id=\"my_contract\"} 2",
+    "web3cache_ingested_blocks_total{contract_id=\"my_contract\",result=\"accepted\"} 3",
+    "web3cache_ingested_blocks_total{contract_id=\"my_contract\",result=\"skipped\"} 1",
+    "# TYPE web3cache_delivery_latency_seconds histogram",
+  ] {
+    assert!(lines.contains(&expected), "missing {}", expected);
+  }
+}
+
+#[tokio::test]
+async fn test_load_pending_blocks() {
+  let db = connect_to_mongodb_test().await.unwrap();
+  let sub_id = ObjectId::new().to_string();
+  let blocks: Vec<Document> = (1..=3_i64)
+    .map(|block_number| {
+      doc! { "subid": &sub_id, "block_number": block_number, "event_name": "Transfer", "transactions": [] }
+    })
+    .collect();
+  insert_many(
+    db.collection("transactionblocks"),
+    &blocks,
+    Default::default(),
+  )
+  .await
+  .unwrap();
+
+  let pending = load_pending_blocks(&db).await.unwrap();
+  let blocks = pending
+    .iter()
+    .find(|blocks| blocks.subid == sub_id)
+    .unwrap();
+  assert_eq!(blocks.count, 3);
+  assert!(blocks.oldest_millis <= bson::DateTime::now().timestamp_millis());
+
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await
+  .unwrap();
+}
@@ -0,0 +1,95 @@
+use bson::doc;
+use reqwest::header::HeaderMap;
+use serde_json::json;
+use std::io::Read;
+use web3cache::payload_encoding::*;
+
+fn body() -> serde_json::Value {
+  json!({
+    "metadata": { "contract_id": "contract", "sequence": 3, "replay": false },
+    "payload_count": 1,
+    "payload": [{ "block_number": 10, "value": -5, "price": 1.5, "transactions": [] }],
+  })
+}
+
+#[test]
+fn test_format_from_subscription() {
+  assert_eq!(
+    PayloadFormat::from_subscription(&doc! {}).unwrap(),
+    PayloadFormat::default()
+  );
+  assert_eq!(
+    PayloadFormat::from_subscription(&doc! { "encoding": "msgpack", "compression": "gzip" })
+      .unwrap(),
+    PayloadFormat {
+      encoding: PayloadEncoding::MessagePack,
+      compression: Some(PayloadCompression::Gzip),
+    }
+  );
+  assert_eq!(
+    PayloadFormat::from_subscription(&doc! { "encoding": "json", "compression": "none" }).unwrap(),
+    PayloadFormat::default()
+  );
+  assert!(PayloadFormat::from_subscription(&doc! { "encoding": "xml" }).is_err());
+  assert!(PayloadFormat::from_subscription(&doc! { "compression": "brotli" }).is_err());
+}
+
+#[test]
+fn test_encodings_round_trip() {
+  let body = body();
+  let json_bytes = PayloadEncoding::Json.encode(&body).unwrap();
+  assert_eq!(
+    serde_json::from_slice::<serde_json::Value>(&json_bytes).unwrap(),
+    body
+  );
+
+  let cbor_bytes = PayloadEncoding::Cbor.encode(&body).unwrap();
+  let decoded: serde_json::Value = ciborium::de::from_reader(cbor_bytes.as_slice()).unwrap();
+  assert_eq!(decoded, body);
+
+  let msgpack_bytes = PayloadEncoding::MessagePack.encode(&body).unwrap();
+  let decoded: serde_json::Value = rmp_serde::from_slice(&msgpack_bytes).unwrap();
+  assert_eq!(decoded, body);
+
+  assert!(cbor_bytes.len() < json_bytes.len());
+  assert!(msgpack_bytes.len() < json_bytes.len());
+}
+
+#[test]
+fn test_compression_round_trip() {
+  let blocks: Vec<serde_json::Value> = (0..50).map(|_| body()).collect();
+  let bytes = serde_json::to_vec(&blocks).unwrap();
+
+  let gzipped = PayloadCompression::Gzip.compress(&bytes).unwrap();
+  let mut decoded = vec![];
+  flate2::read::GzDecoder::new(gzipped.as_slice())
+    .read_to_end(&mut decoded)
+    .unwrap();
+  assert_eq!(decoded, bytes);
+  assert!(gzipped.len() < bytes.len());
+
+  let zstd_bytes = PayloadCompression::Zstd.compress(&bytes).unwrap();
+  assert_eq!(
+    zstd::stream::decode_all(zstd_bytes.as_slice()).unwrap(),
+    bytes
+  );
+  assert!(zstd_bytes.len() < bytes.len());
+}
+
+#[test]
+fn test_apply_headers() {
+  let mut headers = HeaderMap::new();
+  PayloadFormat {
+    encoding: PayloadEncoding::MessagePack,
+    compression: Some(PayloadCompression::Zstd),
+  }
+  .apply_headers(&mut headers);
+  assert_eq!(headers["content-type"], "application/msgpack");
+  assert_eq!(headers["x-msl-webhook-format"], "MSGPACK");
+  assert_eq!(headers["content-encoding"], "zstd");
+
+  PayloadFormat::default().apply_headers(&mut headers);
+  assert_eq!(headers["content-type"], "application/json");
+  assert_eq!(headers["x-msl-webhook-format"], "JSON");
+  assert!(!headers.contains_key("content-encoding"));
+}
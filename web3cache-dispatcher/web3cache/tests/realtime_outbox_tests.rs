@@ -0,0 +1,214 @@
+use bson::{doc, Document};
+use httpmock::Method::POST;
+use httpmock::MockServer;
+use mongodb::options::{FindOneOptions, FindOptions, InsertOneOptions};
+use serde_json::json;
+use web3cache::consumer_api::{ingest_transactions, TransactionBlock, Transactions};
+use web3cache::database::{connect_to_mongodb_test, delete_many, find_all, find_one};
+use web3cache::realtime_outbox::*;
+
+#[test]
+fn test_outbox_doc() {
+  assert!(outbox_doc("c", &[]).unwrap().is_none());
+
+  let entry = outbox_doc("c", &[json!({ "transaction_id": "tx1", "amount": 100 })])
+    .unwrap()
+    .unwrap();
+  assert_eq!(entry.get_str("contract_id").unwrap(), "c");
+  assert_eq!(entry.get_i32("attempts").unwrap(), 0);
+  assert_eq!(entry.get_array("transactions").unwrap().len(), 1);
+}
+
+#[test]
+fn test_notification_body_keeps_outbox_order() {
+  let entries = vec![
+    outbox_doc("a", &[json!({ "id": 1 }), json!({ "id": 2 })])
+      .unwrap()
+      .unwrap(),
+    outbox_doc("b", &[json!({ "id": 3, "value": "1000000000000000000" })])
+      .unwrap()
+      .unwrap(),
+  ];
+  assert_eq!(
+    notification_body(&entries),
+    json!({
+      "transactions": [
+        { "id": 1 },
+        { "id": 2 },
+        { "id": 3, "value": "1000000000000000000" },
+      ],
+    })
+  );
+}
+
+#[test]
+fn test_realtime_backoff_millis() {
+  assert_eq!(realtime_backoff_millis(1), 500);
+  assert_eq!(realtime_backoff_millis(2), 1000);
+  assert_eq!(realtime_backoff_millis(4), 4000);
+  assert_eq!(realtime_backoff_millis(40), 10000);
+}
+
+#[tokio::test]
+async fn test_exhausted_entries_are_parked() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-realtime-parked";
+  let mut entry = outbox_doc(contract_id, &[json!({ "transaction_id": "tx-parked" })])
+    .unwrap()
+    .unwrap();
+  entry.insert("attempts", get_realtime_max_attempts() - 1);
+  db.collection::<Document>("realtimeoutbox")
+    .insert_one(entry, None)
+    .await
+    .unwrap();
+
+  let realtime_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/notify-transactions");
+    then.status(400);
+  });
+  let client = reqwest::Client::new();
+  assert!(publish_outbox_batch(&db, &client, &mock_server.base_url())
+    .await
+    .is_err());
+  realtime_mock.assert();
+
+  // the refused entry no longer holds back the ones behind it
+  assert!(find_one(
+    db.collection("realtimeoutbox"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .is_none());
+  let parked = find_one(
+    db.collection("realtimedeadletters"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(
+    parked.get_i64("attempts").unwrap(),
+    get_realtime_max_attempts()
+  );
+  assert!(parked.get_datetime("parked_at").is_ok());
+
+  delete_many(
+    db.collection("realtimedeadletters"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+}
+
+#[tokio::test]
+async fn test_outbox_is_written_with_blocks_and_drained() {
+  let mock_server = MockServer::start();
+  let db = connect_to_mongodb_test().await.unwrap();
+  let contract_id = "test-realtime-outbox";
+  let sub_id = db
+    .collection::<Document>("subscriptions")
+    .insert_one(
+      doc! { "url": "http://localhost/webhook", "contract_id": contract_id, "isActive": true },
+      InsertOneOptions::default(),
+    )
+    .await
+    .unwrap()
+    .inserted_id
+    .as_object_id()
+    .unwrap();
+  let subscriptions = find_all(
+    db.collection("subscriptions"),
+    doc! { "_id": sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  let payload = Transactions {
+    contract_id: contract_id.to_string(),
+    reset_nonce: 1,
+    data: vec![TransactionBlock {
+      block_number: 10,
+      event_name: "Transfer".to_string(),
+      transactions: vec![json!({ "transaction_id": "tx-outbox" })],
+      block_hash: None,
+    }],
+    head_block: None,
+  };
+
+  ingest_transactions(&db, contract_id, &payload, &subscriptions, true)
+    .await
+    .unwrap();
+  let entry = find_one(
+    db.collection("realtimeoutbox"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(entry.get_array("transactions").unwrap().len(), 1);
+
+  // a failed notification stays in the outbox
+  let mut failing_mock = mock_server.mock(|when, then| {
+    when.method(POST).path("/notify-transactions");
+    then.status(503);
+  });
+  let client = reqwest::Client::new();
+  assert!(publish_outbox_batch(&db, &client, &mock_server.base_url())
+    .await
+    .is_err());
+  failing_mock.assert();
+  failing_mock.delete();
+  let entry = find_one(
+    db.collection("realtimeoutbox"),
+    doc! { "contract_id": contract_id },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(entry.get_i32("attempts").unwrap(), 1);
+
+  let realtime_mock = mock_server.mock(|when, then| {
+    when
+      .method(POST)
+      .path("/notify-transactions")
+      .body_contains("tx-outbox");
+    then.status(200);
+  });
+  assert!(
+    publish_outbox_batch(&db, &client, &mock_server.base_url())
+      .await
+      .unwrap()
+      >= 1
+  );
+  realtime_mock.assert();
+  let remaining = find_all(
+    db.collection("realtimeoutbox"),
+    doc! { "contract_id": contract_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining.is_empty());
+
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": sub_id.to_string() },
+  )
+  .await
+  .unwrap();
+  delete_many(
+    db.collection("events_info"),
+    doc! { "contract_id": contract_id },
+  )
+  .await
+  .unwrap();
+  delete_many(db.collection("subscriptions"), doc! { "_id": sub_id })
+    .await
+    .unwrap();
+}
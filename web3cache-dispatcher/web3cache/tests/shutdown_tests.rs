@@ -0,0 +1,149 @@
+use actix_web::{http::StatusCode, test as actix_test, web, App};
+use bson::{doc, oid::ObjectId, Document};
+use mongodb::options::{FindOptions, InsertManyOptions};
+use serde_json::json;
+use std::time::Duration;
+use web3cache::consumer_api::{consumer_health_check, push_transactions};
+use web3cache::database::{connect_to_mongodb_test, delete_many, find_all, insert_many};
+use web3cache::helper_functions::AppState;
+use web3cache::lease::acquire_lease;
+use web3cache::shutdown::*;
+
+#[test]
+fn test_shutdown_report_summary() {
+  let report = ShutdownReport {
+    finished: 3,
+    abandoned: vec!["sub-a".to_string()],
+    unlocked_blocks: 2,
+    released_leases: 4,
+  };
+  assert_eq!(
+    report.summary(),
+    "3 deliveries finished, 1 abandoned [\"sub-a\"], 2 blocks unlocked, 4 leases released"
+  );
+}
+
+#[test]
+fn test_get_shutdown_deadline() {
+  assert_eq!(get_shutdown_deadline(), Duration::from_secs(20));
+}
+
+// the token is shared by the whole test binary, nothing else here depends on it
+#[actix_web::test]
+async fn test_ingestion_refused_while_shutting_down() {
+  std::env::set_var("INGEST_SECRET", "test-ingest-secret");
+  // the client connects lazily, every request below is refused before it touches the database
+  let db = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+    .await
+    .unwrap()
+    .database("test");
+  let app = actix_test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db }))
+      .service(consumer_health_check)
+      .service(push_transactions),
+  )
+  .await;
+  shutdown_token().cancel();
+  assert!(is_shutting_down());
+
+  let req = actix_test::TestRequest::post()
+    .uri("/push-transactions")
+    .insert_header(("x-msl-ingest-key", "test-ingest-secret"))
+    .set_json(json!({ "contract_id": "c", "reset_nonce": 1, "data": [] }))
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+  let req = actix_test::TestRequest::get()
+    .uri("/healthcheck")
+    .to_request();
+  let response = actix_test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_release_dispatcher_state() {
+  let db = connect_to_mongodb_test().await.unwrap();
+  let owner = "test-shutdown-owner";
+  let abandoned_sub = ObjectId::new().to_string();
+  let finished_sub = ObjectId::new().to_string();
+  let lock_id = ObjectId::new();
+  let retry_lock_id = ObjectId::new();
+  let locked_until = bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + 30_000);
+  let blocks = vec![
+    doc! { "subid": &abandoned_sub, "block_number": 1_i64, "event_name": "Transfer", "locked_until": locked_until, "locked_by": lock_id },
+    doc! { "subid": &abandoned_sub, "block_number": 2_i64, "event_name": "Transfer", "locked_until": locked_until, "locked_by": lock_id },
+    // waiting out a retry delay, it has to keep it
+    doc! { "subid": &finished_sub, "block_number": 1_i64, "event_name": "Transfer", "locked_until": locked_until, "locked_by": retry_lock_id },
+  ];
+  insert_many(
+    db.collection("transactionblocks"),
+    &blocks,
+    InsertManyOptions::default(),
+  )
+  .await
+  .unwrap();
+  round_locks()
+    .lock()
+    .unwrap()
+    .insert(abandoned_sub.clone(), lock_id);
+  for sub_id in [&abandoned_sub, &finished_sub] {
+    assert!(acquire_lease(&db, sub_id, owner).await.unwrap());
+  }
+
+  let report = release_dispatcher_state(
+    &db,
+    owner,
+    &[abandoned_sub.clone(), finished_sub.clone()],
+    1,
+    vec![abandoned_sub.clone()],
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    report,
+    ShutdownReport {
+      finished: 1,
+      abandoned: vec![abandoned_sub.clone()],
+      unlocked_blocks: 2,
+      released_leases: 2,
+    }
+  );
+  assert!(round_locks().lock().unwrap().is_empty());
+
+  let stored: Vec<Document> = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": { "$in": [&abandoned_sub, &finished_sub] } },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  for block in &stored {
+    let unlocked = block.get_str("subid").unwrap() == abandoned_sub;
+    assert_eq!(!block.contains_key("locked_by"), unlocked);
+    assert_eq!(
+      *block.get_datetime("locked_until").unwrap() < locked_until,
+      unlocked
+    );
+  }
+  // another replica can take the subscriptions over right away
+  for sub_id in [&abandoned_sub, &finished_sub] {
+    assert!(acquire_lease(&db, sub_id, "another-dispatcher")
+      .await
+      .unwrap());
+  }
+
+  delete_many(
+    db.collection("transactionblocks"),
+    doc! { "subid": { "$in": [&abandoned_sub, &finished_sub] } },
+  )
+  .await
+  .unwrap();
+  delete_many(
+    db.collection("dispatcherleases"),
+    doc! { "_id": { "$in": [abandoned_sub, finished_sub] } },
+  )
+  .await
+  .unwrap();
+}
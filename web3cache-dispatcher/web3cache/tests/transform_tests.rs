@@ -0,0 +1,220 @@
+use bson::doc;
+use serde_json::json;
+use web3cache::dispatcher::BatchMetadata;
+use web3cache::transform::*;
+
+fn block() -> serde_json::Value {
+  json!({
+    "block_number": 10,
+    "event_name": "Transfer",
+    "block_hash": "0xabc",
+    "transactions": [
+      { "from": "0x1", "to": "0x2", "value": "5", "logs": [{ "topic": "a" }, { "topic": "b" }] },
+      { "from": "0x3", "to": "0x4", "value": "7", "logs": [] },
+    ],
+  })
+}
+
+#[test]
+fn test_parse_path() {
+  assert_eq!(JsonPath::parse("$").unwrap().segments, vec![]);
+  assert_eq!(JsonPath::parse(".").unwrap().segments, vec![]);
+  assert_eq!(
+    JsonPath::parse("$.transactions[*]['block-hash'][-1]")
+      .unwrap()
+      .segments,
+    vec![
+      PathSegment::Key("transactions".to_string()),
+      PathSegment::Wildcard,
+      PathSegment::Key("block-hash".to_string()),
+      PathSegment::Index(-1),
+    ]
+  );
+  assert_eq!(
+    JsonPath::parse(".transaction.value").unwrap(),
+    JsonPath::parse("$[\"transaction\"].value").unwrap()
+  );
+  for invalid in [
+    "", "value", "$value", "$..value", "$.", "$[", "$[x]", "$['a\"]",
+  ] {
+    assert!(JsonPath::parse(invalid).is_err(), "{:?} parsed", invalid);
+  }
+}
+
+// the same paths as test_validate_transform_paths_match_the_dispatcher in the subscriptions service,
+// whatever it accepts has to parse here
+const ACCEPTED_PATHS: [&str; 8] = [
+  "$",
+  ".",
+  ".block_number",
+  "$.transaction.logs[*]['topic']",
+  "$[\"a]b\"]",
+  "$['a]b'].c",
+  "$[\"it's\"][-1]",
+  "$['say \"hi\"'].*",
+];
+const REJECTED_PATHS: [&str; 4] = ["$[\"a\"b\"]", "$['a']b']", "$['a", "$[a]"];
+
+#[test]
+fn test_parse_paths_accepted_by_the_subscriptions_service() {
+  for path in ACCEPTED_PATHS {
+    assert!(JsonPath::parse(path).is_ok(), "{:?} did not parse", path);
+  }
+  for path in REJECTED_PATHS {
+    assert!(JsonPath::parse(path).is_err(), "{:?} parsed", path);
+  }
+  assert_eq!(
+    JsonPath::parse("$['a]b'].c").unwrap().segments,
+    vec![
+      PathSegment::Key("a]b".to_string()),
+      PathSegment::Key("c".to_string()),
+    ]
+  );
+}
+
+#[test]
+fn test_evaluate_path() {
+  let block = block();
+  let evaluate = |path: &str| JsonPath::parse(path).unwrap().evaluate(&block);
+  assert_eq!(evaluate("$.block_number"), json!(10));
+  assert_eq!(evaluate("$.transactions[-1].value"), json!("7"));
+  assert_eq!(evaluate("$.transactions[*].to"), json!(["0x2", "0x4"]));
+  assert_eq!(
+    evaluate("$.transactions[*].logs[*].topic"),
+    json!(["a", "b"])
+  );
+  assert_eq!(evaluate("$.transactions[5].value"), json!(null));
+  assert_eq!(evaluate("$.missing"), json!(null));
+  assert_eq!(evaluate("$.missing[*]"), json!([]));
+}
+
+#[test]
+fn test_flatten_block() {
+  let items = flatten_block(block());
+  assert_eq!(items.len(), 2);
+  assert_eq!(
+    items[1],
+    json!({
+      "block_number": 10,
+      "event_name": "Transfer",
+      "block_hash": "0xabc",
+      "transaction": { "from": "0x3", "to": "0x4", "value": "7", "logs": [] },
+    })
+  );
+
+  let retraction =
+    json!({ "block_number": 10, "event_name": "Transfer", "removed": true, "transactions": [] });
+  assert_eq!(
+    flatten_block(retraction),
+    vec![
+      json!({ "block_number": 10, "event_name": "Transfer", "removed": true, "transaction": null })
+    ]
+  );
+}
+
+#[test]
+fn test_transform_from_subscription() {
+  assert_eq!(Transform::from_subscription(&doc! {}).unwrap(), None);
+
+  let transform = Transform::from_subscription(&doc! {
+    "transform": { "fields": { "to": ".transaction.to" } },
+  })
+  .unwrap()
+  .unwrap();
+  assert!(!transform.flatten);
+  assert!(transform.envelope);
+  assert_eq!(
+    transform.fields,
+    Some(vec![(
+      "to".to_string(),
+      JsonPath::parse("$.transaction.to").unwrap()
+    )])
+  );
+
+  assert!(
+    Transform::from_subscription(&doc! { "transform": { "fields": { "to": "to" } } }).is_err()
+  );
+  assert!(Transform::from_subscription(&doc! { "transform": { "flatten": "yes" } }).is_err());
+}
+
+#[test]
+fn test_delivery_body() {
+  let batch = BatchMetadata {
+    sequence: 4,
+    replay: false,
+  };
+  assert_eq!(
+    delivery_body(vec![block()], None, "c", &batch),
+    json!({
+      "metadata": { "contract_id": "c", "sequence": 4, "replay": false },
+      "payload_count": 1,
+      "payload": [block()],
+    })
+  );
+
+  let transform = Transform {
+    flatten: true,
+    fields: Some(vec![
+      (
+        "block".to_string(),
+        JsonPath::parse("$.block_number").unwrap(),
+      ),
+      (
+        "to".to_string(),
+        JsonPath::parse("$.transaction.to").unwrap(),
+      ),
+    ]),
+    envelope: true,
+  };
+  assert_eq!(
+    delivery_body(vec![block()], Some(&transform), "c", &batch),
+    json!({
+      "metadata": { "contract_id": "c", "sequence": 4, "replay": false },
+      "payload_count": 2,
+      "payload": [{ "block": 10, "to": "0x2" }, { "block": 10, "to": "0x4" }],
+    })
+  );
+
+  let bare = Transform {
+    flatten: false,
+    fields: None,
+    envelope: false,
+  };
+  let mut bare_block = block();
+  bare_block["metadata"] = json!({ "sequence": 4, "replay": false });
+  assert_eq!(
+    delivery_body(vec![block()], Some(&bare), "c", &batch),
+    json!([bare_block])
+  );
+}
+
+#[test]
+fn test_delivery_body_keeps_retractions_and_batch_metadata() {
+  let batch = BatchMetadata {
+    sequence: 9,
+    replay: true,
+  };
+  let retraction = json!({
+    "block_number": 10,
+    "event_name": "Transfer",
+    "block_hash": "0xabc",
+    "removed": true,
+    "transactions": [],
+  });
+  let projection = Transform {
+    flatten: true,
+    fields: Some(vec![(
+      "block".to_string(),
+      JsonPath::parse("$.block_number").unwrap(),
+    )]),
+    envelope: false,
+  };
+  assert_eq!(
+    delivery_body(vec![retraction, block()], Some(&projection), "c", &batch),
+    json!([
+      { "block": 10, "removed": true, "metadata": { "sequence": 9, "replay": true } },
+      { "block": 10, "metadata": { "sequence": 9, "replay": true } },
+      { "block": 10, "metadata": { "sequence": 9, "replay": true } },
+    ])
+  );
+}
@@ -1,12 +1,14 @@
 use dotenv::dotenv;
 use futures::stream::TryStreamExt;
 use log::info;
-use mongodb::error::Error as MongoErr;
+use mongodb::error::{
+  Error as MongoErr, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
 use mongodb::options::{FindOptions, InsertManyOptions, InsertOneOptions, UpdateOptions};
 use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
 use mongodb::{bson::Document, options::FindOneOptions};
 use mongodb::{options::ClientOptions, Client};
-use mongodb::{Collection, Database};
+use mongodb::{ClientSession, Collection, Database};
 use std::env;
 
 pub async fn connect_to_mongodb(is_test_mongo: bool) -> mongodb::error::Result<Database> {
@@ -87,3 +89,58 @@ pub async fn insert_many(
 ) -> Result<InsertManyResult, MongoErr> {
   col.clone().insert_many(docs, option).await
 }
+
+pub async fn find_all_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  option: FindOptions,
+  session: &mut ClientSession,
+) -> Result<Vec<Document>, MongoErr> {
+  let mut cursor = col.find_with_session(filter, option, session).await?;
+  let results: Vec<Document> = cursor.stream(session).try_collect().await?;
+  Ok(results)
+}
+
+pub async fn insert_many_with_session(
+  col: Collection<Document>,
+  docs: &Vec<Document>,
+  option: InsertManyOptions,
+  session: &mut ClientSession,
+) -> Result<InsertManyResult, MongoErr> {
+  col.insert_many_with_session(docs, option, session).await
+}
+
+pub async fn delete_one_with_session(
+  col: Collection<Document>,
+  filter: Document,
+  session: &mut ClientSession,
+) -> Result<DeleteResult, MongoErr> {
+  col.delete_one_with_session(filter, None, session).await
+}
+
+pub async fn count_documents(col: Collection<Document>, filter: Document) -> Result<u64, MongoErr> {
+  col.count_documents(filter, None).await
+}
+
+// the whole transaction can be run again, e.g. after a write conflict with a concurrent redrive
+pub fn is_transient_transaction_error(err: &MongoErr) -> bool {
+  err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+// commits again while the outcome of the previous commit is unknown
+pub async fn commit_transaction(
+  session: &mut ClientSession,
+  max_attempts: usize,
+) -> Result<(), MongoErr> {
+  let mut result = session.commit_transaction().await;
+  for _ in 1..max_attempts {
+    if !result
+      .as_ref()
+      .is_err_and(|err| err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT))
+    {
+      break;
+    }
+    result = session.commit_transaction().await;
+  }
+  result
+}
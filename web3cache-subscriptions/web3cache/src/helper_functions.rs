@@ -1,11 +1,12 @@
 use actix_web::HttpRequest;
 use bson::{doc, Document};
 use mongodb::bson::DateTime;
-use std::env;
+use std::{collections::BTreeMap, env};
 use url::Url;
 
 use lazy_static::lazy_static;
 use mongodb::Database;
+use rand::RngCore;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use validator::{Validate, ValidationError};
@@ -58,6 +59,142 @@ pub struct Subscription {
     message = "Block number validation failed!"
   ))]
   pub block_number: Option<i64>,
+  #[validate]
+  pub delivery_policy: Option<DeliveryPolicy>,
+  #[validate(custom = "validate_signature_type")]
+  pub signature_type: Option<String>,
+  #[validate(custom = "validate_destination")]
+  pub destination: Option<Destination>,
+  #[validate(range(min = 0, max = 10000))]
+  pub confirmations: Option<i64>,
+  #[validate(custom = "validate_transform")]
+  pub transform: Option<Transform>,
+  #[validate(custom = "validate_encoding")]
+  pub encoding: Option<String>,
+  #[validate(custom = "validate_compression")]
+  pub compression: Option<String>,
+}
+
+// where the dispatcher delivers, the webhook `url` unless a broker destination is set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Destination {
+  Http,
+  Kafka { topic: String },
+  Nats { subject: String },
+  RedisStream { stream: String },
+}
+
+pub fn validate_destination(destination: &Destination) -> Result<(), ValidationError> {
+  lazy_static! {
+    static ref KAFKA_TOPIC_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9._-]{1,249}$").unwrap();
+    static ref NATS_SUBJECT_REGEX: Regex = Regex::new(r"^[^.*>\s]+(\.[^.*>\s]+)*$").unwrap();
+  }
+  match destination {
+    Destination::Http => Ok(()),
+    Destination::Kafka { topic } if !KAFKA_TOPIC_REGEX.is_match(topic) => {
+      Err(ValidationError::new(
+        "Kafka topics are 1 to 249 characters of a-z, A-Z, 0-9, '.', '_' and '-'",
+      ))
+    }
+    Destination::Nats { subject }
+      if subject.len() > 255 || !NATS_SUBJECT_REGEX.is_match(subject) =>
+    {
+      Err(ValidationError::new(
+        "NATS subjects are dot separated tokens without wildcards or whitespace",
+      ))
+    }
+    Destination::RedisStream { stream } if stream.is_empty() || stream.len() > 256 => Err(
+      ValidationError::new("Redis stream keys are 1 to 256 characters"),
+    ),
+    _ => Ok(()),
+  }
+}
+
+// reshapes what the dispatcher delivers, `fields` maps output keys to JSONPath expressions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub flatten: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fields: Option<BTreeMap<String, String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub envelope: Option<bool>,
+}
+
+pub fn validate_transform(transform: &Transform) -> Result<(), ValidationError> {
+  lazy_static! {
+    // `$`, `.key`, `.*`, `[0]`, `[-1]`, `[*]` and `['key']`, or jq's `.` and `.key...`
+    static ref PATH_REGEX: Regex = {
+      let key = r"\.[A-Za-z0-9_-]+|\.\*";
+      let segment = format!(r#"{key}|\[(-?[0-9]+|\*|"[^"]*"|'[^']*')\]"#);
+      Regex::new(&format!(r"^(\.|\$({segment})*|({key})({segment})*)$")).unwrap()
+    };
+  }
+  let fields = match &transform.fields {
+    Some(fields) => fields,
+    None => return Ok(()),
+  };
+  if fields.is_empty() || fields.len() > 100 {
+    return Err(ValidationError::new(
+      "Transform fields need between 1 and 100 entries",
+    ));
+  }
+  for (name, path) in fields {
+    if name.is_empty() || name.len() > 128 {
+      return Err(ValidationError::new(
+        "Transform field names are 1 to 128 characters",
+      ));
+    }
+    if path.len() > 256 || !PATH_REGEX.is_match(path) {
+      return Err(ValidationError::new(
+        "Transform fields are JSONPath expressions like '$.transaction.value' or '.block_number'",
+      ));
+    }
+  }
+  Ok(())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "validate_delivery_policy"))]
+pub struct DeliveryPolicy {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 1, max = 500))]
+  pub max_blocks: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 1024, max = 10485760))]
+  pub max_payload_bytes: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 1000, max = 60000))]
+  pub timeout_ms: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 10, max = 60000))]
+  pub backoff_base_ms: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 100, max = 3600000))]
+  pub backoff_cap_ms: Option<i64>,
+}
+
+pub fn validate_delivery_policy(policy: &DeliveryPolicy) -> Result<(), ValidationError> {
+  if let (Some(base), Some(cap)) = (policy.backoff_base_ms, policy.backoff_cap_ms) {
+    if cap < base {
+      return Err(ValidationError::new(
+        "backoff_cap_ms must be greater or equal to backoff_base_ms",
+      ));
+    }
+  }
+  Ok(())
+}
+
+// only the fields that were sent, as dotted keys so an update keeps the other ones
+pub fn delivery_policy_set_doc(policy: &DeliveryPolicy) -> Document {
+  let mut set_doc = doc! {};
+  let fields = bson::to_document(policy).unwrap_or_default();
+  for (key, value) in fields {
+    set_doc.insert(format!("delivery_policy.{}", key), value);
+  }
+  set_doc
 }
 
 /* enum chain_options {
@@ -160,11 +297,35 @@ pub struct UpdateSub {
   #[validate(length(min = 1), custom = "validate_vec_events")]
   pub set_topics: Option<Vec<String>>,
   pub activate: Option<bool>,
+  #[validate]
+  pub delivery_policy: Option<DeliveryPolicy>,
+  #[validate(custom = "validate_signature_type")]
+  pub signature_type: Option<String>,
+  #[validate(custom = "validate_destination")]
+  pub destination: Option<Destination>,
+  #[validate(range(min = 0, max = 10000))]
+  pub confirmations: Option<i64>,
+  #[validate(custom = "validate_transform")]
+  pub transform: Option<Transform>,
+  #[validate(custom = "validate_encoding")]
+  pub encoding: Option<String>,
+  #[validate(custom = "validate_compression")]
+  pub compression: Option<String>,
 }
 
 pub fn format_sub(mut subscription: Document, id: bson::oid::ObjectId) -> Document {
   subscription.insert("_id", id.to_hex());
   subscription.remove("apikey");
+  subscription.remove("secret");
+  subscription.remove("previous_secret");
+  subscription = format_dates(
+    subscription,
+    &[
+      "previous_secret_expires_at",
+      "failing_since",
+      "suspended_at",
+    ],
+  );
   subscription.insert(
     "createdAt",
     subscription
@@ -184,6 +345,76 @@ pub fn format_sub(mut subscription: Document, id: bson::oid::ObjectId) -> Docume
   subscription
 }
 
+const DEFAULT_SECRET_GRACE_PERIOD_SECS: i64 = 86400;
+
+pub fn generate_signing_secret() -> String {
+  let mut bytes = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  let secret: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+  format!("whsec_{}", secret)
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct RotateSecret {
+  #[validate(range(min = 0, max = 604800))]
+  pub grace_period_secs: Option<i64>,
+}
+
+impl RotateSecret {
+  pub fn grace_period_secs(&self) -> i64 {
+    self
+      .grace_period_secs
+      .unwrap_or(DEFAULT_SECRET_GRACE_PERIOD_SECS)
+  }
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 20;
+const MAX_PAGE_LIMIT: i64 = 100;
+
+#[derive(Serialize, Deserialize)]
+pub struct Pagination {
+  pub page: Option<u64>,
+  pub limit: Option<i64>,
+}
+
+impl Pagination {
+  pub fn skip_and_limit(&self) -> (u64, i64) {
+    let limit = self
+      .limit
+      .unwrap_or(DEFAULT_PAGE_LIMIT)
+      .clamp(1, MAX_PAGE_LIMIT);
+    let page = self.page.unwrap_or(1).max(1);
+    ((page - 1) * limit as u64, limit)
+  }
+}
+
+pub fn format_dates(mut document: Document, keys: &[&str]) -> Document {
+  for key in keys {
+    if let Ok(date) = document.get_datetime(key) {
+      let date = date.to_chrono().to_rfc3339();
+      document.insert(*key, date);
+    }
+  }
+  document
+}
+
+pub fn format_dead_letter(mut dead_letter: Document, include_blocks: bool) -> Document {
+  if let Ok(id) = dead_letter.get_object_id("_id") {
+    dead_letter.insert("_id", id.to_hex());
+  }
+  if !include_blocks {
+    dead_letter.remove("blocks");
+  }
+  format_dates(dead_letter, &["first_attempt_at", "dead_lettered_at"])
+}
+
+pub fn format_delivery(mut delivery: Document) -> Document {
+  if let Ok(id) = delivery.get_object_id("_id") {
+    delivery.insert("_id", id.to_hex());
+  }
+  format_dates(delivery, &["created_at"])
+}
+
 pub fn validate_url(url: &str) -> Result<(), ValidationError> {
   if url.is_empty() {
     return Err(ValidationError::new("URL cannot be empty."));
@@ -204,6 +435,33 @@ pub fn validate_url(url: &str) -> Result<(), ValidationError> {
   Ok(())
 }
 
+pub fn validate_signature_type(signature_type: &str) -> Result<(), ValidationError> {
+  if !["jwt.light.v1", "hmac.sha256.v1"].contains(&signature_type) {
+    return Err(ValidationError::new(
+      "Supported signature types are: 'jwt.light.v1', 'hmac.sha256.v1'",
+    ));
+  }
+  Ok(())
+}
+
+pub fn validate_encoding(encoding: &str) -> Result<(), ValidationError> {
+  if !["json", "cbor", "msgpack"].contains(&encoding) {
+    return Err(ValidationError::new(
+      "Supported encodings are: 'json', 'cbor', 'msgpack'",
+    ));
+  }
+  Ok(())
+}
+
+pub fn validate_compression(compression: &str) -> Result<(), ValidationError> {
+  if !["none", "gzip", "zstd"].contains(&compression) {
+    return Err(ValidationError::new(
+      "Supported compressions are: 'none', 'gzip', 'zstd'",
+    ));
+  }
+  Ok(())
+}
+
 pub fn validate_block_number(block_number: i64) -> Result<(), ValidationError> {
   if block_number < 0 {
     return Err(ValidationError::new(
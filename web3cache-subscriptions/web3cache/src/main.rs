@@ -21,8 +21,10 @@ use crate::{
 
 use crate::subscription_api::{
   contract_invalidation, delete_subscription_from_subid, get_contract_from_id, get_contracts,
-  get_subscription_from_subid, get_subscriptions, replay_subscription, subscription_registration,
-  subscription_state, update_subscription, webhook_health_check,
+  get_dead_letter, get_dead_letters, get_deliveries, get_subscription_from_subid,
+  get_subscriptions, redrive_dead_letter, redrive_dead_letters, replay_subscription,
+  rotate_subscription_secret, subscription_registration, subscription_state, update_subscription,
+  webhook_health_check,
 };
 
 #[actix_web::main]
@@ -75,6 +77,30 @@ async fn main() -> anyhow::Result<()> {
             "/subscription/{sub_id}",
             web::get().to(get_subscription_from_subid),
           )
+          .route(
+            "/subscription/{sub_id}/deliveries",
+            web::get().to(get_deliveries),
+          )
+          .route(
+            "/subscription/{sub_id}/rotate-secret",
+            web::post().to(rotate_subscription_secret),
+          )
+          .route(
+            "/subscription/{sub_id}/deadletters",
+            web::get().to(get_dead_letters),
+          )
+          .route(
+            "/subscription/{sub_id}/deadletters/redrive",
+            web::post().to(redrive_dead_letters),
+          )
+          .route(
+            "/subscription/{sub_id}/deadletters/{deadletter_id}",
+            web::get().to(get_dead_letter),
+          )
+          .route(
+            "/subscription/{sub_id}/deadletters/{deadletter_id}/redrive",
+            web::post().to(redrive_dead_letter),
+          )
           .route(
             "/delete-subscription/{sub_id}",
             web::post().to(delete_subscription_from_subid),
@@ -4,7 +4,11 @@ use crate::{
     get_chain_address, get_chain_id, get_contract_abi_if_available,
     get_initial_block_number_by_contract_address,
   },
-  database::{create_entry, delete_one, find_all, find_one, insert_many, update_one},
+  database::{
+    commit_transaction, count_documents, create_entry, delete_one, delete_one_with_session,
+    find_all, find_all_with_session, find_one, insert_many, insert_many_with_session,
+    is_transient_transaction_error, update_one,
+  },
   helper_functions::*,
 };
 use actix_web::{
@@ -24,12 +28,15 @@ use bson::{oid::ObjectId, Bson, Document};
 use dotenv::dotenv;
 use mongodb::{
   bson::doc,
+  error::Error as MongoErr,
   options::{FindOneOptions, FindOptions, InsertManyOptions, InsertOneOptions, UpdateOptions},
-  Database,
+  ClientSession, Database,
 };
 
 use snailquote::unescape;
 
+const MAX_REDRIVE_TRANSACTION_ATTEMPTS: usize = 5;
+
 pub async fn webhook_health_check() -> HttpResponse {
   HttpResponse::Ok().body("web3cache subscriptions OK")
 }
@@ -74,7 +81,8 @@ pub async fn get_subscription_from_subid(
 
     let filter = doc! { "_id": object_id, "apikey": api_key };
     let mut find_option = FindOneOptions::default();
-    find_option.projection = Some(doc! { "apikey": 0, "secret": 0, "__v": 0  });
+    find_option.projection =
+      Some(doc! { "apikey": 0, "secret": 0, "previous_secret": 0, "__v": 0  });
 
     let subscription = find_one(data.db.collection("subscriptions"), filter, find_option)
       .await
@@ -100,6 +108,15 @@ pub async fn get_subscription_from_subid(
           .to_chrono()
           .to_rfc3339(),
       );
+      match pending_blocks(&data.db, &sub).await {
+        Ok(pending) => response.insert("pending", pending),
+        Err(err) => {
+          custom_error!("ERROR: {:?}", err);
+          return HttpResponse::InternalServerError().json(json!({
+            "message": "Internal error, we were not able to count the pending blocks"
+          }));
+        }
+      };
       HttpResponse::Ok().json(response)
     } else {
       //crate::custom_info!("Subscriptions: {:?}", subscription);
@@ -174,7 +191,8 @@ pub async fn replay_subscription(
     let filter = doc! { "_id": object_id, "apikey": api_key };
 
     let mut find_option = FindOneOptions::default();
-    find_option.projection = Some(doc! { "apikey": 0, "secret": 0, "__v": 0  });
+    find_option.projection =
+      Some(doc! { "apikey": 0, "secret": 0, "previous_secret": 0, "__v": 0  });
 
     let subscription = find_one(data.db.collection("subscriptions"), filter, find_option)
       .await
@@ -268,6 +286,7 @@ async fn get_history_block_number(
       full_content.push(doc! {
           "subid": subid.clone(),
           "locked_until": locked_until,
+          "replay": true,
           "block_number": send_transactions[0]["block_number"].as_i64(),
           "event_name": send_transactions[0]["event_name"].as_str(),
           "transactions": send_transactions
@@ -287,6 +306,7 @@ async fn get_history_block_number(
     full_content.push(doc! {
         "subid": subid,
         "locked_until": locked_until,
+        "replay": true,
         "block_number": send_transactions[0]["block_number"].as_i64(),
         "event_name": send_transactions[0]["event_name"].as_str(),
         "transactions": send_transactions
@@ -321,7 +341,8 @@ pub async fn get_subscriptions(req: HttpRequest, data: Data<AppState>) -> HttpRe
     let filter = doc! { "apikey": api_key };
 
     let mut find_option = FindOptions::default();
-    find_option.projection = Some(doc! { "apikey": 0, "secret": 0, "__v": 0  });
+    find_option.projection =
+      Some(doc! { "apikey": 0, "secret": 0, "previous_secret": 0, "__v": 0  });
 
     let subscriptions = find_all(data.db.collection("subscriptions"), filter, find_option)
       .await
@@ -336,7 +357,7 @@ pub async fn get_subscriptions(req: HttpRequest, data: Data<AppState>) -> HttpRe
       .unwrap()
       .to_chrono()
       .to_rfc3339()
-      .to_string(),})
+      .to_string(), "suspended_reason": sub.get_str("suspended_reason").ok()})
     }
     if result.is_empty() {
       HttpResponse::Ok().json(json!({ "message": "No subscription found" }))
@@ -869,7 +890,32 @@ pub async fn subscription_registration(
 
       let utc = Utc::now();
       let bson_date = Bson::from(utc);
-      let mut subscription = doc! {"contract_id" : contract_id , "topics": topics , "apikey":api_key , "isActive":true , "url":url , "createdAt":bson_date.clone() , "updatedAt":bson_date};
+      let secret = generate_signing_secret();
+      let mut subscription = doc! {"contract_id" : contract_id , "topics": topics , "apikey":api_key , "secret": &secret , "isActive":true , "url":url , "createdAt":bson_date.clone() , "updatedAt":bson_date};
+      if let Some(delivery_policy) = &body.delivery_policy {
+        subscription.insert(
+          "delivery_policy",
+          bson::to_document(delivery_policy).unwrap(),
+        );
+      }
+      if let Some(signature_type) = &body.signature_type {
+        subscription.insert("signature_type", signature_type);
+      }
+      if let Some(destination) = &body.destination {
+        subscription.insert("destination", bson::to_bson(destination).unwrap());
+      }
+      if let Some(confirmations) = body.confirmations {
+        subscription.insert("confirmations", confirmations);
+      }
+      if let Some(transform) = &body.transform {
+        subscription.insert("transform", bson::to_document(transform).unwrap());
+      }
+      if let Some(encoding) = &body.encoding {
+        subscription.insert("encoding", encoding);
+      }
+      if let Some(compression) = &body.compression {
+        subscription.insert("compression", compression);
+      }
       let register_sub_result = create_entry(
         data.db.collection("subscriptions"),
         subscription.clone(),
@@ -890,7 +936,8 @@ pub async fn subscription_registration(
             .json(json!({"message":"Internal error, we were not able to restart the block number"}))
         } else {
           let id: bson::oid::ObjectId = register_sub_result.inserted_id.as_object_id().unwrap();
-          let subscription = format_sub(subscription, id);
+          let mut subscription = format_sub(subscription, id);
+          subscription.insert("secret", secret);
 
           HttpResponse::Ok()
             .content_type("application/json")
@@ -898,7 +945,9 @@ pub async fn subscription_registration(
         }
       } else {
         let id: bson::oid::ObjectId = register_sub_result.inserted_id.as_object_id().unwrap();
-        let subscription = format_sub(subscription, id);
+        let mut subscription = format_sub(subscription, id);
+        // the signing secret is only ever returned here and on rotation
+        subscription.insert("secret", secret);
 
         //crate::custom_info!("{:?}", register_sub_result.inserted_id);
         //subscription.extend(doc! {"_id":});
@@ -957,6 +1006,28 @@ pub async fn update_subscription(
       } else {
         set_object.extend(doc! {"isActive":activate})
       }
+      if let Some(delivery_policy) = &body.delivery_policy {
+        set_object.extend(delivery_policy_set_doc(delivery_policy));
+      }
+      if let Some(signature_type) = &body.signature_type {
+        set_object.insert("signature_type", signature_type);
+      }
+      if let Some(destination) = &body.destination {
+        set_object.insert("destination", bson::to_bson(destination).unwrap());
+      }
+      if let Some(confirmations) = body.confirmations {
+        set_object.insert("confirmations", confirmations);
+      }
+      // replaces the whole transform, `{}` turns it off
+      if let Some(transform) = &body.transform {
+        set_object.insert("transform", bson::to_document(transform).unwrap());
+      }
+      if let Some(encoding) = &body.encoding {
+        set_object.insert("encoding", encoding);
+      }
+      if let Some(compression) = &body.compression {
+        set_object.insert("compression", compression);
+      }
       if body.set_topics.is_some() && !body.set_topics.as_ref().unwrap().is_empty() {
         let set_topics = body.set_topics.as_ref().unwrap();
         set_object.extend(doc! {"topics":set_topics});
@@ -1004,6 +1075,9 @@ pub async fn update_subscription(
         .await
         .unwrap();
       }
+      if activate {
+        clear_suspension(&data.db, object_id).await;
+      }
       let mut subscription = find_one(
         data.db.collection("subscriptions"),
         doc! {"_id":object_id , "apikey": api_key},
@@ -1064,8 +1138,15 @@ pub async fn subscription_state(
       )
       .await
       .unwrap();
+      let mut subscription = subscription.unwrap();
+      if state {
+        clear_suspension(&data.db, object_id).await;
+        subscription.remove("suspended_reason");
+        subscription.remove("suspended_at");
+        subscription.remove("failing_since");
+      }
 
-      let mut sub_result = format_sub(subscription.unwrap(), object_id);
+      let mut sub_result = format_sub(subscription, object_id);
       sub_result.insert("isActive", state);
       HttpResponse::Ok()
         .content_type("application/json")
@@ -1077,3 +1158,497 @@ pub async fn subscription_state(
     HttpResponse::BadRequest().json(json!({"message":"Invalid api key"}))
   }
 }
+
+// queued blocks, and how many of them still wait for enough confirmations
+async fn pending_blocks(db: &Database, subscription: &Document) -> anyhow::Result<Document> {
+  let sub_id = subscription.get_object_id("_id")?.to_string();
+  let blocks = count_documents(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+  )
+  .await?;
+  let mut pending = doc! { "blocks": blocks as i64 };
+
+  let confirmations = subscription.get_i64("confirmations").unwrap_or(0);
+  if confirmations > 0 {
+    let head_block = find_one(
+      db.collection("chainheads"),
+      doc! { "contract_id": subscription.get_str("contract_id").unwrap_or_default() },
+      FindOneOptions::default(),
+    )
+    .await?
+    .and_then(|head| head.get_i64("head_block").ok());
+    let mut unconfirmed_filter = doc! { "subid": &sub_id, "removed": { "$ne": true } };
+    if let Some(head_block) = head_block {
+      unconfirmed_filter.insert("block_number", doc! { "$gt": head_block - confirmations });
+    }
+    let unconfirmed =
+      count_documents(db.collection("transactionblocks"), unconfirmed_filter).await?;
+    pending.insert("unconfirmed", unconfirmed as i64);
+    pending.insert("head_block", head_block);
+  }
+  Ok(pending)
+}
+
+// the dispatcher suspends subscriptions that keep failing, reactivating starts a fresh failure window
+async fn clear_suspension(db: &Database, object_id: ObjectId) {
+  update_one(
+    db.collection("subscriptions"),
+    doc! {"_id": object_id},
+    doc! {"$unset": {"suspended_reason": "", "suspended_at": "", "failing_since": ""}},
+    UpdateOptions::default(),
+  )
+  .await
+  .unwrap();
+}
+
+async fn find_owned_subscription(
+  db: &Database,
+  sub_id: String,
+  api_key: &str,
+) -> Result<Document, HttpResponse> {
+  let object_id = match ObjectId::parse_str(sub_id) {
+    Ok(object_id) => object_id,
+    Err(_) => {
+      return Err(HttpResponse::BadRequest().json(json!({
+        "message": "invalid sub_id"
+      })))
+    }
+  };
+  let mut find_option = FindOneOptions::default();
+  find_option.projection = Some(doc! { "apikey": 0, "secret": 0, "previous_secret": 0, "__v": 0  });
+
+  match find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": object_id, "apikey": api_key },
+    find_option,
+  )
+  .await
+  .unwrap()
+  {
+    Some(subscription) => Ok(subscription),
+    None => Err(HttpResponse::NotFound().json(json!({
+      "message": "Subscription not found"
+    }))),
+  }
+}
+
+// deletes the dead letter and queues its blocks again, None when it is gone already
+pub async fn redrive_dead_letter_with_session(
+  db: &Database,
+  session: &mut ClientSession,
+  dead_letter: &Document,
+) -> anyhow::Result<Option<u64>> {
+  let deleted = delete_one_with_session(
+    db.collection("deadletters"),
+    doc! { "_id": dead_letter.get_object_id("_id")? },
+    session,
+  )
+  .await?;
+  if deleted.deleted_count == 0 {
+    return Ok(None);
+  }
+
+  let locked_until = bson::DateTime::now();
+  let blocks: Vec<Document> = dead_letter
+    .get_array("blocks")?
+    .iter()
+    .filter_map(|block| block.as_document())
+    .map(|block| {
+      let mut block = block.clone();
+      // dead letters from before sequences were stripped, a fresh one keeps the nonce increasing
+      block.remove("sequence");
+      block.insert("locked_until", locked_until);
+      block
+    })
+    .collect();
+  if blocks.is_empty() {
+    return Ok(Some(0));
+  }
+
+  // blocks that are already queued again do not need to be redriven, and a duplicate key error
+  // would abort the transaction
+  let block_numbers: Vec<&Bson> = blocks
+    .iter()
+    .filter_map(|block| block.get("block_number"))
+    .collect();
+  let queued = find_all_with_session(
+    db.collection("transactionblocks"),
+    doc! {
+      "subid": dead_letter.get_str("subid").unwrap_or_default(),
+      "block_number": { "$in": block_numbers },
+    },
+    FindOptions::default(),
+    session,
+  )
+  .await?;
+  let fresh: Vec<Document> = blocks
+    .iter()
+    .filter(|block| {
+      !queued.iter().any(|queued| {
+        ["block_number", "event_name", "removed"]
+          .iter()
+          .all(|key| queued.get(*key) == block.get(*key))
+      })
+    })
+    .cloned()
+    .collect();
+  if !fresh.is_empty() {
+    insert_many_with_session(
+      db.collection("transactionblocks"),
+      &fresh,
+      InsertManyOptions::default(),
+      session,
+    )
+    .await?;
+  }
+  Ok(Some(blocks.len() as u64))
+}
+
+// the blocks are queued and the dead letter deleted together, or neither happens
+pub async fn redrive_dead_letter_doc(
+  db: &Database,
+  dead_letter: &Document,
+) -> anyhow::Result<Option<u64>> {
+  let mut session = db
+    .collection::<Document>("deadletters")
+    .client()
+    .start_session(None)
+    .await?;
+  let mut attempt = 1;
+  loop {
+    session.start_transaction(None).await?;
+    let err = match redrive_dead_letter_with_session(db, &mut session, dead_letter).await {
+      Ok(None) => {
+        session.abort_transaction().await?;
+        return Ok(None);
+      }
+      Ok(redriven) => {
+        match commit_transaction(&mut session, MAX_REDRIVE_TRANSACTION_ATTEMPTS).await {
+          Ok(()) => return Ok(redriven),
+          Err(err) => anyhow::Error::from(err),
+        }
+      }
+      Err(err) => {
+        // the server may have aborted the transaction already
+        _ = session.abort_transaction().await;
+        err
+      }
+    };
+    let transient = err
+      .downcast_ref::<MongoErr>()
+      .is_some_and(is_transient_transaction_error);
+    if !transient || attempt >= MAX_REDRIVE_TRANSACTION_ATTEMPTS {
+      return Err(err);
+    }
+    attempt += 1;
+  }
+}
+
+async fn redrive_dead_letter_docs(db: &Database, filter: Document) -> anyhow::Result<(u64, u64)> {
+  let dead_letters = find_all(db.collection("deadletters"), filter, FindOptions::default()).await?;
+  let mut redriven_batches = 0;
+  let mut redriven_blocks = 0;
+
+  for dead_letter in dead_letters {
+    // a concurrent redrive took it already
+    if let Some(blocks) = redrive_dead_letter_doc(db, &dead_letter).await? {
+      redriven_batches += 1;
+      redriven_blocks += blocks;
+    }
+  }
+  Ok((redriven_batches, redriven_blocks))
+}
+
+pub async fn get_dead_letters(
+  req: HttpRequest,
+  path: web::Path<String>,
+  query: web::Query<Pagination>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let subscription = match find_owned_subscription(&data.db, path.into_inner(), api_key).await {
+      Ok(subscription) => subscription,
+      Err(response) => return response,
+    };
+    let filter = doc! { "subid": subscription.get_object_id("_id").unwrap().to_hex() };
+    let (skip, limit) = query.skip_and_limit();
+
+    let mut find_option = FindOptions::default();
+    find_option.sort = Some(doc! { "dead_lettered_at": -1 });
+    find_option.skip = Some(skip);
+    find_option.limit = Some(limit);
+
+    let total = count_documents(data.db.collection("deadletters"), filter.clone())
+      .await
+      .unwrap();
+    let dead_letters: Vec<Document> =
+      find_all(data.db.collection("deadletters"), filter, find_option)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|dead_letter| format_dead_letter(dead_letter, false))
+        .collect();
+
+    HttpResponse::Ok().json(json!({
+      "deadletters": dead_letters,
+      "page": query.page.unwrap_or(1).max(1),
+      "limit": limit,
+      "total": total
+    }))
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
+
+pub async fn get_deliveries(
+  req: HttpRequest,
+  path: web::Path<String>,
+  query: web::Query<Pagination>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let subscription = match find_owned_subscription(&data.db, path.into_inner(), api_key).await {
+      Ok(subscription) => subscription,
+      Err(response) => return response,
+    };
+    let filter = doc! { "subid": subscription.get_object_id("_id").unwrap().to_hex() };
+    let (skip, limit) = query.skip_and_limit();
+
+    let mut find_option = FindOptions::default();
+    find_option.sort = Some(doc! { "created_at": -1 });
+    find_option.skip = Some(skip);
+    find_option.limit = Some(limit);
+
+    let total = count_documents(data.db.collection("deliveries"), filter.clone())
+      .await
+      .unwrap();
+    let deliveries: Vec<Document> = find_all(data.db.collection("deliveries"), filter, find_option)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(format_delivery)
+      .collect();
+
+    HttpResponse::Ok().json(json!({
+      "deliveries": deliveries,
+      "page": query.page.unwrap_or(1).max(1),
+      "limit": limit,
+      "total": total
+    }))
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
+
+pub async fn get_dead_letter(
+  req: HttpRequest,
+  path: web::Path<(String, String)>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let (sub_id, dead_letter_id) = path.into_inner();
+    let subscription = match find_owned_subscription(&data.db, sub_id, api_key).await {
+      Ok(subscription) => subscription,
+      Err(response) => return response,
+    };
+    let dead_letter_id = match ObjectId::parse_str(dead_letter_id) {
+      Ok(object_id) => object_id,
+      Err(_) => {
+        return HttpResponse::BadRequest().json(json!({
+          "message": "invalid deadletter_id"
+        }))
+      }
+    };
+
+    let dead_letter = find_one(
+      data.db.collection("deadletters"),
+      doc! { "_id": dead_letter_id, "subid": subscription.get_object_id("_id").unwrap().to_hex() },
+      FindOneOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    match dead_letter {
+      Some(dead_letter) => HttpResponse::Ok().json(format_dead_letter(dead_letter, true)),
+      None => HttpResponse::NotFound().json(json!({
+        "message": "Deadletter not found"
+      })),
+    }
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
+
+pub async fn redrive_dead_letter(
+  req: HttpRequest,
+  path: web::Path<(String, String)>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let (sub_id, dead_letter_id) = path.into_inner();
+    let subscription = match find_owned_subscription(&data.db, sub_id, api_key).await {
+      Ok(subscription) => subscription,
+      Err(response) => return response,
+    };
+    let dead_letter_id = match ObjectId::parse_str(dead_letter_id) {
+      Ok(object_id) => object_id,
+      Err(_) => {
+        return HttpResponse::BadRequest().json(json!({
+          "message": "invalid deadletter_id"
+        }))
+      }
+    };
+
+    let filter =
+      doc! { "_id": dead_letter_id, "subid": subscription.get_object_id("_id").unwrap().to_hex() };
+    match redrive_dead_letter_docs(&data.db, filter).await {
+      Ok((0, _)) => HttpResponse::NotFound().json(json!({
+        "message": "Deadletter not found"
+      })),
+      Ok((_, blocks)) => HttpResponse::Ok().json(json!({
+        "message": "Ok",
+        "redriven_blocks": blocks
+      })),
+      Err(err) => {
+        custom_error!("ERROR: {:?}", err);
+        HttpResponse::InternalServerError().json(json!({
+          "message": "Internal error, we were not able to redrive the deadletter"
+        }))
+      }
+    }
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
+
+pub async fn redrive_dead_letters(
+  req: HttpRequest,
+  path: web::Path<String>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let subscription = match find_owned_subscription(&data.db, path.into_inner(), api_key).await {
+      Ok(subscription) => subscription,
+      Err(response) => return response,
+    };
+
+    let filter = doc! { "subid": subscription.get_object_id("_id").unwrap().to_hex() };
+    match redrive_dead_letter_docs(&data.db, filter).await {
+      Ok((batches, blocks)) => HttpResponse::Ok().json(json!({
+        "message": "Ok",
+        "redriven_batches": batches,
+        "redriven_blocks": blocks
+      })),
+      Err(err) => {
+        custom_error!("ERROR: {:?}", err);
+        HttpResponse::InternalServerError().json(json!({
+          "message": "Internal error, we were not able to redrive the deadletters"
+        }))
+      }
+    }
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
+
+pub async fn rotate_subscription_secret(
+  req: HttpRequest,
+  path: web::Path<String>,
+  body: Option<web::Json<RotateSecret>>,
+  data: Data<AppState>,
+) -> HttpResponse {
+  if let Some(api_key) = get_api_key(&req) {
+    let rotate = body.map(|body| body.into_inner()).unwrap_or(RotateSecret {
+      grace_period_secs: None,
+    });
+    if let Err(err) = rotate.validate() {
+      return HttpResponse::BadRequest().json(err);
+    }
+    let object_id = match ObjectId::parse_str(path.into_inner()) {
+      Ok(object_id) => object_id,
+      Err(_) => {
+        return HttpResponse::BadRequest().json(json!({
+          "message": "invalid sub_id"
+        }))
+      }
+    };
+    let filter = doc! { "_id": object_id, "apikey": api_key };
+    let subscription = match find_one(
+      data.db.collection("subscriptions"),
+      filter.clone(),
+      FindOneOptions::default(),
+    )
+    .await
+    .unwrap()
+    {
+      Some(subscription) => subscription,
+      None => {
+        return HttpResponse::NotFound().json(json!({
+          "message": "Subscription not found"
+        }))
+      }
+    };
+
+    // the old secret keeps signing deliveries next to the new one until the grace period ends,
+    // subscriptions from before signing secrets were signed with their api key so far
+    let secret = generate_signing_secret();
+    let now = bson::DateTime::now();
+    let grace_period_secs = rotate.grace_period_secs();
+    let update = match subscription
+      .get_str("secret")
+      .or_else(|_| subscription.get_str("apikey"))
+    {
+      Ok(previous_secret) if grace_period_secs > 0 => {
+        let expires_at =
+          bson::DateTime::from_millis(now.timestamp_millis() + grace_period_secs * 1000);
+        doc! {
+          "$set": {
+            "secret": &secret,
+            "previous_secret": previous_secret,
+            "previous_secret_expires_at": expires_at,
+            "updatedAt": now,
+          }
+        }
+      }
+      _ => doc! {
+        "$set": { "secret": &secret, "updatedAt": now },
+        "$unset": { "previous_secret": "", "previous_secret_expires_at": "" },
+      },
+    };
+    update_one(
+      data.db.collection("subscriptions"),
+      filter,
+      update.clone(),
+      UpdateOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let previous_secret_expires_at = update
+      .get_document("$set")
+      .unwrap()
+      .get_datetime("previous_secret_expires_at")
+      .ok()
+      .map(|date| date.to_chrono().to_rfc3339());
+    HttpResponse::Ok().json(json!({
+      "_id": object_id.to_hex(),
+      "secret": secret,
+      "previous_secret_expires_at": previous_secret_expires_at
+    }))
+  } else {
+    HttpResponse::BadRequest().json(json!({
+      "message": "missing x-webhook-api-key"
+    }))
+  }
+}
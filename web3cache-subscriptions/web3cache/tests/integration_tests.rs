@@ -4,16 +4,18 @@ use actix_web::{dev::ServiceResponse};
 use actix_web::{http::StatusCode, test, web, App};
 use bson::doc;
 use bson::oid::ObjectId;
-use mongodb::options::{FindOneOptions, InsertOneOptions};
+use mongodb::options::{FindOneOptions, FindOptions, InsertOneOptions, UpdateOptions};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use web3cache::database::*;
 use web3cache::helper_functions::AppState;
 use web3cache::subscription_api::{
   contract_invalidation, contract_registration, delete_subscription_from_subid,
-  get_contract_from_id, get_contracts, get_subscription_from_subid, get_subscriptions,
-  replay_subscription, subscription_registration, subscription_state,
-  update_subscription, webhook_health_check,
+  get_contract_from_id, get_contracts, get_dead_letter, get_dead_letters, get_deliveries,
+  get_subscription_from_subid, get_subscriptions, redrive_dead_letter, redrive_dead_letter_doc,
+  redrive_dead_letter_with_session, redrive_dead_letters, replay_subscription,
+  rotate_subscription_secret, subscription_registration, subscription_state, update_subscription,
+  webhook_health_check,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1239,4 +1241,664 @@ async fn contract_invalidation_contract_not_modified() {
     response_body["message"],
     "Contract non_existent_contract did not exist or was not modified"
   );
-}
\ No newline at end of file
+}
+async fn insert_test_subscription(api_key: &str, contract_id: &str) -> String {
+  let db = connect_to_mongodb(true).await.unwrap();
+  let now = bson::DateTime::now();
+  create_entry(
+    db.collection("subscriptions"),
+    doc! {
+        "contract_id": contract_id,
+        "topics": [],
+        "apikey": api_key,
+        "isActive": true,
+        "url": "https://webhook.site/test",
+        "createdAt": now,
+        "updatedAt": now,
+    },
+    InsertOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .inserted_id
+  .as_object_id()
+  .unwrap()
+  .to_hex()
+}
+
+async fn insert_test_dead_letter(sub_id: &str, block_number: i64) -> String {
+  let db = connect_to_mongodb(true).await.unwrap();
+  let now = bson::DateTime::now();
+  create_entry(
+    db.collection("deadletters"),
+    doc! {
+        "subid": sub_id,
+        "contract_id": "test_dead_letters",
+        "block_from": block_number,
+        "block_to": block_number,
+        "block_count": 1i64,
+        "attempts": 20i64,
+        "last_error": "webhook responded with HTTP 500 Internal Server Error",
+        "first_attempt_at": now,
+        "dead_lettered_at": now,
+        "blocks": [{
+            "subid": sub_id,
+            "block_number": block_number,
+            "event_name": "Transfer",
+            "sequence": 3i64,
+            "transactions": [{ "transaction_id": "tx1", "block_number": block_number, "event_name": "Transfer" }],
+        }],
+    },
+    InsertOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .inserted_id
+  .as_object_id()
+  .unwrap()
+  .to_hex()
+}
+
+async fn cleanup_test_subscription(sub_id: &str) {
+  let db = connect_to_mongodb(true).await.unwrap();
+  let object_id = ObjectId::parse_str(sub_id).unwrap();
+  delete_one(db.collection("subscriptions"), doc! { "_id": object_id })
+    .await
+    .unwrap();
+  db.collection::<bson::Document>("deadletters")
+    .delete_many(doc! { "subid": sub_id }, None)
+    .await
+    .unwrap();
+  db.collection::<bson::Document>("transactionblocks")
+    .delete_many(doc! { "subid": sub_id }, None)
+    .await
+    .unwrap();
+}
+
+#[actix_web::test]
+async fn get_dead_letters_success() {
+  let sub_id = insert_test_subscription("test_dead_letters", "test_dead_letters").await;
+  insert_test_dead_letter(&sub_id, 10).await;
+  let dead_letter_id = insert_test_dead_letter(&sub_id, 11).await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db }))
+      .route(
+        "/subscription/{sub_id}/deadletters",
+        web::get().to(get_dead_letters),
+      )
+      .route(
+        "/subscription/{sub_id}/deadletters/{deadletter_id}",
+        web::get().to(get_dead_letter),
+      ),
+  )
+  .await;
+
+  let req = test::TestRequest::get()
+    .uri(format!("/subscription/{}/deadletters?page=1&limit=1", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_dead_letters"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+  let body: serde_json::Value = test::read_body_json(response).await;
+  assert_eq!(body["total"], 2);
+  assert_eq!(body["deadletters"].as_array().unwrap().len(), 1);
+  assert!(body["deadletters"][0].get("blocks").is_none());
+
+  let req = test::TestRequest::get()
+    .uri(format!("/subscription/{}/deadletters/{}", sub_id, dead_letter_id).as_str())
+    .append_header(("x-webhook-api-key", "test_dead_letters"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+  let body: serde_json::Value = test::read_body_json(response).await;
+  assert_eq!(
+    body["last_error"],
+    "webhook responded with HTTP 500 Internal Server Error"
+  );
+  assert_eq!(body["blocks"].as_array().unwrap().len(), 1);
+
+  let req = test::TestRequest::get()
+    .uri(format!("/subscription/{}/deadletters", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "another_api_key"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+  cleanup_test_subscription(&sub_id).await;
+}
+
+#[actix_web::test]
+async fn redrive_dead_letters_success() {
+  let sub_id = insert_test_subscription("test_redrive_dead_letters", "test_dead_letters").await;
+  let dead_letter_id = insert_test_dead_letter(&sub_id, 20).await;
+  insert_test_dead_letter(&sub_id, 21).await;
+  insert_test_dead_letter(&sub_id, 22).await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/subscription/{sub_id}/deadletters/redrive",
+        web::post().to(redrive_dead_letters),
+      )
+      .route(
+        "/subscription/{sub_id}/deadletters/{deadletter_id}/redrive",
+        web::post().to(redrive_dead_letter),
+      ),
+  )
+  .await;
+
+  let req = test::TestRequest::post()
+    .uri(
+      format!(
+        "/subscription/{}/deadletters/{}/redrive",
+        sub_id, dead_letter_id
+      )
+      .as_str(),
+    )
+    .append_header(("x-webhook-api-key", "test_redrive_dead_letters"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+  let queued = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(queued.len(), 1);
+  assert_eq!(queued[0].get_i64("block_number").unwrap(), 20);
+  assert!(!queued[0].contains_key("sequence"));
+
+  // redriving the same deadletter twice is a not found
+  let req = test::TestRequest::post()
+    .uri(
+      format!(
+        "/subscription/{}/deadletters/{}/redrive",
+        sub_id, dead_letter_id
+      )
+      .as_str(),
+    )
+    .append_header(("x-webhook-api-key", "test_redrive_dead_letters"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription/{}/deadletters/redrive", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_redrive_dead_letters"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+  let body: serde_json::Value = test::read_body_json(response).await;
+  assert_eq!(body["redriven_batches"], 2);
+
+  let queued = find_all(
+    db.collection("transactionblocks"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(queued.len(), 3);
+  let remaining = find_all(
+    db.collection("deadletters"),
+    doc! { "subid": &sub_id },
+    FindOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert!(remaining.is_empty());
+
+  cleanup_test_subscription(&sub_id).await;
+}
+
+#[actix_web::test]
+async fn redrive_dead_letter_is_atomic() {
+  let sub_id = insert_test_subscription("test_redrive_atomic", "test_dead_letters").await;
+  let dead_letter_id = insert_test_dead_letter(&sub_id, 30).await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let dead_letter = find_one(
+    db.collection("deadletters"),
+    doc! { "_id": ObjectId::parse_str(&dead_letter_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  let queued_blocks = || async {
+    find_all(
+      db.collection("transactionblocks"),
+      doc! { "subid": &sub_id },
+      FindOptions::default(),
+    )
+    .await
+    .unwrap()
+    .len()
+  };
+
+  // a redrive that fails before committing leaves the dead letter and queues nothing
+  let mut session = db
+    .collection::<bson::Document>("deadletters")
+    .client()
+    .start_session(None)
+    .await
+    .unwrap();
+  session.start_transaction(None).await.unwrap();
+  assert_eq!(
+    redrive_dead_letter_with_session(&db, &mut session, &dead_letter)
+      .await
+      .unwrap(),
+    Some(1)
+  );
+  session.abort_transaction().await.unwrap();
+  assert_eq!(queued_blocks().await, 0);
+
+  assert_eq!(
+    redrive_dead_letter_doc(&db, &dead_letter).await.unwrap(),
+    Some(1)
+  );
+  assert_eq!(queued_blocks().await, 1);
+
+  // once delivered, a stale copy of the dead letter cannot queue its blocks again
+  db.collection::<bson::Document>("transactionblocks")
+    .delete_many(doc! { "subid": &sub_id }, None)
+    .await
+    .unwrap();
+  assert_eq!(
+    redrive_dead_letter_doc(&db, &dead_letter).await.unwrap(),
+    None
+  );
+  assert_eq!(queued_blocks().await, 0);
+
+  cleanup_test_subscription(&sub_id).await;
+}
+
+#[actix_web::test]
+async fn get_deliveries_success() {
+  let sub_id = insert_test_subscription("test_get_deliveries", "test_deliveries").await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  for block_number in 0..3i64 {
+    create_entry(
+      db.collection("deliveries"),
+      doc! {
+          "subid": &sub_id,
+          "contract_id": "test_deliveries",
+          "block_from": block_number,
+          "block_to": block_number,
+          "block_count": 1i64,
+          "attempt": 1i64,
+          "success": block_number != 1,
+          "status": if block_number == 1 { 500 } else { 200 },
+          "latency_ms": 42i64,
+          "response_excerpt": "ok",
+          "created_at": bson::DateTime::from_millis(1_700_000_000_000 + block_number),
+      },
+      InsertOneOptions::default(),
+    )
+    .await
+    .unwrap();
+  }
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/subscription/{sub_id}/deliveries",
+        web::get().to(get_deliveries),
+      ),
+  )
+  .await;
+
+  let req = test::TestRequest::get()
+    .uri(format!("/subscription/{}/deliveries?page=2&limit=2", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_get_deliveries"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+  let body: serde_json::Value = test::read_body_json(response).await;
+  assert_eq!(body["total"], 3);
+  assert_eq!(body["page"], 2);
+  // newest first, so the second page only holds the oldest delivery
+  let deliveries = body["deliveries"].as_array().unwrap();
+  assert_eq!(deliveries.len(), 1);
+  assert_eq!(deliveries[0]["block_from"], 0);
+  assert!(deliveries[0]["created_at"].is_string());
+
+  let req = test::TestRequest::get()
+    .uri("/subscription/invalid/deliveries")
+    .append_header(("x-webhook-api-key", "test_get_deliveries"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  db.collection::<bson::Document>("deliveries")
+    .delete_many(doc! { "subid": &sub_id }, None)
+    .await
+    .unwrap();
+  cleanup_test_subscription(&sub_id).await;
+}
+
+#[actix_web::test]
+async fn update_subscription_delivery_policy() {
+  let sub_id = insert_test_subscription("test_delivery_policy", "test_delivery_policy").await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/update-subscription/{sub_id}",
+        web::post().to(update_subscription),
+      ),
+  )
+  .await;
+
+  let payload = json!({
+    "delivery_policy": { "max_blocks": 10, "timeout_ms": 5000 },
+    "signature_type": "hmac.sha256.v1",
+    "destination": { "type": "kafka", "topic": "web3cache.events" },
+    "transform": { "flatten": true, "fields": { "amount": "$.transaction.value" } },
+    "encoding": "cbor",
+    "compression": "zstd"
+  });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+
+  // a later update keeps the fields it does not mention
+  let payload = json!({ "delivery_policy": { "backoff_base_ms": 500 } });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+
+  let invalid_payload = json!({ "delivery_policy": { "max_blocks": 0 } });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(invalid_payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let invalid_payload = json!({ "transform": { "fields": { "amount": "value" } } });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(invalid_payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let invalid_payload = json!({ "encoding": "xml" });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(invalid_payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let invalid_payload = json!({ "destination": { "type": "nats", "subject": "events.*" } });
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_delivery_policy"))
+    .set_json(invalid_payload)
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    doc! { "_id": ObjectId::parse_str(&sub_id).unwrap() },
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  let delivery_policy = subscription.get_document("delivery_policy").unwrap();
+  cleanup_test_subscription(&sub_id).await;
+  assert_eq!(delivery_policy.get_i64("max_blocks").unwrap(), 10);
+  assert_eq!(delivery_policy.get_i64("timeout_ms").unwrap(), 5000);
+  assert_eq!(delivery_policy.get_i64("backoff_base_ms").unwrap(), 500);
+  assert_eq!(
+    subscription.get_str("signature_type").unwrap(),
+    "hmac.sha256.v1"
+  );
+  assert_eq!(
+    subscription.get_document("destination").unwrap(),
+    &doc! { "type": "kafka", "topic": "web3cache.events" }
+  );
+  assert_eq!(
+    subscription.get_document("transform").unwrap(),
+    &doc! { "flatten": true, "fields": { "amount": "$.transaction.value" } }
+  );
+  assert_eq!(subscription.get_str("encoding").unwrap(), "cbor");
+  assert_eq!(subscription.get_str("compression").unwrap(), "zstd");
+}
+
+#[actix_web::test]
+async fn rotate_subscription_secret_success() {
+  let sub_id = insert_test_subscription("test_rotate_secret", "test_rotate_secret").await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/subscription/{sub_id}/rotate-secret",
+        web::post().to(rotate_subscription_secret),
+      ),
+  )
+  .await;
+  let filter = doc! { "_id": ObjectId::parse_str(&sub_id).unwrap() };
+
+  // a subscription without a secret gets its first one, the api key it was signed with so far
+  // keeps signing during the grace period
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription/{}/rotate-secret", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_rotate_secret"))
+    .to_request();
+  let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+  let first_secret = response["secret"].as_str().unwrap().to_string();
+  assert!(first_secret.starts_with("whsec_"));
+  assert!(response["previous_secret_expires_at"].is_string());
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    filter.clone(),
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(
+    subscription.get_str("previous_secret").unwrap(),
+    "test_rotate_secret"
+  );
+
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription/{}/rotate-secret", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_rotate_secret"))
+    .set_json(json!({ "grace_period_secs": 3600 }))
+    .to_request();
+  let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+  let second_secret = response["secret"].as_str().unwrap().to_string();
+  assert_ne!(first_secret, second_secret);
+  assert!(response["previous_secret_expires_at"].is_string());
+
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    filter.clone(),
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(subscription.get_str("secret").unwrap(), second_secret);
+  assert_eq!(
+    subscription.get_str("previous_secret").unwrap(),
+    first_secret
+  );
+
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription/{}/rotate-secret", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_rotate_secret"))
+    .set_json(json!({ "grace_period_secs": -1 }))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription/{}/rotate-secret", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "another_api_key"))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  cleanup_test_subscription(&sub_id).await;
+  assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn subscription_state_reactivates_suspended_subscription() {
+  let sub_id = insert_test_subscription("test_reactivate", "test_reactivate").await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let filter = doc! { "_id": ObjectId::parse_str(&sub_id).unwrap() };
+  update_one(
+    db.collection("subscriptions"),
+    filter.clone(),
+    doc! { "$set": {
+      "isActive": false,
+      "suspended_reason": "webhook deliveries failing continuously",
+      "suspended_at": bson::DateTime::now(),
+      "failing_since": bson::DateTime::now(),
+    } },
+    UpdateOptions::default(),
+  )
+  .await
+  .unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/subscription_state/{sub_id}",
+        web::post().to(subscription_state),
+      )
+      .route("/subscriptions", web::get().to(get_subscriptions)),
+  )
+  .await;
+
+  let req = test::TestRequest::get()
+    .uri("/subscriptions")
+    .append_header(("x-webhook-api-key", "test_reactivate"))
+    .to_request();
+  let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+  assert_eq!(response[0]["isActive"], false);
+  assert!(response[0]["suspended_reason"]
+    .as_str()
+    .unwrap()
+    .contains("failing continuously"));
+
+  let req = test::TestRequest::post()
+    .uri(format!("/subscription_state/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_reactivate"))
+    .set_json(json!({ "activate": true }))
+    .to_request();
+  let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+  assert_eq!(response["isActive"], true);
+  assert!(response.get("suspended_reason").is_none());
+
+  let subscription = find_one(
+    db.collection("subscriptions"),
+    filter,
+    FindOneOptions::default(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  cleanup_test_subscription(&sub_id).await;
+  assert!(subscription.get_bool("isActive").unwrap());
+  assert!(subscription.get("suspended_reason").is_none());
+  assert!(subscription.get("failing_since").is_none());
+}
+
+#[actix_web::test]
+async fn get_subscription_reports_unconfirmed_blocks() {
+  let sub_id = insert_test_subscription("test_confirmations", "test_confirmations").await;
+  let db = connect_to_mongodb(true).await.unwrap();
+  let app = test::init_service(
+    App::new()
+      .app_data(web::Data::new(AppState { db: db.clone() }))
+      .route(
+        "/update-subscription/{sub_id}",
+        web::post().to(update_subscription),
+      )
+      .route(
+        "/subscription/{sub_id}",
+        web::get().to(get_subscription_from_subid),
+      ),
+  )
+  .await;
+
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_confirmations"))
+    .set_json(json!({ "confirmations": 20000 }))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let req = test::TestRequest::post()
+    .uri(format!("/update-subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_confirmations"))
+    .set_json(json!({ "confirmations": 5 }))
+    .to_request();
+  let response = test::call_service(&app, req).await;
+  assert!(response.status().is_success());
+
+  let blocks = vec![
+    doc! { "subid": &sub_id, "block_number": 100_i64, "event_name": "Transfer", "transactions": [] },
+    doc! { "subid": &sub_id, "block_number": 103_i64, "event_name": "Transfer", "transactions": [] },
+  ];
+  insert_many(
+    db.collection("transactionblocks"),
+    &blocks,
+    Default::default(),
+  )
+  .await
+  .unwrap();
+  create_entry(
+    db.collection("chainheads"),
+    doc! { "contract_id": "test_confirmations", "head_block": 106_i64 },
+    InsertOneOptions::default(),
+  )
+  .await
+  .unwrap();
+
+  let req = test::TestRequest::get()
+    .uri(format!("/subscription/{}", sub_id).as_str())
+    .append_header(("x-webhook-api-key", "test_confirmations"))
+    .to_request();
+  let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+  db.collection::<bson::Document>("transactionblocks")
+    .delete_many(doc! { "subid": &sub_id }, None)
+    .await
+    .unwrap();
+  db.collection::<bson::Document>("chainheads")
+    .delete_many(doc! { "contract_id": "test_confirmations" }, None)
+    .await
+    .unwrap();
+  cleanup_test_subscription(&sub_id).await;
+  assert_eq!(response["confirmations"], 5);
+  assert_eq!(
+    response["pending"],
+    json!({ "blocks": 2, "unconfirmed": 1, "head_block": 106 })
+  );
+}
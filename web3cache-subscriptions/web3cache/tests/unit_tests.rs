@@ -236,3 +236,244 @@ async fn test_validate_vec_events_valid() {
 
   assert!(result.is_ok());
 }
+
+#[test]
+async fn test_pagination_skip_and_limit() {
+  let pagination = Pagination {
+    page: None,
+    limit: None,
+  };
+  assert_eq!(pagination.skip_and_limit(), (0, 20));
+
+  let pagination = Pagination {
+    page: Some(3),
+    limit: Some(10),
+  };
+  assert_eq!(pagination.skip_and_limit(), (20, 10));
+
+  let pagination = Pagination {
+    page: Some(0),
+    limit: Some(1000),
+  };
+  assert_eq!(pagination.skip_and_limit(), (0, 100));
+}
+
+#[test]
+async fn test_format_dead_letter() {
+  let id = ObjectId::new();
+  let dead_letter = doc! {
+    "_id": id,
+    "subid": "sub",
+    "dead_lettered_at": DateTime::now(),
+    "blocks": [{ "block_number": 1 }],
+  };
+
+  let formatted = format_dead_letter(dead_letter.clone(), false);
+  assert_eq!(formatted.get_str("_id").unwrap(), id.to_hex());
+  assert!(formatted.get_str("dead_lettered_at").is_ok());
+  assert!(formatted.get("blocks").is_none());
+
+  let formatted = format_dead_letter(dead_letter, true);
+  assert!(formatted.get_array("blocks").is_ok());
+}
+
+#[test]
+async fn test_validate_delivery_policy() {
+  use validator::Validate;
+
+  let policy = DeliveryPolicy {
+    max_blocks: Some(10),
+    max_payload_bytes: Some(65536),
+    timeout_ms: Some(5000),
+    backoff_base_ms: Some(200),
+    backoff_cap_ms: Some(60000),
+  };
+  assert!(policy.validate().is_ok());
+  assert!(DeliveryPolicy::default().validate().is_ok());
+
+  let policy = DeliveryPolicy {
+    max_blocks: Some(0),
+    ..Default::default()
+  };
+  assert!(policy.validate().is_err());
+
+  let policy = DeliveryPolicy {
+    timeout_ms: Some(120000),
+    ..Default::default()
+  };
+  assert!(policy.validate().is_err());
+
+  let policy = DeliveryPolicy {
+    backoff_base_ms: Some(5000),
+    backoff_cap_ms: Some(1000),
+    ..Default::default()
+  };
+  assert!(policy.validate().is_err());
+}
+
+#[test]
+async fn test_delivery_policy_set_doc() {
+  let policy = DeliveryPolicy {
+    max_blocks: Some(10),
+    timeout_ms: Some(5000),
+    ..Default::default()
+  };
+  assert_eq!(
+    delivery_policy_set_doc(&policy),
+    doc! { "delivery_policy.max_blocks": 10_i64, "delivery_policy.timeout_ms": 5000_i64 }
+  );
+  assert!(delivery_policy_set_doc(&DeliveryPolicy::default()).is_empty());
+}
+
+#[test]
+async fn test_validate_signature_type() {
+  assert!(validate_signature_type("jwt.light.v1").is_ok());
+  assert!(validate_signature_type("hmac.sha256.v1").is_ok());
+  assert!(validate_signature_type("hmac.sha1.v1").is_err());
+  assert!(validate_signature_type("").is_err());
+}
+
+#[test]
+async fn test_validate_encoding_and_compression() {
+  for encoding in ["json", "cbor", "msgpack"] {
+    assert!(validate_encoding(encoding).is_ok());
+  }
+  assert!(validate_encoding("JSON").is_err());
+  assert!(validate_encoding("").is_err());
+  for compression in ["none", "gzip", "zstd"] {
+    assert!(validate_compression(compression).is_ok());
+  }
+  assert!(validate_compression("brotli").is_err());
+}
+
+#[test]
+async fn test_generate_signing_secret() {
+  let secret = generate_signing_secret();
+  assert!(secret.starts_with("whsec_"));
+  assert_eq!(secret.len(), 6 + 64);
+  assert_ne!(secret, generate_signing_secret());
+}
+
+#[test]
+async fn test_format_sub_hides_secrets() {
+  let id = ObjectId::new();
+  let subscription = doc! {
+    "apikey": "apikey",
+    "secret": "whsec_new",
+    "previous_secret": "whsec_old",
+    "previous_secret_expires_at": DateTime::now(),
+    "suspended_reason": "webhook deliveries failing continuously",
+    "suspended_at": DateTime::now(),
+    "createdAt": DateTime::now(),
+    "updatedAt": DateTime::now(),
+  };
+  let formatted = format_sub(subscription, id);
+  assert!(formatted.get("apikey").is_none());
+  assert!(formatted.get("secret").is_none());
+  assert!(formatted.get("previous_secret").is_none());
+  assert!(formatted.get_str("previous_secret_expires_at").is_ok());
+  assert!(formatted.get_str("suspended_at").is_ok());
+  assert!(formatted.get_str("suspended_reason").is_ok());
+}
+
+#[test]
+async fn test_validate_transform() {
+  let transform: Transform = serde_json::from_value(serde_json::json!({
+    "flatten": true,
+    "fields": {
+      "amount": "$.transaction.value",
+      "block": ".block_number",
+      "topics": "$.transaction.logs[*]['topic']",
+      "last": "$.transaction.logs[-1]",
+      "item": "."
+    },
+    "envelope": false
+  }))
+  .unwrap();
+  assert!(validate_transform(&transform).is_ok());
+  assert!(validate_transform(&Transform::default()).is_ok());
+
+  for path in ["value", "$value", "$..value", "$.", "$[x]", "[0]", "$.a b"] {
+    let transform = Transform {
+      fields: Some([("field".to_string(), path.to_string())].into()),
+      ..Default::default()
+    };
+    assert!(validate_transform(&transform).is_err(), "{:?} passed", path);
+  }
+  let transform = Transform {
+    fields: Some(Default::default()),
+    ..Default::default()
+  };
+  assert!(validate_transform(&transform).is_err());
+  assert!(serde_json::from_value::<Transform>(serde_json::json!({ "select": "$" })).is_err());
+}
+
+// the same paths as test_parse_paths_accepted_by_the_subscriptions_service in the dispatcher,
+// everything accepted here has to parse there
+const ACCEPTED_PATHS: [&str; 8] = [
+  "$",
+  ".",
+  ".block_number",
+  "$.transaction.logs[*]['topic']",
+  "$[\"a]b\"]",
+  "$['a]b'].c",
+  "$[\"it's\"][-1]",
+  "$['say \"hi\"'].*",
+];
+const REJECTED_PATHS: [&str; 4] = ["$[\"a\"b\"]", "$['a']b']", "$['a", "$[a]"];
+
+#[test]
+async fn test_validate_transform_paths_match_the_dispatcher() {
+  let validate_path = |path: &str| {
+    validate_transform(&Transform {
+      fields: Some([("field".to_string(), path.to_string())].into()),
+      ..Default::default()
+    })
+  };
+  for path in ACCEPTED_PATHS {
+    assert!(validate_path(path).is_ok(), "{:?} was rejected", path);
+  }
+  for path in REJECTED_PATHS {
+    assert!(validate_path(path).is_err(), "{:?} passed", path);
+  }
+}
+
+#[test]
+async fn test_validate_destination() {
+  let destination: Destination =
+    serde_json::from_value(serde_json::json!({ "type": "kafka", "topic": "web3cache.events" }))
+      .unwrap();
+  assert!(validate_destination(&destination).is_ok());
+  assert!(validate_destination(&Destination::Http).is_ok());
+  assert!(validate_destination(&Destination::Kafka {
+    topic: "bad topic".to_string()
+  })
+  .is_err());
+  assert!(validate_destination(&Destination::Nats {
+    subject: "web3cache.events".to_string()
+  })
+  .is_ok());
+  assert!(validate_destination(&Destination::Nats {
+    subject: "web3cache.>".to_string()
+  })
+  .is_err());
+  assert!(validate_destination(&Destination::Nats {
+    subject: "web3cache..events".to_string()
+  })
+  .is_err());
+  assert!(validate_destination(&Destination::RedisStream {
+    stream: String::new()
+  })
+  .is_err());
+  assert!(serde_json::from_value::<Destination>(serde_json::json!({ "type": "ftp" })).is_err());
+}
+
+#[test]
+async fn test_validate_confirmations() {
+  let update: UpdateSub =
+    serde_json::from_value(serde_json::json!({ "confirmations": 12 })).unwrap();
+  assert!(validator::Validate::validate(&update).is_ok());
+  let update: UpdateSub =
+    serde_json::from_value(serde_json::json!({ "confirmations": -1 })).unwrap();
+  assert!(validator::Validate::validate(&update).is_err());
+}